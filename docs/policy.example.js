@@ -23,3 +23,17 @@ function new_file_storages({ stash_path, size, mtime, executable }) {
 function fofs_base_url(pile_hostname) {
     return `http://${pile_hostname}.wg:31415`;
 }
+
+// Optional: return the cipher with which new files in a google domain are encrypted,
+// either "AES_128_GCM" (the default if this function is not defined) or "AES_256_GCM"
+function gdrive_cipher(domain_id) {
+    return "AES_256_GCM";
+}
+
+// Optional: return true to link a new file to an existing stash file with the same
+// content instead of storing it again, if the existing file already has all the storages
+// new_file_storages returns. The dirent then gets the existing file's mtime.
+// Defaults to false if this function is not defined; `es x add --dedupe` always dedupes.
+function dedupe_new_file({ stash_path, size, mtime, executable }) {
+    return size > 1024 * 1024;
+}
@@ -0,0 +1,30 @@
+-- Incrementally maintained materialized views (IMMVs, from pg_ivm) with per-dir
+-- rollups of direct children, so that dir sizes can be read without walking every
+-- dirent below a dir. See `db::rollup::DirRollup`.
+--
+-- pg_ivm maintains these IMMVs in the transaction that changes dirents or files,
+-- and holds an exclusive lock on each IMMV it maintains until that transaction
+-- commits or rolls back. This serializes all transactions that write dirents or
+-- files, so keep them short: do reads and slow work before the first write and
+-- commit soon after it, as `traversal::remove_tree`, `dedupe::relink`, and
+-- `storage::delete::delete_unreferenced_file` do. A REPEATABLE READ or SERIALIZABLE
+-- transaction fails instead of waiting for the lock, so writers may need
+-- `db::set_isolation_level_read_committed`.
+
+-- The number of dirents directly in each dir.
+-- `child_dir IS DISTINCT FROM 1` filters out the root directory self-reference.
+SELECT pgivm.create_immv('dir_child_counts', $$
+    SELECT parent AS dir_id, COUNT(*) AS dirents
+    FROM dirents
+    WHERE child_dir IS DISTINCT FROM 1
+    GROUP BY parent
+$$);
+
+-- The number of file dirents directly in each dir and the total size of their files.
+-- A file with more than one dirent in the same dir is counted once per dirent.
+SELECT pgivm.create_immv('dir_file_sizes', $$
+    SELECT dirents.parent AS dir_id, COUNT(*) AS files, SUM(files.size) AS bytes
+    FROM dirents
+    JOIN files ON files.id = dirents.child_file
+    GROUP BY dirents.parent
+$$);
@@ -51,6 +51,20 @@ CREATE UNIQUE INDEX dirents_child_dir_index     ON dirents (child_dir);
 CREATE        INDEX dirents_child_file_index    ON dirents (child_file);
 CREATE        INDEX dirents_child_symlink_index ON dirents (child_symlink);
 
+-- For finding removed dirents to browse or restore, see `Dirent::find_last_removed`
+-- and `Dirent::find_by_parents` with `as_of`.
+CREATE INDEX dirents_history_parent_basename_index ON dirents_history (parent, basename);
+
+-- For searching for dirents by name anywhere in the stash, see
+-- `Dirent::find_by_basename_like`. The pg_trgm index supports LIKE and ILIKE
+-- patterns with wildcards on either end.
+CREATE INDEX dirents_basename_trgm_index ON dirents USING gin (basename public.gin_trgm_ops);
+
+-- For finding files and symlinks that no dirent pointed to recently, see
+-- `File::find_unreferenced_ids` and `Symlink::find_unreferenced_ids`.
+CREATE INDEX dirents_history_child_file_index    ON dirents_history (child_file);
+CREATE INDEX dirents_history_child_symlink_index ON dirents_history (child_symlink);
+
 -- We limit inserts and deletes to one dirent at a time. Because a dir must be a
 -- parent of some existing directory, this prevents the creation of cycles like an
 -- A->B, B->A not connected to the root dir, or the re-parenting a directory to a
@@ -71,7 +85,7 @@ BEGIN
         RETURN NULL;
     END IF;
 
-    -- escape hatch for populate-exastash and to-be-implemented directory move operation
+    -- escape hatch for populate-exastash, traversal::make_dirs, traversal::move_dirent, traversal::remove_tree, and traversal::restore_tree
     unsafe_internal_dirent_creation := current_setting('stash.unsafe_internal_dirent_creation', /* missing_ok */true);
     IF unsafe_internal_dirent_creation = '1' THEN
         RETURN NULL;
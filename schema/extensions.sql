@@ -2,3 +2,5 @@ CREATE EXTENSION IF NOT EXISTS periods CASCADE;
 -- GRANT USAGE ON SCHEMA periods TO archive;
 
 CREATE EXTENSION IF NOT EXISTS pg_ivm CASCADE;
+
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
@@ -34,6 +34,10 @@ CREATE TABLE dirs (
 );
 -- This should always get id=1
 INSERT INTO dirs VALUES (DEFAULT, now(), now(), 140, '');
+-- Keep removed dirs in dirs_history so that `traversal::restore_tree` can restore
+-- them with their mtimes and birth information.
+SELECT periods.add_system_time_period('dirs', 'row_start', 'row_end');
+SELECT periods.add_system_versioning('dirs');
 
 CREATE TABLE files (
     -- Limit of 2T can be raised if needed
@@ -53,6 +57,9 @@ CREATE TABLE files (
 -- Files with size = 0 don't need storage. This index speeds up some queries
 -- like the one used to find files with dirents but missing storage.
 CREATE INDEX ON stash.files (size) WHERE size = 0;
+-- For finding an existing file with the same content when adding a file with
+-- deduplication, see `File::find_by_b3sum_and_size`.
+CREATE INDEX files_b3sum_index ON stash.files (b3sum);
 
 CREATE TABLE symlinks (
     -- Limit of 1T can be raised if needed
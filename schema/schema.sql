@@ -27,3 +27,4 @@ $$ LANGUAGE plpgsql;
 \ir google_auth.sql
 \ir dirents.sql
 \ir inodes_views.sql
+\ir dir_rollups.sql
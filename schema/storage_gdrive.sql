@@ -139,12 +139,14 @@ CREATE TRIGGER gdrive_files_forbid_truncate
 
 -- Storage (sequences of Google Drive files)
 
-CREATE TYPE cipher AS ENUM ('AES_128_CTR', 'AES_128_GCM');
+CREATE TYPE cipher AS ENUM ('AES_128_CTR', 'AES_128_GCM', 'AES_256_GCM');
 
 -- Columns are ordered for optimal packing, be careful
 CREATE TABLE storage_gdrive (
     -- Not a UUID, just using uuid as a 128-bit field instead of bytea to save one byte
     cipher_key     uuid      NOT NULL,
+    -- The second half of the key for ciphers with 256-bit keys, NULL otherwise
+    cipher_key_ext uuid      CHECK ((cipher_key_ext IS NOT NULL) = (cipher = 'AES_256_GCM')),
     file_id        bigint    NOT NULL REFERENCES files (id),
     cipher         cipher    NOT NULL,
     google_domain  smallint  NOT NULL REFERENCES google_domains (id),
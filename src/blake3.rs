@@ -1,5 +1,6 @@
 //! BLAKE3-related helpers
 
+use std::path::Path;
 use std::pin::Pin;
 use std::sync::Arc;
 use parking_lot::Mutex;
@@ -94,6 +95,17 @@ pub fn b3sum_bytes(bytes: &[u8]) -> blake3::Hash {
     blake3::Hasher::finalize(&b3sum)
 }
 
+/// Return the BLAKE3 hash for the content of a local file.
+pub async fn b3sum_local_file(path: &Path) -> anyhow::Result<blake3::Hash> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let mut b3sum = blake3::Hasher::new();
+        std::io::copy(&mut file, &mut b3sum)?;
+        Ok(b3sum.finalize())
+    }).await?
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -105,4 +117,12 @@ mod tests {
             &hex::decode("d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24").unwrap()[..]
         );
     }
+
+    #[tokio::test]
+    async fn test_b3sum_local_file() -> anyhow::Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        std::io::Write::write_all(&mut file, b"hello world")?;
+        assert_eq!(b3sum_local_file(file.path()).await?, b3sum_bytes(b"hello world"));
+        Ok(())
+    }
 }
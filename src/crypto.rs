@@ -2,7 +2,7 @@
 
 use anyhow::{anyhow, bail, Result, Error};
 use byteorder::{BigEndian, WriteBytesExt};
-use ring::aead::{LessSafeKey, Nonce, Aad, Tag, UnboundKey, AES_128_GCM};
+use ring::aead::{LessSafeKey, Nonce, Aad, Tag, UnboundKey, Algorithm, AES_128_GCM, AES_256_GCM};
 use bytes::{Bytes, BytesMut, Buf, BufMut};
 use tokio_util::codec::{Decoder, Encoder};
 
@@ -11,14 +11,36 @@ pub(crate) fn write_gcm_iv_for_block_number(buf: &mut [u8; 12], block_number: u6
     (&mut buf[4..]).write_u64::<BigEndian>(block_number).unwrap();
 }
 
-pub(crate) fn gcm_create_key(bytes: [u8; 16]) -> Result<LessSafeKey> {
+fn create_key(algorithm: &'static Algorithm, bytes: &[u8]) -> Result<LessSafeKey> {
     let key = LessSafeKey::new(
-        UnboundKey::new(&AES_128_GCM, &bytes)
+        UnboundKey::new(algorithm, bytes)
             .map_err(|_| anyhow!("ring failed to create key"))?
     );
     Ok(key)
 }
 
+pub(crate) fn gcm_create_key(bytes: [u8; 16]) -> Result<LessSafeKey> {
+    create_key(&AES_128_GCM, &bytes)
+}
+
+pub(crate) fn gcm_256_create_key(bytes: [u8; 32]) -> Result<LessSafeKey> {
+    create_key(&AES_256_GCM, &bytes)
+}
+
+/// Create an AES-128-GCM key from `key`, or an AES-256-GCM key from `key`
+/// followed by `ext` if `ext` is given.
+pub(crate) fn gcm_create_key_from_halves(key: [u8; 16], ext: Option<[u8; 16]>) -> Result<LessSafeKey> {
+    match ext {
+        None => gcm_create_key(key),
+        Some(ext) => {
+            let mut bytes = [0; 32];
+            bytes[..16].copy_from_slice(&key);
+            bytes[16..].copy_from_slice(&ext);
+            gcm_256_create_key(bytes)
+        }
+    }
+}
+
 #[inline]
 fn gcm_encrypt_block(key: &LessSafeKey, block_number: u64, in_out: &mut [u8]) -> Result<Tag> {
     let mut iv = [0; 12];
@@ -230,6 +252,37 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_gcmencoder_gcmdecoder_256_bit_key() -> Result<()> {
+        let blocks = vec![
+            Bytes::from_static(b"hellowo"),
+            Bytes::from_static(b"short"),
+        ];
+        let block_size = 7;
+        let mut key_bytes = [0; 32];
+        for (i, byte) in key_bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let encoder = GcmEncoder::new(block_size, gcm_256_create_key(key_bytes)?, 0);
+        let decoder = GcmDecoder::new(block_size, gcm_256_create_key(key_bytes)?, 0);
+        let blocks_s = stream::iter(blocks.clone()).map(Ok);
+
+        let mut frame_data = vec![];
+        let frame_writer = FramedWrite::new(&mut frame_data, encoder);
+        blocks_s.forward(frame_writer).await?;
+        // Same framing as AES-128-GCM: a 16-byte tag before each block
+        assert_eq!(frame_data.len(), 16 + 7 + 16 + 5);
+
+        let mut out: Vec<Bytes> = vec![];
+        let mut frame_reader = FramedRead::new(frame_data.as_ref(), decoder);
+        while let Some(result) = frame_reader.next().await {
+            out.push(result.unwrap());
+        }
+        assert_eq!(out, blocks);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_gcmencoder_cannot_encode_zero_sized_block() -> Result<()> {
         let block_size = 7;
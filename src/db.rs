@@ -4,6 +4,7 @@ pub mod inode;
 pub mod dirent;
 pub mod storage;
 pub mod traversal;
+pub mod rollup;
 pub mod google_auth;
 
 use anyhow::Result;
@@ -85,6 +86,10 @@ pub async fn disable_synchronous_commit(transaction: &mut Transaction<'_, Postgr
 /// so that they don't get `error returned from database: could not obtain lock on
 /// materialized view "..." during incremental maintenance` from pg_ivm:
 /// https://github.com/sraoss/pg_ivm#concurrent-transactions
+///
+/// Even at READ COMMITTED, pg_ivm keeps the dir rollups locked from a transaction's
+/// first write to dirents or files until it ends, so other writers wait for it;
+/// see schema/dir_rollups.sql.
 pub async fn set_isolation_level_read_committed(transaction: &mut Transaction<'_, Postgres>) -> Result<()> {
     sqlx::query_unchecked!("SET TRANSACTION ISOLATION LEVEL READ COMMITTED").execute(&mut **transaction).await?;
     Ok(())
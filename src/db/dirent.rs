@@ -2,8 +2,10 @@
 
 use crate::db::inode::InodeId;
 use anyhow::{bail, Error, Result};
+use chrono::{DateTime, Utc};
 use futures::{StreamExt, TryStreamExt};
 use sqlx::{Postgres, Transaction};
+use std::collections::HashMap;
 
 /// A (dir, file, symlink) tuple that is useful when interacting with
 /// the dirents table.
@@ -53,6 +55,41 @@ impl From<DirentRow> for Dirent {
     }
 }
 
+/// A version of a directory entry, from either the dirents table or the dirents_history table
+#[derive(Debug, PartialEq, Eq)]
+pub struct DirentVersion {
+    /// The directory entry
+    pub dirent: Dirent,
+    /// When the directory entry was created
+    pub row_start: DateTime<Utc>,
+    /// When the directory entry was removed, or `None` if it still exists
+    pub row_end: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct DirentVersionRow {
+    parent: i64,
+    basename: String,
+    child_dir: Option<i64>,
+    child_file: Option<i64>,
+    child_symlink: Option<i64>,
+    row_start: DateTime<Utc>,
+    row_end: Option<DateTime<Utc>>,
+}
+
+impl From<DirentVersionRow> for DirentVersion {
+    fn from(row: DirentVersionRow) -> Self {
+        let dirent = DirentRow {
+            parent: row.parent,
+            basename: row.basename,
+            child_dir: row.child_dir,
+            child_file: row.child_file,
+            child_symlink: row.child_symlink,
+        }.into();
+        DirentVersion { dirent, row_start: row.row_start, row_end: row.row_end }
+    }
+}
+
 #[derive(Debug)]
 struct DirentRow {
     parent: i64,
@@ -90,6 +127,23 @@ impl Dirent {
         Ok(())
     }
 
+    /// Remove every dirent that points to any one of `file_ids`, moving them to the
+    /// dirents_history table, and return them ordered like `find_by_child_files`.
+    /// Does not commit the transaction, you must do so yourself.
+    pub async fn remove_by_child_files(transaction: &mut Transaction<'_, Postgres>, file_ids: &[i64]) -> Result<Vec<Dirent>> {
+        if file_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut rows = sqlx::query_as!(DirentRow, r#"
+            DELETE FROM stash.dirents
+            WHERE child_file = ANY($1)
+            RETURNING parent, basename, child_dir, child_file, child_symlink"#, file_ids
+        ).fetch_all(&mut **transaction).await?;
+        // DELETE ... RETURNING has no ORDER BY
+        rows.sort_by(|a, b| (a.child_file, a.parent, &a.basename).cmp(&(b.child_file, b.parent, &b.basename)));
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
     /// Remove a directory entry by `parent` and `basename`, moving it to the dirents_history table.
     /// Does not commit the transaction, you must do so yourself.
     pub async fn remove_by_parent_basename(transaction: &mut Transaction<'_, Postgres>, parent: i64, basename: &str) -> Result<()> {
@@ -110,21 +164,50 @@ impl Dirent {
         Ok(())
     }
 
+    /// Remove all directory entries with the given `parents`, moving them to the dirents_history table.
+    /// Does not commit the transaction, you must do so yourself.
+    pub async fn remove_by_parents(transaction: &mut Transaction<'_, Postgres>, parents: &[i64]) -> Result<()> {
+        sqlx::query!(r#"
+            DELETE FROM stash.dirents
+            WHERE parent = ANY($1)"#, parents
+        ).execute(&mut **transaction).await?;
+        Ok(())
+    }
+
     /// Return a `Vec<Dirent>` for all `Dirent`s with the given parents.
+    /// If `as_of` is given, return the `Dirent`s that existed at that time instead,
+    /// based on the dirents_history table.
     /// There is no error on missing parents.
-    pub async fn find_by_parents(transaction: &mut Transaction<'_, Postgres>, parents: &[i64]) -> Result<Vec<Dirent>> {
+    pub async fn find_by_parents(transaction: &mut Transaction<'_, Postgres>, parents: &[i64], as_of: Option<DateTime<Utc>>) -> Result<Vec<Dirent>> {
         // `child_dir IS DISTINCT FROM 1` filters out the root directory self-reference
-        let dirents = sqlx::query_as!(DirentRow, r#"
-            SELECT parent, basename, child_dir, child_file, child_symlink
-            FROM stash.dirents
-            WHERE
-                parent = ANY($1) AND
-                child_dir IS DISTINCT FROM 1"#,
-            parents
-        )
-            .fetch(&mut **transaction)
-            .map(|result| result.map(|row| row.into()))
-            .try_collect().await?;
+        let dirents = match as_of {
+            None => {
+                sqlx::query_as!(DirentRow, r#"
+                    SELECT parent, basename, child_dir, child_file, child_symlink
+                    FROM stash.dirents
+                    WHERE
+                        parent = ANY($1) AND
+                        child_dir IS DISTINCT FROM 1"#,
+                    parents
+                )
+                    .fetch(&mut **transaction)
+                    .map(|result| result.map(|row| row.into()))
+                    .try_collect().await?
+            }
+            Some(as_of) => {
+                sqlx::query_as!(DirentRow, r#"
+                    SELECT parent AS "parent!", basename AS "basename!", child_dir, child_file, child_symlink
+                    FROM stash.dirents__as_of($2)
+                    WHERE
+                        parent = ANY($1) AND
+                        child_dir IS DISTINCT FROM 1"#,
+                    parents, as_of
+                )
+                    .fetch(&mut **transaction)
+                    .map(|result| result.map(|row| row.into()))
+                    .try_collect().await?
+            }
+        };
         Ok(dirents)
     }
 
@@ -140,6 +223,198 @@ impl Dirent {
         Ok(row.map(Into::into))
     }
 
+    /// Return an `Option<Dirent>` if a `Dirent` with the given `parent` and `basename` existed
+    /// at time `as_of`, based on the dirents_history table.
+    pub async fn find_by_parent_and_basename_as_of(transaction: &mut Transaction<'_, Postgres>, parent: i64, basename: &str, as_of: DateTime<Utc>) -> Result<Option<Dirent>> {
+        // `child_dir IS DISTINCT FROM 1` filters out the root directory self-reference
+        let row = sqlx::query_as!(DirentRow, r#"
+            SELECT parent AS "parent!", basename AS "basename!", child_dir, child_file, child_symlink
+            FROM stash.dirents__as_of($3)
+            WHERE parent = $1 AND basename = $2 AND child_dir IS DISTINCT FROM 1"#, parent, basename, as_of
+        )
+            .fetch_optional(&mut **transaction).await?;
+        Ok(row.map(Into::into))
+    }
+
+    /// Return all `Dirent`s below dir `under` (1 for the whole stash) with a basename
+    /// matching the SQL `LIKE` pattern `like_pattern`, which uses `\` as the escape
+    /// character. The trigram index on basename makes this fast as long as the pattern
+    /// has at least a few consecutive literal characters.
+    pub async fn find_by_basename_like(
+        transaction: &mut Transaction<'_, Postgres>,
+        like_pattern: &str,
+        case_insensitive: bool,
+        under: i64,
+    ) -> Result<Vec<Dirent>> {
+        // `child_dir IS DISTINCT FROM 1` filters out the root directory self-reference.
+        // For each parent of a match, walk its ancestors up to `under` or the root.
+        let rows = if case_insensitive {
+            sqlx::query_as!(DirentRow,
+                "WITH RECURSIVE matches AS (
+                     SELECT parent, basename, child_dir, child_file, child_symlink
+                     FROM stash.dirents
+                     WHERE basename ILIKE $1 AND child_dir IS DISTINCT FROM 1
+                 ),
+                 ancestors(start, dir) AS (
+                     SELECT DISTINCT parent, parent FROM matches WHERE $2::bigint <> 1
+                     UNION
+                     SELECT ancestors.start, dirents.parent
+                     FROM ancestors
+                     JOIN stash.dirents ON dirents.child_dir = ancestors.dir
+                     WHERE ancestors.dir <> $2::bigint AND dirents.child_dir <> 1
+                 )
+                 SELECT parent AS \"parent!\", basename AS \"basename!\", child_dir, child_file, child_symlink
+                 FROM matches
+                 WHERE $2::bigint = 1 OR parent IN (SELECT start FROM ancestors WHERE dir = $2::bigint)
+                 ORDER BY parent, basename", like_pattern, under
+            ).fetch_all(&mut **transaction).await?
+        } else {
+            sqlx::query_as!(DirentRow,
+                "WITH RECURSIVE matches AS (
+                     SELECT parent, basename, child_dir, child_file, child_symlink
+                     FROM stash.dirents
+                     WHERE basename LIKE $1 AND child_dir IS DISTINCT FROM 1
+                 ),
+                 ancestors(start, dir) AS (
+                     SELECT DISTINCT parent, parent FROM matches WHERE $2::bigint <> 1
+                     UNION
+                     SELECT ancestors.start, dirents.parent
+                     FROM ancestors
+                     JOIN stash.dirents ON dirents.child_dir = ancestors.dir
+                     WHERE ancestors.dir <> $2::bigint AND dirents.child_dir <> 1
+                 )
+                 SELECT parent AS \"parent!\", basename AS \"basename!\", child_dir, child_file, child_symlink
+                 FROM matches
+                 WHERE $2::bigint = 1 OR parent IN (SELECT start FROM ancestors WHERE dir = $2::bigint)
+                 ORDER BY parent, basename", like_pattern, under
+            ).fetch_all(&mut **transaction).await?
+        };
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    /// Walk `basenames` down from dir `base_dir` in a single recursive query, returning the
+    /// `Dirent` for each basename in order. The walk stops at the first basename that is not
+    /// found or that is under a non-dir, so the result may be shorter than `basenames`.
+    /// If `as_of` is given, walks the dirents that existed at that time instead.
+    pub async fn find_chain(
+        transaction: &mut Transaction<'_, Postgres>,
+        base_dir: i64,
+        basenames: &[String],
+        as_of: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Dirent>> {
+        if basenames.is_empty() {
+            return Ok(vec![]);
+        }
+        // `child_dir IS DISTINCT FROM 1` filters out the root directory self-reference.
+        // Postgres arrays are 1-based, so the dirent at `depth` has basename `$2[depth]`.
+        // `$2` is cast because Postgres cannot infer the type of a subscripted parameter.
+        let rows = match as_of {
+            None => {
+                sqlx::query_as!(DirentRow, r#"
+                    WITH RECURSIVE chain AS (
+                        SELECT 1 AS depth, parent, basename, child_dir, child_file, child_symlink
+                        FROM stash.dirents
+                        WHERE parent = $1 AND basename = ($2::text[])[1] AND child_dir IS DISTINCT FROM 1
+                        UNION ALL
+                        SELECT chain.depth + 1, dirents.parent, dirents.basename, dirents.child_dir, dirents.child_file, dirents.child_symlink
+                        FROM chain
+                        JOIN stash.dirents ON dirents.parent = chain.child_dir AND dirents.basename = ($2::text[])[chain.depth + 1]
+                        WHERE chain.depth < cardinality($2::text[]) AND dirents.child_dir IS DISTINCT FROM 1
+                    )
+                    SELECT parent AS "parent!", basename AS "basename!", child_dir, child_file, child_symlink
+                    FROM chain
+                    ORDER BY depth"#,
+                    base_dir, basenames
+                ).fetch_all(&mut **transaction).await?
+            }
+            Some(as_of) => {
+                sqlx::query_as!(DirentRow, r#"
+                    WITH RECURSIVE chain AS (
+                        SELECT 1 AS depth, parent, basename, child_dir, child_file, child_symlink
+                        FROM stash.dirents__as_of($3)
+                        WHERE parent = $1 AND basename = ($2::text[])[1] AND child_dir IS DISTINCT FROM 1
+                        UNION ALL
+                        SELECT chain.depth + 1, dirents.parent, dirents.basename, dirents.child_dir, dirents.child_file, dirents.child_symlink
+                        FROM chain
+                        JOIN stash.dirents__as_of($3) AS dirents ON dirents.parent = chain.child_dir AND dirents.basename = ($2::text[])[chain.depth + 1]
+                        WHERE chain.depth < cardinality($2::text[]) AND dirents.child_dir IS DISTINCT FROM 1
+                    )
+                    SELECT parent AS "parent!", basename AS "basename!", child_dir, child_file, child_symlink
+                    FROM chain
+                    ORDER BY depth"#,
+                    base_dir, basenames, as_of
+                ).fetch_all(&mut **transaction).await?
+            }
+        };
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    /// Return the most recently removed `Dirent` with the given `parent` and `basename`,
+    /// along with the time it was removed, based on the dirents_history table.
+    pub async fn find_last_removed(transaction: &mut Transaction<'_, Postgres>, parent: i64, basename: &str) -> Result<Option<(Dirent, DateTime<Utc>)>> {
+        let row = sqlx::query!(r#"
+            SELECT parent, basename, child_dir, child_file, child_symlink, row_end
+            FROM stash.dirents_history
+            WHERE parent = $1 AND basename = $2
+            ORDER BY row_end DESC
+            LIMIT 1"#, parent, basename
+        )
+            .fetch_optional(&mut **transaction).await?;
+        Ok(row.map(|row| {
+            let dirent = DirentRow {
+                parent: row.parent,
+                basename: row.basename,
+                child_dir: row.child_dir,
+                child_file: row.child_file,
+                child_symlink: row.child_symlink,
+            };
+            (dirent.into(), row.row_end)
+        }))
+    }
+
+    /// Return all versions of the `Dirent`s with the given parents, both current and removed,
+    /// ordered by the time they were created.
+    pub async fn find_versions_by_parents(transaction: &mut Transaction<'_, Postgres>, parents: &[i64]) -> Result<Vec<DirentVersion>> {
+        // `child_dir IS DISTINCT FROM 1` filters out the root directory self-reference
+        let versions = sqlx::query_as!(DirentVersionRow, r#"
+            SELECT
+                parent AS "parent!", basename AS "basename!", child_dir, child_file, child_symlink,
+                row_start AS "row_start!", NULLIF(row_end, 'infinity') AS row_end
+            FROM stash.dirents_with_history
+            WHERE
+                parent = ANY($1) AND
+                child_dir IS DISTINCT FROM 1
+            ORDER BY row_start, basename"#,
+            parents
+        )
+            .fetch(&mut **transaction)
+            .map(|result| result.map(|row| row.into()))
+            .try_collect().await?;
+        Ok(versions)
+    }
+
+    /// Return all versions of the `Dirent` with the given `parent` and `basename`, both current
+    /// and removed, ordered by the time they were created.
+    pub async fn find_versions_by_parent_and_basename(transaction: &mut Transaction<'_, Postgres>, parent: i64, basename: &str) -> Result<Vec<DirentVersion>> {
+        // `child_dir IS DISTINCT FROM 1` filters out the root directory self-reference
+        let versions = sqlx::query_as!(DirentVersionRow, r#"
+            SELECT
+                parent AS "parent!", basename AS "basename!", child_dir, child_file, child_symlink,
+                row_start AS "row_start!", NULLIF(row_end, 'infinity') AS row_end
+            FROM stash.dirents_with_history
+            WHERE
+                parent = $1 AND
+                basename = $2 AND
+                child_dir IS DISTINCT FROM 1
+            ORDER BY row_start"#,
+            parent, basename
+        )
+            .fetch(&mut **transaction)
+            .map(|result| result.map(|row| row.into()))
+            .try_collect().await?;
+        Ok(versions)
+    }
+
     /// Return a `Vec` of `Dirent`s for all dirents that exist with given `parent` and any one of `basenames`.
     pub async fn find_by_parent_and_basenames(transaction: &mut Transaction<'_, Postgres>, parent: i64, basenames: &[&str]) -> Result<Vec<Dirent>> {
         // sqlx::query_as! insists on String
@@ -172,6 +447,67 @@ impl Dirent {
         Ok(maybe_dirent)
     }
 
+    /// Return a `Vec` of `Dirent`s for all dirents that point to any one of `file_ids`.
+    pub async fn find_by_child_files(transaction: &mut Transaction<'_, Postgres>, file_ids: &[i64]) -> Result<Vec<Dirent>> {
+        if file_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let dirents = sqlx::query_as!(DirentRow, r#"
+            SELECT parent, basename, child_dir, child_file, child_symlink
+            FROM stash.dirents
+            WHERE child_file = ANY($1)
+            ORDER BY child_file, parent, basename"#, file_ids
+        )
+            .fetch(&mut **transaction)
+            .map(|result| result.map(|row| row.into()))
+            .try_collect().await?;
+        Ok(dirents)
+    }
+
+    /// Return a map of inode -> the number of dirents pointing to it, for the given `children`.
+    /// Inodes that no dirent points to are not included in the map.
+    pub async fn count_by_children(transaction: &mut Transaction<'_, Postgres>, children: &[InodeId]) -> Result<HashMap<InodeId, i64>> {
+        let mut dir_ids = vec![];
+        let mut file_ids = vec![];
+        let mut symlink_ids = vec![];
+        for child in children {
+            match child {
+                InodeId::Dir(id)     => { dir_ids.push(*id); }
+                InodeId::File(id)    => { file_ids.push(*id); }
+                InodeId::Symlink(id) => { symlink_ids.push(*id); }
+            }
+        }
+        let mut out = HashMap::new();
+        if !dir_ids.is_empty() {
+            let rows = sqlx::query!(r#"
+                SELECT child_dir AS "id!", COUNT(*) AS "count!"
+                FROM stash.dirents
+                WHERE child_dir = ANY($1)
+                GROUP BY child_dir"#, &dir_ids
+            ).fetch_all(&mut **transaction).await?;
+            out.extend(rows.into_iter().map(|row| (InodeId::Dir(row.id), row.count)));
+        }
+        if !file_ids.is_empty() {
+            let rows = sqlx::query!(r#"
+                SELECT child_file AS "id!", COUNT(*) AS "count!"
+                FROM stash.dirents
+                WHERE child_file = ANY($1)
+                GROUP BY child_file"#, &file_ids
+            ).fetch_all(&mut **transaction).await?;
+            out.extend(rows.into_iter().map(|row| (InodeId::File(row.id), row.count)));
+        }
+        if !symlink_ids.is_empty() {
+            let rows = sqlx::query!(r#"
+                SELECT child_symlink AS "id!", COUNT(*) AS "count!"
+                FROM stash.dirents
+                WHERE child_symlink = ANY($1)
+                GROUP BY child_symlink"#, &symlink_ids
+            ).fetch_all(&mut **transaction).await?;
+            out.extend(rows.into_iter().map(|row| (InodeId::Symlink(row.id), row.count)));
+        }
+        Ok(out)
+    }
+
     /// Return a count of the number of dirents in the database.
     pub async fn count(transaction: &mut Transaction<'_, Postgres>) -> Result<i64> {
         let count: i64 = sqlx::query_scalar!("SELECT COUNT(parent) FROM stash.dirents")
@@ -223,8 +559,8 @@ pub(crate) mod tests {
             transaction.commit().await?;
 
             let mut transaction = pool.begin().await?;
-            assert_eq!(Dirent::find_by_parents(&mut transaction, &[child_dir.id]).await?, vec![]);
-            assert_eq!(Dirent::find_by_parents(&mut transaction, &[parent.id]).await?, vec![
+            assert_eq!(Dirent::find_by_parents(&mut transaction, &[child_dir.id], None).await?, vec![]);
+            assert_eq!(Dirent::find_by_parents(&mut transaction, &[parent.id], None).await?, vec![
                 Dirent::new(parent.id, "child_dir", InodeId::Dir(child_dir.id)),
                 Dirent::new(parent.id, "child_file", InodeId::File(child_file.id)),
                 Dirent::new(parent.id, "child_symlink", InodeId::Symlink(child_symlink.id)),
@@ -232,6 +568,147 @@ pub(crate) mod tests {
 
             Ok(())
         }
+
+        #[tokio::test]
+        async fn test_find_as_of() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let mut transaction = pool.begin().await?;
+            let birth = inode::Birth::here_and_now();
+            let parent = inode::NewDir { mtime: Utc::now(), birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(1, make_basename("parent"), InodeId::Dir(parent.id)).create(&mut transaction).await?;
+            let file = inode::NewFile { size: 0, executable: false, mtime: Utc::now(), birth: birth.clone(), b3sum: None }.create(&mut transaction).await?;
+            Dirent::new(parent.id, "old_name", InodeId::File(file.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let before_rename = sqlx::query_scalar!(r#"SELECT now() AS "now!""#).fetch_one(&mut *transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            Dirent::remove_by_parent_basename(&mut transaction, parent.id, "old_name").await?;
+            Dirent::new(parent.id, "new_name", InodeId::File(file.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            assert_eq!(Dirent::find_by_parents(&mut transaction, &[parent.id], None).await?, vec![
+                Dirent::new(parent.id, "new_name", InodeId::File(file.id)),
+            ]);
+            assert_eq!(Dirent::find_by_parents(&mut transaction, &[parent.id], Some(before_rename)).await?, vec![
+                Dirent::new(parent.id, "old_name", InodeId::File(file.id)),
+            ]);
+            assert_eq!(
+                Dirent::find_by_parent_and_basename_as_of(&mut transaction, parent.id, "old_name", before_rename).await?,
+                Some(Dirent::new(parent.id, "old_name", InodeId::File(file.id)))
+            );
+            assert_eq!(Dirent::find_by_parent_and_basename_as_of(&mut transaction, parent.id, "new_name", before_rename).await?, None);
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_find_by_basename_like() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let mut transaction = pool.begin().await?;
+            let birth = inode::Birth::here_and_now();
+            let grandparent = inode::NewDir { mtime: Utc::now(), birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(1, make_basename("grandparent"), InodeId::Dir(grandparent.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let parent = inode::NewDir { mtime: Utc::now(), birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(grandparent.id, "parent", InodeId::Dir(parent.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let elsewhere = inode::NewDir { mtime: Utc::now(), birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(1, make_basename("elsewhere"), InodeId::Dir(elsewhere.id)).create(&mut transaction).await?;
+            let file = inode::NewFile { size: 0, executable: false, mtime: Utc::now(), birth: birth.clone(), b3sum: None }.create(&mut transaction).await?;
+            let unique = make_basename("Search");
+            Dirent::new(parent.id, format!("{unique}.txt"), InodeId::File(file.id)).create(&mut transaction).await?;
+            Dirent::new(parent.id, format!("{unique}_100%"), InodeId::File(file.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let txt = || Dirent::new(parent.id, format!("{unique}.txt"), InodeId::File(file.id));
+            let percent = || Dirent::new(parent.id, format!("{unique}_100%"), InodeId::File(file.id));
+            assert_eq!(Dirent::find_by_basename_like(&mut transaction, &format!("{unique}%"), false, 1).await?.len(), 2);
+            assert_eq!(Dirent::find_by_basename_like(&mut transaction, &format!("{unique}%.txt"), false, 1).await?, vec![txt()]);
+            assert_eq!(Dirent::find_by_basename_like(&mut transaction, &format!("{unique}\\_100\\%"), false, 1).await?, vec![percent()]);
+            assert_eq!(Dirent::find_by_basename_like(&mut transaction, &format!("{}.TXT", unique.to_uppercase()), false, 1).await?, vec![]);
+            assert_eq!(Dirent::find_by_basename_like(&mut transaction, &format!("{}.TXT", unique.to_uppercase()), true, 1).await?, vec![txt()]);
+            assert_eq!(Dirent::find_by_basename_like(&mut transaction, &format!("{unique}%.txt"), false, parent.id).await?, vec![txt()]);
+            assert_eq!(Dirent::find_by_basename_like(&mut transaction, &format!("{unique}%.txt"), false, grandparent.id).await?, vec![txt()]);
+            assert_eq!(Dirent::find_by_basename_like(&mut transaction, &format!("{unique}%.txt"), false, elsewhere.id).await?, vec![]);
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_find_versions() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let mut transaction = pool.begin().await?;
+            let birth = inode::Birth::here_and_now();
+            let parent = inode::NewDir { mtime: Utc::now(), birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(1, make_basename("parent"), InodeId::Dir(parent.id)).create(&mut transaction).await?;
+            let old_file = inode::NewFile { size: 0, executable: false, mtime: Utc::now(), birth: birth.clone(), b3sum: None }.create(&mut transaction).await?;
+            let new_file = inode::NewFile { size: 1, executable: false, mtime: Utc::now(), birth: birth.clone(), b3sum: None }.create(&mut transaction).await?;
+            Dirent::new(parent.id, "file", InodeId::File(old_file.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            Dirent::remove_by_parent_basename(&mut transaction, parent.id, "file").await?;
+            Dirent::new(parent.id, "file", InodeId::File(new_file.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let versions = Dirent::find_versions_by_parent_and_basename(&mut transaction, parent.id, "file").await?;
+            assert_eq!(versions.len(), 2);
+            assert_eq!(versions[0].dirent, Dirent::new(parent.id, "file", InodeId::File(old_file.id)));
+            assert_eq!(versions[1].dirent, Dirent::new(parent.id, "file", InodeId::File(new_file.id)));
+            // The old version was removed when the new version was created
+            assert_eq!(versions[0].row_end, Some(versions[1].row_start));
+            assert_eq!(versions[1].row_end, None);
+            assert_eq!(Dirent::find_versions_by_parents(&mut transaction, &[parent.id]).await?, versions);
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_count_by_children() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let mut transaction = pool.begin().await?;
+            let birth = inode::Birth::here_and_now();
+            let parent = inode::NewDir { mtime: Utc::now(), birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(1, make_basename("parent"), InodeId::Dir(parent.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let file = inode::NewFile { size: 0, executable: false, mtime: Utc::now(), birth: birth.clone(), b3sum: None }.create(&mut transaction).await?;
+            let unlinked_file = inode::NewFile { size: 0, executable: false, mtime: Utc::now(), birth: birth.clone(), b3sum: None }.create(&mut transaction).await?;
+            let symlink = inode::NewSymlink { target: "target".into(), mtime: Utc::now(), birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(parent.id, "file_1", InodeId::File(file.id)).create(&mut transaction).await?;
+            Dirent::new(parent.id, "file_2", InodeId::File(file.id)).create(&mut transaction).await?;
+            Dirent::new(parent.id, "symlink", InodeId::Symlink(symlink.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let children = [InodeId::Dir(parent.id), InodeId::File(file.id), InodeId::File(unlinked_file.id), InodeId::Symlink(symlink.id)];
+            let counts = Dirent::count_by_children(&mut transaction, &children).await?;
+            assert_eq!(counts, HashMap::from([
+                (InodeId::Dir(parent.id), 1),
+                (InodeId::File(file.id), 2),
+                (InodeId::Symlink(symlink.id), 1),
+            ]));
+
+            Ok(())
+        }
     }
 
     // Testing our .sql from Rust, not testing our Rust
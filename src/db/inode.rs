@@ -124,6 +124,24 @@ impl Dir {
         Ok(dirs)
     }
 
+    /// Return a `Vec<Dir>` for the corresponding list of dir `ids`, as they were at time
+    /// `as_of`, including dirs that have since been deleted, based on the dirs_history table.
+    /// There is no error on missing dirs.
+    pub async fn find_by_ids_as_of(transaction: &mut Transaction<'_, Postgres>, ids: &[i64], as_of: DateTime<Utc>) -> Result<Vec<Dir>> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let dirs = sqlx::query_as!(DirRow, r#"
+            SELECT id AS "id!", mtime AS "mtime!", birth_time AS "birth_time!", birth_version AS "birth_version!", birth_hostname AS "birth_hostname!"
+            FROM stash.dirs__as_of($2)
+            WHERE id = ANY($1)"#, ids, as_of
+        )
+            .fetch(&mut **transaction)
+            .map(|result| result.map(|row| row.into()))
+            .try_collect().await?;
+        Ok(dirs)
+    }
+
     /// Delete dirs with given `ids`.
     ///
     /// Note that that foreign key constraints in the database require removing
@@ -176,6 +194,24 @@ impl NewDir {
             birth: self.birth,
         })
     }
+
+    /// Create an entry for a directory with a specific `id` in the database and return a `Dir`.
+    /// This is for restoring a deleted dir, so that the dirents that referred to it in the
+    /// dirents_history table refer to it again.
+    /// Does not commit the transaction, you must do so yourself.
+    pub async fn create_with_id(self, transaction: &mut Transaction<'_, Postgres>, id: i64) -> Result<Dir> {
+        sqlx::query!(r#"
+            INSERT INTO stash.dirs (id, mtime, birth_time, birth_version, birth_hostname)
+            OVERRIDING SYSTEM VALUE
+            VALUES ($1, $2, $3, $4, $5::text)"#,
+            id, self.mtime, self.birth.time, self.birth.version, &self.birth.hostname
+        ).execute(&mut **transaction).await?;
+        Ok(Dir {
+            id,
+            mtime: self.mtime,
+            birth: self.birth,
+        })
+    }
 }
 
 /// A file
@@ -247,6 +283,46 @@ impl File {
         Ok(files)
     }
 
+    /// Return a `Vec<File>` of the files with the given `b3sum` and `size`, oldest first.
+    pub async fn find_by_b3sum_and_size(transaction: &mut Transaction<'_, Postgres>, b3sum: &[u8; 32], size: i64) -> Result<Vec<File>> {
+        let files = sqlx::query_as!(FileRow, r#"
+            SELECT id, mtime, size, executable, birth_time, birth_version, birth_hostname, b3sum
+            FROM stash.files
+            WHERE b3sum = $1 AND size = $2
+            ORDER BY id"#, b3sum.as_ref(), size
+        )
+            .fetch(&mut **transaction)
+            .map(|result| result.map(|row| row.into()))
+            .try_collect().await?;
+        Ok(files)
+    }
+
+    /// Return a `Vec<File>` of the non-empty files that some dirent points to and that have
+    /// the same b3sum and size as another such file, ordered by b3sum and then oldest first.
+    pub async fn find_duplicates(transaction: &mut Transaction<'_, Postgres>) -> Result<Vec<File>> {
+        let files = sqlx::query_as!(FileRow, r#"
+            SELECT id, mtime, size, executable, birth_time, birth_version, birth_hostname, b3sum
+            FROM stash.files
+            WHERE
+                b3sum IS NOT NULL AND size > 0 AND
+                EXISTS (SELECT 1 FROM stash.dirents WHERE child_file = files.id) AND
+                (b3sum, size) IN (
+                    SELECT b3sum, size
+                    FROM stash.files AS f
+                    WHERE
+                        b3sum IS NOT NULL AND size > 0 AND
+                        EXISTS (SELECT 1 FROM stash.dirents WHERE child_file = f.id)
+                    GROUP BY b3sum, size
+                    HAVING COUNT(*) > 1
+                )
+            ORDER BY b3sum, id"#
+        )
+            .fetch(&mut **transaction)
+            .map(|result| result.map(|row| row.into()))
+            .try_collect().await?;
+        Ok(files)
+    }
+
     /// Return a new, unique id for a file.  Caller can take this id and `create()` a `File` with it later.
     pub async fn next_id(transaction: &mut Transaction<'_, Postgres>) -> Result<i64> {
         db::nextval(transaction, "stash.files_id_seq").await
@@ -288,6 +364,29 @@ impl File {
         Ok(())
     }
 
+    /// Lock the row of file `id` until the end of the transaction, blocking the creation
+    /// of dirents that point to it. Return whether the file exists.
+    pub async fn lock_for_update(transaction: &mut Transaction<'_, Postgres>, id: i64) -> Result<bool> {
+        let row = sqlx::query_scalar!(r#"
+            SELECT id FROM stash.files WHERE id = $1 FOR UPDATE"#, id
+        ).fetch_optional(&mut **transaction).await?;
+        Ok(row.is_some())
+    }
+
+    /// Return the ids of files that are not the child of any dirent, and were not the child
+    /// of any dirent removed at or after `cutoff`. Files born at or after `cutoff` are
+    /// excluded because a file is created before the dirent that points to it.
+    pub async fn find_unreferenced_ids(transaction: &mut Transaction<'_, Postgres>, cutoff: DateTime<Utc>) -> Result<Vec<i64>> {
+        let ids = sqlx::query_scalar!(r#"
+            SELECT id FROM stash.files
+            WHERE birth_time < $1
+            AND NOT EXISTS (SELECT 1 FROM stash.dirents WHERE child_file = files.id)
+            AND NOT EXISTS (SELECT 1 FROM stash.dirents_history WHERE child_file = files.id AND row_end >= $1)
+            ORDER BY id"#, cutoff
+        ).fetch_all(&mut **transaction).await?;
+        Ok(ids)
+    }
+
     /// Return a count of the number of files in the database.
     pub async fn count(transaction: &mut Transaction<'_, Postgres>) -> Result<i64> {
         let count: i64 = sqlx::query_scalar!("SELECT COUNT(id) FROM stash.files")
@@ -408,6 +507,20 @@ impl Symlink {
         Ok(())
     }
 
+    /// Return the ids of symlinks that are not the child of any dirent, and were not the child
+    /// of any dirent removed at or after `cutoff`. Symlinks born at or after `cutoff` are
+    /// excluded because a symlink is created before the dirent that points to it.
+    pub async fn find_unreferenced_ids(transaction: &mut Transaction<'_, Postgres>, cutoff: DateTime<Utc>) -> Result<Vec<i64>> {
+        let ids = sqlx::query_scalar!(r#"
+            SELECT id FROM stash.symlinks
+            WHERE birth_time < $1
+            AND NOT EXISTS (SELECT 1 FROM stash.dirents WHERE child_symlink = symlinks.id)
+            AND NOT EXISTS (SELECT 1 FROM stash.dirents_history WHERE child_symlink = symlinks.id AND row_end >= $1)
+            ORDER BY id"#, cutoff
+        ).fetch_all(&mut **transaction).await?;
+        Ok(ids)
+    }
+
     /// Return a count of the number of symlinks in the database.
     pub async fn count(transaction: &mut Transaction<'_, Postgres>) -> Result<i64> {
         let count: i64 = sqlx::query_scalar!("SELECT COUNT(id) FROM stash.symlinks")
@@ -606,6 +719,21 @@ pub(crate) mod tests {
             Ok(())
         }
 
+        /// NewDir::create_with_id can re-create a deleted dir with the same id
+        #[tokio::test]
+        async fn test_dir_create_with_id() -> Result<()> {
+            let pool = new_primary_pool().await;
+            let mut transaction = pool.begin().await?;
+
+            let dir = NewDir { mtime: util::now_no_nanos(), birth: Birth::here_and_now() }.create(&mut transaction).await?;
+            Dir::delete(&mut transaction, &[dir.id]).await?;
+            let restored = NewDir { mtime: dir.mtime, birth: dir.birth.clone() }.create_with_id(&mut transaction, dir.id).await?;
+            assert_eq!(restored, dir);
+            assert_eq!(Dir::find_by_ids(&mut transaction, &[dir.id]).await?, vec![dir]);
+
+            Ok(())
+        }
+
         /// Cannot create dir without it being a child_dir of something in dirents
         #[tokio::test]
         async fn test_cannot_create_dir_without_dirent() -> Result<()> {
@@ -697,6 +825,75 @@ pub(crate) mod tests {
 
             Ok(())
         }
+
+        #[tokio::test]
+        async fn test_find_by_b3sum_and_size() -> Result<()> {
+            let pool = new_primary_pool().await;
+            let mut transaction = pool.begin().await?;
+
+            let b3sum = *blake3::hash(util::now_no_nanos().to_rfc3339().as_bytes()).as_bytes();
+            let birth = Birth::here_and_now();
+            let first = NewFile { executable: false, size: 10, mtime: util::now_no_nanos(), birth: birth.clone(), b3sum: Some(b3sum) }
+                .create(&mut transaction).await?;
+            let second = NewFile { executable: true, size: 10, mtime: util::now_no_nanos(), birth: birth.clone(), b3sum: Some(b3sum) }
+                .create(&mut transaction).await?;
+            NewFile { executable: false, size: 11, mtime: util::now_no_nanos(), birth, b3sum: Some(b3sum) }
+                .create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            assert_eq!(File::find_by_b3sum_and_size(&mut transaction, &b3sum, 10).await?, vec![first, second]);
+            assert_eq!(File::find_by_b3sum_and_size(&mut transaction, &[0; 32], 10).await?, vec![]);
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
+
+        /// File::find_unreferenced_ids and Symlink::find_unreferenced_ids return only inodes
+        /// born before the cutoff that no dirent points to or pointed to since the cutoff
+        #[tokio::test]
+        async fn test_find_unreferenced_ids() -> Result<()> {
+            let pool = new_primary_pool().await;
+            let mut transaction = pool.begin().await?;
+
+            let old_birth = Birth { time: util::now_no_nanos() - chrono::Duration::days(10), ..Birth::here_and_now() };
+            let cutoff = util::now_no_nanos() - chrono::Duration::days(1);
+            let unreferenced_file = NewFile { executable: false, size: 0, mtime: util::now_no_nanos(), birth: old_birth.clone(), b3sum: None }
+                .create(&mut transaction).await?;
+            let referenced_file = NewFile { executable: false, size: 0, mtime: util::now_no_nanos(), birth: old_birth.clone(), b3sum: None }
+                .create(&mut transaction).await?;
+            let new_file = create_dummy_file(&mut transaction).await?;
+            let unreferenced_symlink = NewSymlink { target: "test".into(), mtime: util::now_no_nanos(), birth: old_birth.clone() }
+                .create(&mut transaction).await?;
+            let referenced_symlink = NewSymlink { target: "test".into(), mtime: util::now_no_nanos(), birth: old_birth }
+                .create(&mut transaction).await?;
+            let dir = create_dummy_dir(&mut transaction, "test_find_unreferenced_ids").await?;
+            Dirent::new(dir.id, "file", InodeId::File(referenced_file.id)).create(&mut transaction).await?;
+            Dirent::new(dir.id, "symlink", InodeId::Symlink(referenced_symlink.id)).create(&mut transaction).await?;
+
+            let file_ids = File::find_unreferenced_ids(&mut transaction, cutoff).await?;
+            assert!(file_ids.contains(&unreferenced_file.id));
+            assert!(!file_ids.contains(&referenced_file.id));
+            assert!(!file_ids.contains(&new_file.id));
+            let symlink_ids = Symlink::find_unreferenced_ids(&mut transaction, cutoff).await?;
+            assert!(symlink_ids.contains(&unreferenced_symlink.id));
+            assert!(!symlink_ids.contains(&referenced_symlink.id));
+            transaction.commit().await?;
+
+            // A dirent removed after the cutoff still protects its child
+            let mut transaction = pool.begin().await?;
+            Dirent::remove_by_parents(&mut transaction, &[dir.id]).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let file_ids = File::find_unreferenced_ids(&mut transaction, cutoff).await?;
+            assert!(!file_ids.contains(&referenced_file.id));
+            let symlink_ids = Symlink::find_unreferenced_ids(&mut transaction, cutoff).await?;
+            assert!(!symlink_ids.contains(&referenced_symlink.id));
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
     }
 
     // Testing our .sql from Rust, not testing our Rust
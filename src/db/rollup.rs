@@ -0,0 +1,128 @@
+//! CRUD operations for the per-dir rollups maintained by pg_ivm
+
+use std::collections::HashMap;
+use anyhow::Result;
+use serde::Serialize;
+use sqlx::{Postgres, Transaction};
+
+/// Counts and sizes for the dirents in a dir, counting a file once per dirent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DirRollup {
+    /// The number of dirents
+    pub dirents: i64,
+    /// The number of dirents that point to files
+    pub files: i64,
+    /// The total size of the files
+    pub bytes: i64,
+}
+
+impl DirRollup {
+    /// Return a map of dir id -> rollup of the dirents directly in that dir, for each of `dir_ids`.
+    /// Dirs without dirents are included with zeros.
+    pub async fn find_by_dir_ids(transaction: &mut Transaction<'_, Postgres>, dir_ids: &[i64]) -> Result<HashMap<i64, DirRollup>> {
+        if dir_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let rows = sqlx::query!(r#"
+            SELECT
+                dir_ids.id AS "dir_id!",
+                COALESCE(dir_child_counts.dirents, 0) AS "dirents!",
+                COALESCE(dir_file_sizes.files, 0) AS "files!",
+                COALESCE(dir_file_sizes.bytes, 0)::bigint AS "bytes!"
+            FROM unnest($1::bigint[]) AS dir_ids(id)
+            LEFT JOIN stash.dir_child_counts ON dir_child_counts.dir_id = dir_ids.id
+            LEFT JOIN stash.dir_file_sizes ON dir_file_sizes.dir_id = dir_ids.id"#,
+            dir_ids
+        ).fetch_all(&mut **transaction).await?;
+        Ok(rows.into_iter()
+            .map(|row| (row.dir_id, DirRollup { dirents: row.dirents, files: row.files, bytes: row.bytes }))
+            .collect())
+    }
+
+    /// Return a map of dir id -> rollup of all the dirents below that dir, for each of `dir_ids`.
+    /// This only walks the dirs below each dir, not the file or symlink dirents.
+    pub async fn find_recursive_by_dir_ids(transaction: &mut Transaction<'_, Postgres>, dir_ids: &[i64]) -> Result<HashMap<i64, DirRollup>> {
+        if dir_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        // `child_dir <> 1` filters out the root directory self-reference
+        let rows = sqlx::query!(r#"
+            WITH RECURSIVE tree AS (
+                SELECT id AS root, id AS dir_id
+                FROM unnest($1::bigint[]) AS dir_ids(id)
+                UNION ALL
+                SELECT tree.root, dirents.child_dir
+                FROM stash.dirents
+                JOIN tree ON dirents.parent = tree.dir_id
+                WHERE dirents.child_dir IS NOT NULL AND dirents.child_dir <> 1
+            )
+            SELECT
+                tree.root AS "dir_id!",
+                COALESCE(SUM(dir_child_counts.dirents), 0)::bigint AS "dirents!",
+                COALESCE(SUM(dir_file_sizes.files), 0)::bigint AS "files!",
+                COALESCE(SUM(dir_file_sizes.bytes), 0)::bigint AS "bytes!"
+            FROM tree
+            LEFT JOIN stash.dir_child_counts ON dir_child_counts.dir_id = tree.dir_id
+            LEFT JOIN stash.dir_file_sizes ON dir_file_sizes.dir_id = tree.dir_id
+            GROUP BY tree.root"#,
+            dir_ids
+        ).fetch_all(&mut **transaction).await?;
+        Ok(rows.into_iter()
+            .map(|row| (row.dir_id, DirRollup { dirents: row.dirents, files: row.files, bytes: row.bytes }))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::new_primary_pool;
+    use crate::db::dirent::Dirent;
+    use crate::db::inode::{create_dummy_dir, Birth, InodeId, NewDir, NewFile};
+    use chrono::Utc;
+
+    mod api {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_dir_rollups() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let mut transaction = pool.begin().await?;
+            let parent = create_dummy_dir(&mut transaction, "test_dir_rollups").await?;
+            let empty = create_dummy_dir(&mut transaction, "test_dir_rollups_empty").await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let child = NewDir { mtime: Utc::now(), birth: Birth::here_and_now() }.create(&mut transaction).await?;
+            let file = NewFile { size: 10, executable: false, mtime: Utc::now(), birth: Birth::here_and_now(), b3sum: None }.create(&mut transaction).await?;
+            Dirent::new(parent.id, "child", InodeId::Dir(child.id)).create(&mut transaction).await?;
+            Dirent::new(parent.id, "file", InodeId::File(file.id)).create(&mut transaction).await?;
+            Dirent::new(child.id, "file1", InodeId::File(file.id)).create(&mut transaction).await?;
+            Dirent::new(child.id, "file2", InodeId::File(file.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let rollups = DirRollup::find_by_dir_ids(&mut transaction, &[parent.id, child.id, empty.id]).await?;
+            assert_eq!(rollups[&parent.id], DirRollup { dirents: 2, files: 1, bytes: 10 });
+            assert_eq!(rollups[&child.id], DirRollup { dirents: 2, files: 2, bytes: 20 });
+            assert_eq!(rollups[&empty.id], DirRollup { dirents: 0, files: 0, bytes: 0 });
+            let rollups = DirRollup::find_recursive_by_dir_ids(&mut transaction, &[parent.id, empty.id]).await?;
+            assert_eq!(rollups[&parent.id], DirRollup { dirents: 4, files: 3, bytes: 30 });
+            assert_eq!(rollups[&empty.id], DirRollup { dirents: 0, files: 0, bytes: 0 });
+            transaction.commit().await?;
+
+            // Removing dirents updates the rollups
+            let mut transaction = pool.begin().await?;
+            Dirent::remove_by_parent_basename(&mut transaction, child.id, "file2").await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let rollups = DirRollup::find_recursive_by_dir_ids(&mut transaction, &[parent.id]).await?;
+            assert_eq!(rollups[&parent.id], DirRollup { dirents: 3, files: 2, bytes: 20 });
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
+    }
+}
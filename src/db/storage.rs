@@ -5,6 +5,7 @@ pub mod inline;
 pub mod gdrive;
 pub mod namedfiles;
 pub mod internetarchive;
+pub mod report;
 
 use crate::db;
 use anyhow::Result;
@@ -54,6 +55,19 @@ pub enum StorageView {
     InternetArchive(internetarchive::Storage),
 }
 
+impl StorageView {
+    /// The id of the exastash file for which this storage exists
+    pub fn file_id(&self) -> i64 {
+        match self {
+            StorageView::Fofs(view) => view.file_id,
+            StorageView::Inline(storage) => storage.file_id,
+            StorageView::Gdrive(storage) => storage.file_id,
+            StorageView::NamedFiles(storage) => storage.file_id,
+            StorageView::InternetArchive(storage) => storage.file_id,
+        }
+    }
+}
+
 macro_rules! find_by_file_ids {
     ($pool:ident, $t:ty, $variant:path, $ids:ident) => {
         async {
@@ -157,7 +171,7 @@ mod tests {
             let gdrive_file = gdrive::file::GdriveFile { id: "I".repeat(28), owner_id: None, md5: [0; 16], crc32c: 0, size: 1, last_probed: None };
             gdrive_file.create(&mut transaction).await?;
             let domain = gdrive::tests::create_dummy_domain(&mut transaction).await?;
-            let storage3 = gdrive::Storage { file_id: dummy.id, google_domain: domain.id, cipher: gdrive::Cipher::Aes128Gcm, cipher_key: [0; 16], gdrive_ids: vec![gdrive_file.id.clone()] };
+            let storage3 = gdrive::Storage { file_id: dummy.id, google_domain: domain.id, cipher: gdrive::Cipher::Aes128Gcm, cipher_key: [0; 16], cipher_key_ext: None, gdrive_ids: vec![gdrive_file.id.clone()] };
             storage3.create(&mut transaction).await?;
 
             // inline
@@ -1,11 +1,19 @@
 //! CRUD operations for storage_fofs entities in PostgreSQL
 
-use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use anyhow::{Result, bail};
 use sqlx::{Postgres, Transaction};
 use sqlx::types::Decimal;
 use serde::Serialize;
+use serde_hex::{SerHex, Strict};
+use tracing::warn;
 use crate::storage::read::{read, write_stream_to_sink};
+use crate::blake3::b3sum_local_file;
 use crate::db;
+use crate::util;
 
 /// A pile entity
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, sqlx::FromRow)]
@@ -286,6 +294,216 @@ impl StorageView {
 
 
 
+/// A storage_fofs entity along with the size and b3sum of its file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PileEntry {
+    /// The id of the exastash file for which this storage exists
+    pub file_id: i64,
+    /// The fofs cell that contains a copy of this file
+    pub cell_id: i32,
+    /// Size of the file in bytes
+    pub size: i64,
+    /// b3sum (BLAKE3 hash) for the full content of the file
+    pub b3sum: Option<[u8; 32]>,
+}
+
+#[derive(Debug)]
+struct PileEntryRow {
+    file_id: i64,
+    cell_id: i32,
+    size: i64,
+    b3sum: Option<Vec<u8>>,
+}
+
+impl From<PileEntryRow> for PileEntry {
+    fn from(row: PileEntryRow) -> Self {
+        PileEntry {
+            file_id: row.file_id,
+            cell_id: row.cell_id,
+            size: row.size,
+            b3sum: row.b3sum.map(|o| o.try_into().expect("b3sum from postgres wasn't 32 bytes?")),
+        }
+    }
+}
+
+impl PileEntry {
+    /// Get all storage_fofs entities in cells of the pile with id `pile_id`
+    pub async fn find_by_pile_id(transaction: &mut Transaction<'_, Postgres>, pile_id: i32) -> Result<Vec<PileEntry>> {
+        let rows = sqlx::query_as!(PileEntryRow, r#"
+            SELECT storage_fofs.file_id, storage_fofs.cell_id, files.size, files.b3sum
+            FROM stash.storage_fofs
+            JOIN stash.cells ON cells.id = storage_fofs.cell_id
+            JOIN stash.files ON files.id = storage_fofs.file_id
+            WHERE cells.pile_id = $1"#, pile_id
+        ).fetch_all(&mut **transaction).await?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+}
+
+
+
+/// An inconsistency between the files in a fofs pile and the storage_fofs table
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "problem", rename_all = "kebab-case")]
+pub enum AuditProblem {
+    /// A file in the pile that no storage_fofs row points to
+    Orphan {
+        /// Path to the file
+        path: PathBuf,
+    },
+    /// A storage_fofs row for which the pile has no file
+    Missing {
+        /// Path where the file should be
+        path: PathBuf,
+        /// The id of the exastash file
+        file_id: i64,
+        /// The fofs cell that should contain the file
+        cell_id: i32,
+    },
+    /// A file in the pile that does not have the size of its exastash file
+    WrongSize {
+        /// Path to the file
+        path: PathBuf,
+        /// The id of the exastash file
+        file_id: i64,
+        /// The fofs cell that contains the file
+        cell_id: i32,
+        /// Size of the exastash file
+        expected: i64,
+        /// Size of the file in the pile
+        actual: i64,
+    },
+    /// A file in the pile that does not have the b3sum of its exastash file
+    B3sumMismatch {
+        /// Path to the file
+        path: PathBuf,
+        /// The id of the exastash file
+        file_id: i64,
+        /// The fofs cell that contains the file
+        cell_id: i32,
+        /// b3sum of the exastash file
+        #[serde(with = "SerHex::<Strict>")]
+        expected: [u8; 32],
+        /// b3sum of the file in the pile
+        #[serde(with = "SerHex::<Strict>")]
+        actual: [u8; 32],
+    },
+}
+
+impl fmt::Display for AuditProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditProblem::Orphan { path } => {
+                write!(f, "orphan          {}", path.display())
+            }
+            AuditProblem::Missing { path, .. } => {
+                write!(f, "missing         {}", path.display())
+            }
+            AuditProblem::WrongSize { path, expected, actual, .. } => {
+                write!(f, "wrong-size      {} (expected {expected} bytes, got {actual} bytes)", path.display())
+            }
+            AuditProblem::B3sumMismatch { path, expected, actual, .. } => {
+                write!(f, "b3sum-mismatch  {} (expected {}, got {})", path.display(), hex::encode(expected), hex::encode(actual))
+            }
+        }
+    }
+}
+
+impl AuditProblem {
+    /// Fix the problem: remove an orphan file, drop the storage_fofs row for a missing
+    /// file, or drop the storage_fofs row for a corrupt file and remove the file.
+    pub async fn fix(&self) -> Result<()> {
+        let pool = db::pgpool().await;
+        match self {
+            AuditProblem::Orphan { path } => {
+                tokio::fs::remove_file(path).await?;
+            }
+            AuditProblem::Missing { file_id, cell_id, .. } => {
+                let mut transaction = pool.begin().await?;
+                Storage::delete_by_file_id_and_cell_id(&mut transaction, *file_id, *cell_id).await?;
+                transaction.commit().await?;
+            }
+            AuditProblem::WrongSize { path, file_id, cell_id, .. } |
+            AuditProblem::B3sumMismatch { path, file_id, cell_id, .. } => {
+                let mut transaction = pool.begin().await?;
+                Storage::delete_by_file_id_and_cell_id(&mut transaction, *file_id, *cell_id).await?;
+                transaction.commit().await?;
+                // As in `delete_storages`, remove the database reference first to avoid
+                // the possibility of the database pointing to nonexistent storages.
+                tokio::fs::remove_file(path).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Files modified more recently than this are not reported as orphans,
+/// because they may be in the middle of being added to the pile
+const ORPHAN_MIN_AGE: Duration = Duration::from_secs(3600);
+
+/// Compare the files in a fofs pile on this machine against the storage_fofs rows
+/// for the pile and return the inconsistencies. If `checksum` is set, also compare
+/// the b3sum of each file that has the correct size.
+pub async fn audit_pile(pile: &Pile, checksum: bool) -> Result<Vec<AuditProblem>> {
+    if pile.hostname != util::get_hostname() {
+        bail!("fofs pile {} is on {:?}, not this machine", pile.id, pile.hostname);
+    }
+    let pile_dir = PathBuf::from(format!("{}/{}", pile.path, pile.id));
+    if !tokio::fs::try_exists(&pile_dir).await? {
+        bail!("fofs pile directory {pile_dir:?} does not exist, is the drive mounted?");
+    }
+
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    let entries = PileEntry::find_by_pile_id(&mut transaction, pile.id).await?;
+    transaction.commit().await?; // close read-only transaction
+    let mut entries: HashMap<(i32, i64), PileEntry> = entries.into_iter()
+        .map(|entry| ((entry.cell_id, entry.file_id), entry))
+        .collect();
+
+    let mut problems = vec![];
+    let mut cell_dirs = tokio::fs::read_dir(&pile_dir).await?;
+    while let Some(cell_dir) = cell_dirs.next_entry().await? {
+        let Some(cell_id) = cell_dir.file_name().to_str().and_then(|name| name.parse::<i32>().ok()) else {
+            warn!(path = ?cell_dir.path(), "skipping unexpected entry in fofs pile directory");
+            continue;
+        };
+        let mut files = tokio::fs::read_dir(cell_dir.path()).await?;
+        while let Some(file) = files.next_entry().await? {
+            let path = file.path();
+            let metadata = file.metadata().await?;
+            let file_id = file.file_name().to_str().and_then(|name| name.parse::<i64>().ok());
+            let Some(entry) = file_id.and_then(|file_id| entries.remove(&(cell_id, file_id))) else {
+                let age = SystemTime::now().duration_since(metadata.modified()?).unwrap_or_default();
+                if age >= ORPHAN_MIN_AGE {
+                    problems.push(AuditProblem::Orphan { path });
+                }
+                continue;
+            };
+            let PileEntry { file_id, cell_id, size, b3sum } = entry;
+            let actual = metadata.len() as i64;
+            if actual != size {
+                problems.push(AuditProblem::WrongSize { path, file_id, cell_id, expected: size, actual });
+                continue;
+            }
+            if let (true, Some(expected)) = (checksum, b3sum) {
+                let actual = *b3sum_local_file(&path).await?.as_bytes();
+                if actual != expected {
+                    problems.push(AuditProblem::B3sumMismatch { path, file_id, cell_id, expected, actual });
+                }
+            }
+        }
+    }
+
+    let mut missing: Vec<PileEntry> = entries.into_values().collect();
+    missing.sort_by_key(|entry| (entry.cell_id, entry.file_id));
+    for PileEntry { file_id, cell_id, .. } in missing {
+        let path = pile_dir.join(cell_id.to_string()).join(file_id.to_string());
+        problems.push(AuditProblem::Missing { path, file_id, cell_id });
+    }
+    Ok(problems)
+}
+
 /// Fix all unset b3sums in the database, based on the fofs files we have on a particular host
 pub async fn backfill_b3sums(hostname: &str) -> Result<()> {
     let pool = db::pgpool().await;
@@ -301,3 +519,80 @@ pub async fn backfill_b3sums(hostname: &str) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::inode::{Birth, NewFile};
+    use crate::db::tests::new_primary_pool;
+
+    mod api {
+        use super::*;
+
+        /// audit_pile reports orphan, missing, wrong-size, and b3sum-mismatch files,
+        /// and fixing them leaves nothing to report
+        #[tokio::test]
+        async fn test_audit_pile() -> Result<()> {
+            let pool = new_primary_pool().await;
+            let pile_root = tempfile::tempdir()?;
+            let mut transaction = pool.begin().await?;
+            let pile = NewPile {
+                files_per_cell: 10,
+                hostname: util::get_hostname(),
+                path: pile_root.path().to_str().unwrap().into(),
+                fullness_check_ratio: Decimal::new(1, 0),
+                offline: false,
+            }.create(&mut transaction).await?;
+            let cell = NewCell { pile_id: pile.id }.create(&mut transaction).await?;
+            let content = b"hello";
+            let b3sum = *blake3::hash(content).as_bytes();
+            let mut file_ids = vec![];
+            for _ in 0..4 {
+                let file = NewFile { executable: false, size: content.len() as i64, mtime: util::now_no_nanos(), birth: Birth::here_and_now(), b3sum: Some(b3sum) }
+                    .create(&mut transaction).await?;
+                Storage { file_id: file.id, cell_id: cell.id }.create(&mut transaction).await?;
+                file_ids.push(file.id);
+            }
+            transaction.commit().await?;
+
+            let cell_dir = pile_root.path().join(pile.id.to_string()).join(cell.id.to_string());
+            std::fs::create_dir_all(&cell_dir)?;
+            // good, wrong size, wrong content, and missing
+            std::fs::write(cell_dir.join(file_ids[0].to_string()), content)?;
+            std::fs::write(cell_dir.join(file_ids[1].to_string()), b"hello world")?;
+            std::fs::write(cell_dir.join(file_ids[2].to_string()), b"HELLO")?;
+            // A recent orphan is not reported, an old one is
+            let recent_orphan = cell_dir.join("recent");
+            std::fs::write(&recent_orphan, content)?;
+            let old_orphan = cell_dir.join("old");
+            std::fs::write(&old_orphan, content)?;
+            filetime::set_file_mtime(&old_orphan, filetime::FileTime::from_unix_time(0, 0))?;
+
+            let problems = audit_pile(&pile, false).await?;
+            assert_eq!(problems.len(), 3, "{problems:?}");
+            assert!(problems.contains(&AuditProblem::Orphan { path: old_orphan.clone() }));
+            assert!(problems.contains(&AuditProblem::WrongSize {
+                path: cell_dir.join(file_ids[1].to_string()), file_id: file_ids[1], cell_id: cell.id, expected: 5, actual: 11,
+            }));
+            assert!(problems.contains(&AuditProblem::Missing {
+                path: cell_dir.join(file_ids[3].to_string()), file_id: file_ids[3], cell_id: cell.id,
+            }));
+
+            let problems = audit_pile(&pile, true).await?;
+            assert_eq!(problems.len(), 4, "{problems:?}");
+            assert!(problems.contains(&AuditProblem::B3sumMismatch {
+                path: cell_dir.join(file_ids[2].to_string()), file_id: file_ids[2], cell_id: cell.id,
+                expected: b3sum, actual: *blake3::hash(b"HELLO").as_bytes(),
+            }));
+
+            for problem in &problems {
+                problem.fix().await?;
+            }
+            assert_eq!(audit_pile(&pile, true).await?, vec![]);
+            assert!(recent_orphan.exists());
+            assert!(!old_orphan.exists());
+
+            Ok(())
+        }
+    }
+}
@@ -4,7 +4,7 @@ use anyhow::Result;
 use futures::{StreamExt, TryStreamExt};
 use sqlx::{Postgres, Transaction};
 use serde::Serialize;
-use serde_hex::{SerHex, Strict};
+use serde_hex::{SerHex, SerHexOpt, Strict};
 use uuid::Uuid;
 
 pub mod file;
@@ -22,6 +22,10 @@ pub enum Cipher {
     #[sqlx(rename = "AES_128_GCM")]
     #[serde(rename = "AES_128_GCM")]
     Aes128Gcm,
+    /// AES-256-GCM
+    #[sqlx(rename = "AES_256_GCM")]
+    #[serde(rename = "AES_256_GCM")]
+    Aes256Gcm,
 }
 
 /// A Google Drive folder into which files are uploaded
@@ -56,6 +60,16 @@ impl GdriveParent {
         Ok(parents.pop())
     }
 
+    /// Find all gdrive_parent entities.
+    pub async fn find_all(transaction: &mut Transaction<'_, Postgres>) -> Result<Vec<GdriveParent>> {
+        let parents = sqlx::query_as!(GdriveParent, r#"
+            SELECT name, parent, "full"
+            FROM stash.gdrive_parents
+            ORDER BY name"#
+        ).fetch_all(&mut **transaction).await?;
+        Ok(parents)
+    }
+
     /// Find the first gdrive_parent that is not full.
     pub async fn find_first_non_full(transaction: &mut Transaction<'_, Postgres>) -> Result<Option<GdriveParent>> {
         let maybe_parent = sqlx::query_as!(GdriveParent, r#"
@@ -187,6 +201,9 @@ pub struct Storage {
     /// The cipher key used to encrypt the chunks in gdrive
     #[serde(with = "SerHex::<Strict>")]
     pub cipher_key: [u8; 16],
+    /// The second half of the cipher key, for ciphers with 256-bit keys
+    #[serde(with = "SerHexOpt::<Strict>")]
+    pub cipher_key_ext: Option<[u8; 16]>,
     /// An ordered list of gdrive file IDs
     pub gdrive_ids: Vec<String>,
 }
@@ -198,6 +215,7 @@ impl From<StorageRow> for Storage {
             google_domain: row.google_domain,
             cipher: row.cipher,
             cipher_key: *row.cipher_key.as_bytes(),
+            cipher_key_ext: row.cipher_key_ext.map(|uuid| *uuid.as_bytes()),
             gdrive_ids: row.gdrive_ids,
         }
     }
@@ -209,6 +227,7 @@ struct StorageRow {
     google_domain: i16,
     cipher: Cipher,
     cipher_key: Uuid,
+    cipher_key_ext: Option<Uuid>,
     gdrive_ids: Vec<String>,
 }
 
@@ -218,10 +237,10 @@ impl Storage {
     /// Does not commit the transaction, you must do so yourself.
     pub async fn create(&self, transaction: &mut Transaction<'_, Postgres>) -> Result<()> {
         sqlx::query!(r#"
-            INSERT INTO stash.storage_gdrive (file_id, google_domain, cipher, cipher_key, gdrive_ids)
-            VALUES ($1, $2, $3, $4, $5)"#,
+            INSERT INTO stash.storage_gdrive (file_id, google_domain, cipher, cipher_key, cipher_key_ext, gdrive_ids)
+            VALUES ($1, $2, $3, $4, $5, $6)"#,
             self.file_id, self.google_domain, self.cipher as _,
-            Uuid::from_bytes(self.cipher_key), &self.gdrive_ids
+            Uuid::from_bytes(self.cipher_key), self.cipher_key_ext.map(Uuid::from_bytes), &self.gdrive_ids
         ).execute(&mut **transaction).await?;
         Ok(())
     }
@@ -246,7 +265,7 @@ impl Storage {
         }
         // Note that we can get more than one row per unique file_id
         let storages = sqlx::query_as!(StorageRow, r#"
-            SELECT file_id, google_domain, cipher as "cipher: Cipher", cipher_key, gdrive_ids
+            SELECT file_id, google_domain, cipher as "cipher: Cipher", cipher_key, cipher_key_ext, gdrive_ids
             FROM stash.storage_gdrive
             WHERE file_id = ANY($1)"#, file_ids
         )
@@ -295,6 +314,8 @@ pub(crate) mod tests {
             let mut transaction = pool.begin().await?;
             let maybe_gdrive_parent = GdriveParent::find_by_name(&mut transaction, "test_gdrive_parent").await?;
             assert_eq!(maybe_gdrive_parent, Some(gdrive_parent.clone()));
+            let all_parents = GdriveParent::find_all(&mut transaction).await?;
+            assert!(all_parents.contains(&gdrive_parent));
 
             // Can set the gdrive_parent to full = true
             let mut transaction = pool.begin().await?;
@@ -326,7 +347,27 @@ pub(crate) mod tests {
             let file2 = GdriveFile { id: "X".repeat(160), owner_id: None, md5: [0; 16], crc32c: 100, size: 1000, last_probed: None };
             file2.create(&mut transaction).await?;
             let domain = create_dummy_domain(&mut transaction).await?;
-            let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], gdrive_ids: vec![file1.id, file2.id] };
+            let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], cipher_key_ext: None, gdrive_ids: vec![file1.id, file2.id] };
+            storage.create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            assert_eq!(Storage::find_by_file_ids(&mut transaction, &[dummy.id]).await?, vec![storage]);
+
+            Ok(())
+        }
+
+        /// A storage with a 256-bit cipher key round-trips through the database
+        #[tokio::test]
+        async fn test_create_storage_with_256_bit_key() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let mut transaction = pool.begin().await?;
+            let dummy = create_dummy_file(&mut transaction).await?;
+            let file = GdriveFile { id: "K".repeat(28), owner_id: None, md5: [0; 16], crc32c: 0, size: 1, last_probed: None };
+            file.create(&mut transaction).await?;
+            let domain = create_dummy_domain(&mut transaction).await?;
+            let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes256Gcm, cipher_key: [1; 16], cipher_key_ext: Some([2; 16]), gdrive_ids: vec![file.id] };
             storage.create(&mut transaction).await?;
             transaction.commit().await?;
 
@@ -345,7 +386,7 @@ pub(crate) mod tests {
             let dummy = create_dummy_file(&mut transaction).await?;
             let file = GdriveFile { id: "FileNeverAddedToDatabase".into(), owner_id: None, md5: [0; 16], crc32c: 0, size: 1, last_probed: None };
             let domain = create_dummy_domain(&mut transaction).await?;
-            let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], gdrive_ids: vec![file.id] };
+            let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], cipher_key_ext: None, gdrive_ids: vec![file.id] };
             let result = storage.create(&mut transaction).await;
             assert_eq!(
                 result.expect_err("expected an error").to_string(),
@@ -366,7 +407,7 @@ pub(crate) mod tests {
             file1.create(&mut transaction).await?;
             let file2 = GdriveFile { id: "FileNeverAddedToDatabase".into(), owner_id: None, md5: [0; 16], crc32c: 0, size: 1, last_probed: None };
             let domain = create_dummy_domain(&mut transaction).await?;
-            let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], gdrive_ids: vec![file1.id, file2.id] };
+            let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], cipher_key_ext: None, gdrive_ids: vec![file1.id, file2.id] };
             let result = storage.create(&mut transaction).await;
             assert_eq!(
                 result.expect_err("expected an error").to_string(),
@@ -384,7 +425,7 @@ pub(crate) mod tests {
             let mut transaction = pool.begin().await?;
             let dummy = create_dummy_file(&mut transaction).await?;
             let domain = create_dummy_domain(&mut transaction).await?;
-            let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], gdrive_ids: vec![] };
+            let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], cipher_key_ext: None, gdrive_ids: vec![] };
             let result = storage.create(&mut transaction).await;
             assert_eq!(
                 result.expect_err("expected an error").to_string(),
@@ -413,7 +454,7 @@ pub(crate) mod tests {
             file1.create(&mut transaction).await?;
             GdriveFile { id: id2.clone(), owner_id: None, md5: [0; 16], crc32c: 0, size: 1, last_probed: None }.create(&mut transaction).await?;
             let domain = create_dummy_domain(&mut transaction).await?;
-            Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], gdrive_ids: vec![file1.id] }.create(&mut transaction).await?;
+            Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], cipher_key_ext: None, gdrive_ids: vec![file1.id] }.create(&mut transaction).await?;
             transaction.commit().await?;
 
             let pairs = [
@@ -421,6 +462,7 @@ pub(crate) mod tests {
                 ("google_domain", "100"),
                 ("cipher", "'AES_128_CTR'::stash.cipher"),
                 ("cipher_key", "'1111-1111-1111-1111-1111-1111-1111-1111'::uuid"),
+                ("cipher_key_ext", "'1111-1111-1111-1111-1111-1111-1111-1111'::uuid"),
                 ("gdrive_ids", &format!("'{{\"{id1}\",\"{id2}\"}}'::text[]"))
             ];
 
@@ -437,6 +479,31 @@ pub(crate) mod tests {
             Ok(())
         }
 
+        /// cipher_key_ext must be present for AES_256_GCM and absent otherwise
+        #[tokio::test]
+        async fn test_cipher_key_ext_matches_cipher() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let mut transaction = pool.begin().await?;
+            let file = GdriveFile { id: "E".repeat(28), owner_id: None, md5: [0; 16], crc32c: 0, size: 1, last_probed: None };
+            file.create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            for (cipher, cipher_key_ext) in [(Cipher::Aes256Gcm, None), (Cipher::Aes128Gcm, Some([0; 16]))] {
+                let mut transaction = pool.begin().await?;
+                let dummy = create_dummy_file(&mut transaction).await?;
+                let domain = create_dummy_domain(&mut transaction).await?;
+                let storage = Storage { file_id: dummy.id, google_domain: domain.id, cipher, cipher_key: [0; 16], cipher_key_ext, gdrive_ids: vec![file.id.clone()] };
+                let result = storage.create(&mut transaction).await;
+                assert_eq!(
+                    result.expect_err("expected an error").to_string(),
+                    "error returned from database: new row for relation \"storage_gdrive\" violates check constraint \"storage_gdrive_check\""
+                );
+            }
+
+            Ok(())
+        }
+
         /// Cannot TRUNCATE storage_gdrive table
         #[tokio::test]
         #[serial]
@@ -448,7 +515,7 @@ pub(crate) mod tests {
             let file = GdriveFile { id: "T".repeat(28),  owner_id: None, md5: [0; 16], crc32c: 0, size: 1, last_probed: None };
             file.create(&mut transaction).await?;
             let domain = create_dummy_domain(&mut transaction).await?;
-            Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], gdrive_ids: vec![file.id] }.create(&mut transaction).await?;
+            Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], cipher_key_ext: None, gdrive_ids: vec![file.id] }.create(&mut transaction).await?;
             transaction.commit().await?;
 
             let mut transaction = pool.begin().await?;
@@ -161,6 +161,14 @@ impl GdriveFile {
         Ok(())
     }
 
+    /// Return the ids of all gdrive files in the database.
+    pub async fn find_all_ids(transaction: &mut Transaction<'_, Postgres>) -> Result<Vec<String>> {
+        let ids = sqlx::query_scalar!(r#"
+            SELECT id FROM stash.gdrive_files"#
+        ).fetch_all(&mut **transaction).await?;
+        Ok(ids)
+    }
+
     /// Return gdrive files with matching ids, in the same order as the ids.
     pub async fn find_by_ids_in_order(transaction: &mut Transaction<'_, Postgres>, ids: &[&str]) -> Result<Vec<GdriveFile>> {
         // sqlx::query_as! insists on String
@@ -224,6 +232,10 @@ pub(crate) mod tests {
             let files = GdriveFile::find_by_ids_in_order(&mut transaction, &[&file1.id, &file2.id]).await?;
             assert_eq!(files, vec![file1.clone(), file2.clone()]);
 
+            let all_ids = GdriveFile::find_all_ids(&mut transaction).await?;
+            assert!(all_ids.contains(&file1.id));
+            assert!(all_ids.contains(&file2.id));
+
             // Files are returned in the same order as ids
             let files = GdriveFile::find_by_ids_in_order(&mut transaction, &[&file2.id, &file1.id]).await?;
             assert_eq!(files, vec![file2.clone(), file1.clone()]);
@@ -274,7 +286,7 @@ pub(crate) mod tests {
             transaction.commit().await?;
 
             let mut transaction = pool.begin().await?;
-            Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], gdrive_ids: vec![file.id.clone()] }.create(&mut transaction).await?;
+            Storage { file_id: dummy.id, google_domain: domain.id, cipher: Cipher::Aes128Gcm, cipher_key: [0; 16], cipher_key_ext: None, gdrive_ids: vec![file.id.clone()] }.create(&mut transaction).await?;
             transaction.commit().await?;
 
             let mut transaction = pool.begin().await?;
@@ -0,0 +1,279 @@
+//! Aggregate queries for reporting how much is stored in each fofs pile and cell,
+//! google domain, and gdrive owner
+
+use anyhow::Result;
+use sqlx::{Postgres, Transaction};
+use serde::Serialize;
+
+/// The files stored in a fofs pile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PileUsage {
+    /// The pile id
+    pub pile_id: i32,
+    /// The machine on which the pile is stored
+    pub hostname: String,
+    /// The absolute path to the root directory of the pile on the machine
+    pub path: String,
+    /// Whether the pile is on a drive that is normally expected to be offline
+    pub offline: bool,
+    /// The number of cells in the pile
+    pub cells: i64,
+    /// The number of cells in the pile that are marked full
+    pub full_cells: i64,
+    /// The number of files stored in the pile
+    pub files: i64,
+    /// The total size of the files stored in the pile
+    pub bytes: i64,
+    /// The number of files stored in cells that are not yet full
+    pub files_in_open_cells: i64,
+    /// The total size of the files stored in cells that are not yet full
+    pub bytes_in_open_cells: i64,
+}
+
+impl PileUsage {
+    /// Return the usage of every pile, ordered by pile id
+    pub async fn find_all(transaction: &mut Transaction<'_, Postgres>) -> Result<Vec<PileUsage>> {
+        let piles = sqlx::query_as!(PileUsage, r#"
+            SELECT
+                piles.id AS pile_id,
+                piles.hostname AS "hostname!",
+                piles.path,
+                piles.offline,
+                COUNT(DISTINCT cells.id) AS "cells!",
+                COUNT(DISTINCT cells.id) FILTER (WHERE cells.full) AS "full_cells!",
+                COUNT(files.id) AS "files!",
+                COALESCE(SUM(files.size), 0)::bigint AS "bytes!",
+                COUNT(files.id) FILTER (WHERE NOT cells.full) AS "files_in_open_cells!",
+                COALESCE(SUM(files.size) FILTER (WHERE NOT cells.full), 0)::bigint AS "bytes_in_open_cells!"
+            FROM stash.piles
+            LEFT JOIN stash.cells ON cells.pile_id = piles.id
+            LEFT JOIN stash.storage_fofs ON storage_fofs.cell_id = cells.id
+            LEFT JOIN stash.files ON files.id = storage_fofs.file_id
+            GROUP BY piles.id
+            ORDER BY piles.id"#
+        ).fetch_all(&mut **transaction).await?;
+        Ok(piles)
+    }
+}
+
+/// The files stored in a fofs cell
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CellUsage {
+    /// The cell id
+    pub cell_id: i32,
+    /// The pile the cell is in
+    pub pile_id: i32,
+    /// Whether the cell is marked full
+    pub full: bool,
+    /// The number of files stored in the cell
+    pub files: i64,
+    /// The total size of the files stored in the cell
+    pub bytes: i64,
+}
+
+impl CellUsage {
+    /// Return the usage of every cell, ordered by pile id and cell id
+    pub async fn find_all(transaction: &mut Transaction<'_, Postgres>) -> Result<Vec<CellUsage>> {
+        let cells = sqlx::query_as!(CellUsage, r#"
+            SELECT
+                cells.id AS cell_id,
+                cells.pile_id,
+                cells.full,
+                COUNT(files.id) AS "files!",
+                COALESCE(SUM(files.size), 0)::bigint AS "bytes!"
+            FROM stash.cells
+            LEFT JOIN stash.storage_fofs ON storage_fofs.cell_id = cells.id
+            LEFT JOIN stash.files ON files.id = storage_fofs.file_id
+            GROUP BY cells.id
+            ORDER BY cells.pile_id, cells.id"#
+        ).fetch_all(&mut **transaction).await?;
+        Ok(cells)
+    }
+}
+
+/// The files stored in a google domain, and how much the stored chunks take up
+/// beyond the content of the files
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DomainUsage {
+    /// The google_domain id
+    pub domain_id: i16,
+    /// The domain name
+    pub domain: String,
+    /// The number of files stored in the domain
+    pub files: i64,
+    /// The total size of the content of the files
+    pub content_bytes: i64,
+    /// The total size of the gdrive files holding the encrypted files
+    pub stored_bytes: i64,
+    /// The bytes used by AES-GCM authentication tags
+    pub gcm_tag_bytes: i64,
+    /// The remaining stored bytes, which are the padding added by `conceal_size`
+    pub padding_bytes: i64,
+}
+
+impl DomainUsage {
+    /// Return the usage of every google domain, ordered by domain id
+    pub async fn find_all(transaction: &mut Transaction<'_, Postgres>) -> Result<Vec<DomainUsage>> {
+        // The GCM tag count must match `get_aes_gcm_length`: one 16-byte tag for
+        // every started block of 65536 - 16 bytes of content. AES_128_CTR has no tags.
+        let rows = sqlx::query!(r#"
+            SELECT
+                google_domains.id AS domain_id,
+                google_domains.domain,
+                COUNT(files.id) AS "files!",
+                COALESCE(SUM(files.size), 0)::bigint AS "content_bytes!",
+                COALESCE(SUM(chunks.size), 0)::bigint AS "stored_bytes!",
+                COALESCE(SUM(
+                    CASE WHEN storage_gdrive.cipher = 'AES_128_CTR' THEN 0
+                    ELSE 16 * ((files.size + 65519) / 65520) END
+                ), 0)::bigint AS "gcm_tag_bytes!"
+            FROM stash.google_domains
+            LEFT JOIN stash.storage_gdrive ON storage_gdrive.google_domain = google_domains.id
+            LEFT JOIN stash.files ON files.id = storage_gdrive.file_id
+            LEFT JOIN LATERAL (
+                SELECT SUM(gdrive_files.size) AS size
+                FROM stash.gdrive_files
+                WHERE gdrive_files.id = ANY(storage_gdrive.gdrive_ids)
+            ) AS chunks ON true
+            GROUP BY google_domains.id
+            ORDER BY google_domains.id"#
+        ).fetch_all(&mut **transaction).await?;
+        Ok(rows.into_iter().map(|row| DomainUsage {
+            domain_id: row.domain_id,
+            domain: row.domain,
+            files: row.files,
+            content_bytes: row.content_bytes,
+            stored_bytes: row.stored_bytes,
+            gcm_tag_bytes: row.gcm_tag_bytes,
+            padding_bytes: row.stored_bytes - row.content_bytes - row.gcm_tag_bytes,
+        }).collect())
+    }
+}
+
+/// The gdrive files owned by a gdrive owner
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OwnerUsage {
+    /// The gdrive_owners id, or `None` for gdrive files with no recorded owner
+    pub owner_id: Option<i32>,
+    /// The email address or other identifier of the owner
+    pub owner: Option<String>,
+    /// The google_domain id of the owner
+    pub domain_id: Option<i16>,
+    /// The number of gdrive files owned
+    pub gdrive_files: i64,
+    /// The total size of the gdrive files owned
+    pub bytes: i64,
+}
+
+impl OwnerUsage {
+    /// Return the usage of every gdrive owner, ordered by owner id, followed by
+    /// the usage of gdrive files with no recorded owner if there are any
+    pub async fn find_all(transaction: &mut Transaction<'_, Postgres>) -> Result<Vec<OwnerUsage>> {
+        let owners = sqlx::query_as!(OwnerUsage, r#"
+            SELECT
+                gdrive_owners.id AS "owner_id?",
+                gdrive_owners.owner AS "owner?",
+                gdrive_owners.domain AS "domain_id?",
+                COUNT(gdrive_files.id) AS "gdrive_files!",
+                COALESCE(SUM(gdrive_files.size), 0)::bigint AS "bytes!"
+            FROM stash.gdrive_owners
+            FULL JOIN stash.gdrive_files ON gdrive_files.owner = gdrive_owners.id
+            GROUP BY gdrive_owners.id
+            ORDER BY gdrive_owners.id NULLS LAST"#
+        ).fetch_all(&mut **transaction).await?;
+        Ok(owners)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conceal_size::conceal_size;
+    use crate::db::inode::{Birth, NewFile};
+    use crate::db::storage::{fofs, gdrive};
+    use crate::db::storage::gdrive::file::GdriveFile;
+    use crate::db::storage::gdrive::file::tests::create_dummy_owner;
+    use crate::db::storage::gdrive::tests::create_dummy_domain;
+    use crate::db::tests::new_primary_pool;
+    use crate::storage::read::get_aes_gcm_length;
+    use chrono::Utc;
+
+    mod api {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_storage_report() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let mut transaction = pool.begin().await?;
+            let size = 200_000;
+            let file = NewFile { executable: false, size, mtime: Utc::now(), birth: Birth::here_and_now(), b3sum: None }.create(&mut transaction).await?;
+
+            let pile = fofs::NewPile { files_per_cell: 10, hostname: "localhost".into(), path: "/tmp/fake-fofs".into(), fullness_check_ratio: 1.into(), offline: false }.create(&mut transaction).await?;
+            let full_cell = fofs::NewCell { pile_id: pile.id }.create(&mut transaction).await?;
+            fofs::Cell::set_full(&mut transaction, full_cell.id, true).await?;
+            let open_cell = fofs::NewCell { pile_id: pile.id }.create(&mut transaction).await?;
+            fofs::Storage { file_id: file.id, cell_id: full_cell.id }.create(&mut transaction).await?;
+            fofs::Storage { file_id: file.id, cell_id: open_cell.id }.create(&mut transaction).await?;
+
+            let domain = create_dummy_domain(&mut transaction).await?;
+            let owner = create_dummy_owner(&mut transaction, domain.id).await?;
+            let encrypted_size = get_aes_gcm_length(size as u64, 65536 - 16);
+            let stored_size = conceal_size(encrypted_size) as i64;
+            let gdrive_file = GdriveFile { id: "StorageReportTest".repeat(2), owner_id: Some(owner.id), md5: [0; 16], crc32c: 0, size: stored_size, last_probed: None };
+            gdrive_file.create(&mut transaction).await?;
+            gdrive::Storage { file_id: file.id, google_domain: domain.id, cipher: gdrive::Cipher::Aes128Gcm, cipher_key: [0; 16], cipher_key_ext: None, gdrive_ids: vec![gdrive_file.id.clone()] }.create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let piles = PileUsage::find_all(&mut transaction).await?;
+            let pile_usage = piles.into_iter().find(|usage| usage.pile_id == pile.id).unwrap();
+            assert_eq!(pile_usage, PileUsage {
+                pile_id: pile.id,
+                hostname: "localhost".into(),
+                path: "/tmp/fake-fofs".into(),
+                offline: false,
+                cells: 2,
+                full_cells: 1,
+                files: 2,
+                bytes: 2 * size,
+                files_in_open_cells: 1,
+                bytes_in_open_cells: size,
+            });
+
+            let cells: Vec<CellUsage> = CellUsage::find_all(&mut transaction).await?
+                .into_iter()
+                .filter(|usage| usage.pile_id == pile.id)
+                .collect();
+            assert_eq!(cells, vec![
+                CellUsage { cell_id: full_cell.id, pile_id: pile.id, full: true, files: 1, bytes: size },
+                CellUsage { cell_id: open_cell.id, pile_id: pile.id, full: false, files: 1, bytes: size },
+            ]);
+
+            let domains = DomainUsage::find_all(&mut transaction).await?;
+            let domain_usage = domains.into_iter().find(|usage| usage.domain_id == domain.id).unwrap();
+            assert_eq!(domain_usage, DomainUsage {
+                domain_id: domain.id,
+                domain: domain.domain.clone(),
+                files: 1,
+                content_bytes: size,
+                stored_bytes: stored_size,
+                gcm_tag_bytes: encrypted_size as i64 - size,
+                padding_bytes: stored_size - encrypted_size as i64,
+            });
+
+            let owners = OwnerUsage::find_all(&mut transaction).await?;
+            let owner_usage = owners.into_iter().find(|usage| usage.owner_id == Some(owner.id)).unwrap();
+            assert_eq!(owner_usage, OwnerUsage {
+                owner_id: Some(owner.id),
+                owner: Some(owner.owner.clone()),
+                domain_id: Some(domain.id),
+                gdrive_files: 1,
+                bytes: stored_size,
+            });
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
+    }
+}
@@ -1,10 +1,13 @@
 //! Functions for walking a path from a base_dir
 
-use chrono::Utc;
-use anyhow::{anyhow, bail, Result};
+use std::collections::{HashMap, HashSet};
+use chrono::{DateTime, Utc};
+use anyhow::{anyhow, bail, ensure, Result};
+use futures::stream::{BoxStream, StreamExt};
+use serde::Serialize;
 use sqlx::{Postgres, Transaction};
-use crate::db::dirent::Dirent;
-use crate::db::inode::{InodeId, NewDir, Birth};
+use crate::db::dirent::{Dirent, InodeTuple};
+use crate::db::inode::{InodeId, Inode, Dir, File, Symlink, NewDir, Birth};
 
 /// Error traversing a path
 #[derive(thiserror::Error, Debug)]
@@ -17,65 +20,507 @@ pub enum TraversalError {
         /// The basename for the expected directory entry
         basename: String,
     },
+
+    /// A directory entry already exists where we wanted to create one
+    #[error("dirent {basename:?} already exists under dir {parent:?}")]
+    DirentExists {
+        /// The parent of the existing directory entry
+        parent: i64,
+        /// The basename of the existing directory entry
+        basename: String,
+    },
+
+    /// A dir cannot be moved into itself or one of its descendants
+    #[error("cannot move dir {dir:?} into dir {new_parent:?}, which is the same dir or one of its descendants")]
+    MoveIntoSelf {
+        /// The dir being moved
+        dir: i64,
+        /// The requested new parent
+        new_parent: i64,
+    },
 }
 
-/// Returns the inode referenced by the last path segment, starting from some base directory.
+/// Advisory lock key held while moving a dir, to serialize dir moves
+const DIR_MOVE_LOCK_KEY: i64 = 0x6578_6d76; // "exmv"
+
+/// Returns the dirent for each path segment, starting from some base directory,
+/// using a single query instead of a round trip per segment.
+/// If `as_of` is given, walks the dirents that existed at that time instead.
 /// Does not resolve symlinks.
-pub async fn resolve_inode<S: AsRef<str> + ToString + Clone>(transaction: &mut Transaction<'_, Postgres>, base_dir: i64, path_components: &[S]) -> Result<InodeId> {
-    let mut current_inode = InodeId::Dir(base_dir);
-    for component in path_components {
-        let dir_id = current_inode.dir_id()?;
-        if let Some(dirent) = Dirent::find_by_parent_and_basename(transaction, dir_id, component.as_ref()).await? {
-            current_inode = dirent.child;
-        } else {
-            bail!(TraversalError::NoDirent { parent: dir_id, basename: component.to_string() });
-        }
+pub async fn resolve_dirents<S: AsRef<str> + ToString + Clone>(
+    transaction: &mut Transaction<'_, Postgres>,
+    base_dir: i64,
+    path_components: &[S],
+    as_of: Option<DateTime<Utc>>,
+) -> Result<Vec<Dirent>> {
+    let basenames: Vec<String> = path_components.iter().map(ToString::to_string).collect();
+    let dirents = Dirent::find_chain(transaction, base_dir, &basenames, as_of).await?;
+    if dirents.len() < basenames.len() {
+        // Report the first segment that could not be walked, the same way a walk
+        // one segment at a time would have.
+        let last_inode = dirents.last().map_or(InodeId::Dir(base_dir), |dirent| dirent.child);
+        let dir_id = last_inode.dir_id()?;
+        bail!(TraversalError::NoDirent { parent: dir_id, basename: basenames[dirents.len()].clone() });
     }
-    Ok(current_inode)
+    Ok(dirents)
+}
+
+/// Returns the inode referenced by the last path segment, starting from some base directory.
+/// If `as_of` is given, walks the dirents that existed at that time instead.
+/// Does not resolve symlinks.
+pub async fn resolve_inode<S: AsRef<str> + ToString + Clone>(
+    transaction: &mut Transaction<'_, Postgres>,
+    base_dir: i64,
+    path_components: &[S],
+    as_of: Option<DateTime<Utc>>,
+) -> Result<InodeId> {
+    let dirents = resolve_dirents(transaction, base_dir, path_components, as_of).await?;
+    Ok(dirents.last().map_or(InodeId::Dir(base_dir), |dirent| dirent.child))
 }
 
 /// Returns the dirent referenced by the last path segment, starting from some base directory.
 /// Does not resolve symlinks.
 pub async fn resolve_dirent<S: AsRef<str> + ToString + Clone>(transaction: &mut Transaction<'_, Postgres>, base_dir: i64, path_components: &[S]) -> Result<Dirent> {
-    let mut current_inode = InodeId::Dir(base_dir);
-    let mut last_dirent = None;
-    for component in path_components {
-        let dir_id = current_inode.dir_id()?;
-        if let Some(dirent) = Dirent::find_by_parent_and_basename(transaction, dir_id, component.as_ref()).await? {
-            current_inode = dirent.child;
-            last_dirent = Some(dirent);
-        } else {
-            bail!(TraversalError::NoDirent { parent: dir_id, basename: component.to_string() });
-        }
-    }
-    last_dirent.ok_or_else(|| anyhow!("resolve_dirent: need at least one path segment to traverse"))
+    let mut dirents = resolve_dirents(transaction, base_dir, path_components, None).await?;
+    dirents.pop().ok_or_else(|| anyhow!("resolve_dirent: need at least one path segment to traverse"))
 }
 
 /// Resolve path_components but also create new dirs as needed, like `mkdir -p`.
 /// Returns the `InodeId` of the dir created by the last path segment.
+/// If `mtimes` is given, it must have one mtime per path component, used for the
+/// corresponding dir if it needs to be created; otherwise, new dirs get the current time.
 /// Sets `stash.unsafe_internal_dirent_creation` to `1` on the transaction.
 /// Does not commit the transaction, you must do so yourself.
-pub async fn make_dirs<S: AsRef<str> + ToString + Clone>(transaction: &mut Transaction<'_, Postgres>, base_dir: i64, path_components: &[S]) -> Result<InodeId> {
+pub async fn make_dirs<S: AsRef<str> + ToString + Clone>(
+    transaction: &mut Transaction<'_, Postgres>,
+    base_dir: i64,
+    path_components: &[S],
+    mtimes: Option<&[DateTime<Utc>]>,
+) -> Result<InodeId> {
+    if let Some(mtimes) = mtimes {
+        ensure!(mtimes.len() == path_components.len(),
+            "make_dirs: got {} mtimes for {} path components", mtimes.len(), path_components.len());
+    }
+
     // We trust ourselves to not create circular references here
     sqlx::query!("SET stash.unsafe_internal_dirent_creation = 1").execute(&mut **transaction).await?;
 
-    let mut current_inode = InodeId::Dir(base_dir);
-    for component in path_components {
+    // Find the dirs that already exist in one query, then create the rest
+    let basenames: Vec<String> = path_components.iter().map(ToString::to_string).collect();
+    let existing = Dirent::find_chain(transaction, base_dir, &basenames, None).await?;
+    let mut current_inode = existing.last().map_or(InodeId::Dir(base_dir), |dirent| dirent.child);
+    for (i, component) in path_components.iter().enumerate().skip(existing.len()) {
         let dir_id = current_inode.dir_id()?;
-        if let Some(dirent) = Dirent::find_by_parent_and_basename(transaction, dir_id, component.as_ref()).await? {
-            current_inode = dirent.child;
-        } else {
-            let mtime = Utc::now();
-            let birth = Birth::here_and_now();
-            let dir = NewDir { mtime, birth }.create(transaction).await?;
-            Dirent::new(dir_id, component.as_ref(), InodeId::Dir(dir.id)).create(transaction).await?;
-
-            current_inode = InodeId::Dir(dir.id);
-        }
+        let mtime = mtimes.map_or_else(Utc::now, |mtimes| mtimes[i]);
+        let birth = Birth::here_and_now();
+        let dir = NewDir { mtime, birth }.create(transaction).await?;
+        Dirent::new(dir_id, component.as_ref(), InodeId::Dir(dir.id)).create(transaction).await?;
+
+        current_inode = InodeId::Dir(dir.id);
     }
     Ok(current_inode)
 }
 
+/// Move the dirent named `basename` under `parent` to `new_basename` under `new_parent`,
+/// renaming and/or re-parenting a file, symlink, or dir.
+///
+/// When moving a dir, checks that `new_parent` is not the moved dir or one of its
+/// descendants, while holding a transaction-level advisory lock that serializes dir
+/// moves, so that two concurrent moves cannot together create a cycle. Sets
+/// `stash.unsafe_internal_dirent_creation` to `1` for the rest of the transaction.
+/// Does not commit the transaction, you must do so yourself.
+pub async fn move_dirent(
+    transaction: &mut Transaction<'_, Postgres>,
+    parent: i64,
+    basename: &str,
+    new_parent: i64,
+    new_basename: &str,
+) -> Result<Dirent> {
+    let dirent = Dirent::find_by_parent_and_basename(transaction, parent, basename).await?
+        .ok_or_else(|| TraversalError::NoDirent { parent, basename: basename.to_string() })?;
+    if parent == new_parent && basename == new_basename {
+        return Ok(dirent);
+    }
+    if Dirent::find_by_parent_and_basename(transaction, new_parent, new_basename).await?.is_some() {
+        bail!(TraversalError::DirentExists { parent: new_parent, basename: new_basename.to_string() });
+    }
+
+    if let InodeId::Dir(dir_id) = dirent.child {
+        sqlx::query("SELECT pg_advisory_xact_lock($1)").bind(DIR_MOVE_LOCK_KEY).execute(&mut **transaction).await?;
+        let root_dir = 1;
+        let mut ancestor = new_parent;
+        loop {
+            if ancestor == dir_id {
+                bail!(TraversalError::MoveIntoSelf { dir: dir_id, new_parent });
+            }
+            if ancestor == root_dir {
+                break;
+            }
+            ancestor = Dirent::find_by_child_dir(transaction, ancestor).await?
+                .ok_or_else(|| anyhow!("no dirent with child dir {}", ancestor))?
+                .parent;
+        }
+        // We checked for cycles above, and we need to both remove and create a dirent with a child_dir
+        sqlx::query!("SET LOCAL stash.unsafe_internal_dirent_creation = 1").execute(&mut **transaction).await?;
+    }
+
+    dirent.remove(transaction).await?;
+    let new_dirent = Dirent::new(new_parent, new_basename, dirent.child);
+    new_dirent.create(transaction).await?;
+    Ok(new_dirent)
+}
+
+/// Remove `dirent`, and if it points to a dir, remove that dir and all of its descendant
+/// dirents and dirs. Does not delete any files or symlinks.
+///
+/// Everything is removed in this transaction, so that all of the removed dirents share a
+/// `row_end` in the dirents_history table and the whole subtree can be found by `find_removed_tree`.
+/// The removed dirs are kept in the dirs_history table for `restore_tree`.
+/// The subtree is walked before anything is removed, so that the dir rollups, which
+/// pg_ivm locks until commit (see schema/dir_rollups.sql), are locked only briefly.
+/// Sets `stash.unsafe_internal_dirent_creation` to `1` on the transaction if `dirent` points to a dir.
+/// Does not commit the transaction, you must do so yourself.
+pub async fn remove_tree(transaction: &mut Transaction<'_, Postgres>, dirent: &Dirent) -> Result<()> {
+    let InodeId::Dir(dir_id) = dirent.child else {
+        return dirent.remove(transaction).await;
+    };
+
+    let mut dir_ids = vec![];
+    let mut level = vec![dir_id];
+    while !level.is_empty() {
+        let dirents = Dirent::find_by_parents(transaction, &level, None).await?;
+        dir_ids.append(&mut level);
+        level = dirents.iter().filter_map(|dirent| dirent.child.dir_id().ok()).collect();
+    }
+
+    // Removing dirents cannot create cycles, and we need to remove many dirents with a child_dir
+    sqlx::query!("SET LOCAL stash.unsafe_internal_dirent_creation = 1").execute(&mut **transaction).await?;
+    Dirent::remove_by_parents(transaction, &dir_ids).await?;
+    dirent.remove(transaction).await?;
+    Dir::delete(transaction, &dir_ids).await?;
+    Ok(())
+}
+
+/// Find the dirent named `basename` under dir `parent` that no longer exists, as it was at
+/// time `as_of`, or if `as_of` is not given, the most recently removed one, as it was just
+/// before it was removed. If it points to a dir, also find the dirents in the subtree below
+/// it as of that time. Returns the dirents in breadth-first order, starting with the one
+/// at `basename`, and the time they were found as of.
+pub async fn find_removed_tree(
+    transaction: &mut Transaction<'_, Postgres>,
+    parent: i64,
+    basename: &str,
+    as_of: Option<DateTime<Utc>>,
+) -> Result<(Vec<Dirent>, DateTime<Utc>)> {
+    if Dirent::find_by_parent_and_basename(transaction, parent, basename).await?.is_some() {
+        bail!(TraversalError::DirentExists { parent, basename: basename.to_string() });
+    }
+    let (dirent, as_of) = match as_of {
+        Some(as_of) => {
+            let dirent = Dirent::find_by_parent_and_basename_as_of(transaction, parent, basename, as_of).await?
+                .ok_or_else(|| anyhow!("no dirent {basename:?} under dir {parent:?} as of {as_of}"))?;
+            (dirent, as_of)
+        }
+        None => {
+            let (dirent, removed) = Dirent::find_last_removed(transaction, parent, basename).await?
+                .ok_or_else(|| anyhow!("no removed dirent {basename:?} under dir {parent:?} in history"))?;
+            // Timestamps have microsecond precision
+            (dirent, removed - chrono::Duration::microseconds(1))
+        }
+    };
+
+    let mut dirents = vec![];
+    let mut level: Vec<i64> = dirent.child.dir_id().into_iter().collect();
+    dirents.push(dirent);
+    while !level.is_empty() {
+        let children = Dirent::find_by_parents(transaction, &level, Some(as_of)).await?;
+        level = children.iter().filter_map(|dirent| dirent.child.dir_id().ok()).collect();
+        dirents.extend(children);
+    }
+    Ok((dirents, as_of))
+}
+
+/// Restore `dirents` found by `find_removed_tree` as of time `as_of`, recreating the dirs
+/// they point to with the mtimes and birth information they had at that time. Dirs that
+/// are missing from the dirs_history table get `as_of` as their mtime and a new birth.
+///
+/// Fails before writing anything if a dir in the subtree still exists elsewhere or if a
+/// file or symlink in the subtree no longer exists (e.g. because `es gc` deleted it).
+/// Everything is restored in this transaction, so a failure does not leave a partially
+/// restored subtree. Sets `stash.unsafe_internal_dirent_creation` to `1` on the transaction.
+/// Does not commit the transaction, you must do so yourself.
+pub async fn restore_tree(transaction: &mut Transaction<'_, Postgres>, dirents: &[Dirent], as_of: DateTime<Utc>) -> Result<()> {
+    let dir_ids: Vec<i64> = dirents.iter().filter_map(|dirent| dirent.child.dir_id().ok()).collect();
+    let file_ids: Vec<i64> = dirents.iter().filter_map(|dirent| dirent.child.file_id().ok()).collect();
+    let symlink_ids: Vec<i64> = dirents.iter().filter_map(|dirent| dirent.child.symlink_id().ok()).collect();
+    if let Some(dir) = Dir::find_by_ids(transaction, &dir_ids).await?.first() {
+        bail!("dir {:?} in the subtree still exists elsewhere", dir.id);
+    }
+    let files: HashSet<i64> = File::find_by_ids(transaction, &file_ids).await?.iter().map(|file| file.id).collect();
+    if let Some(id) = file_ids.iter().find(|id| !files.contains(id)) {
+        bail!("file {id:?} in the subtree no longer exists");
+    }
+    let symlinks: HashSet<i64> = Symlink::find_by_ids(transaction, &symlink_ids).await?.iter().map(|symlink| symlink.id).collect();
+    if let Some(id) = symlink_ids.iter().find(|id| !symlinks.contains(id)) {
+        bail!("symlink {id:?} in the subtree no longer exists");
+    }
+
+    let mut old_dirs: HashMap<i64, Dir> = Dir::find_by_ids_as_of(transaction, &dir_ids, as_of).await?
+        .into_iter()
+        .map(|dir| (dir.id, dir))
+        .collect();
+    let birth = Birth::here_and_now();
+    for id in &dir_ids {
+        let new_dir = match old_dirs.remove(id) {
+            Some(dir) => NewDir { mtime: dir.mtime, birth: dir.birth },
+            None => NewDir { mtime: as_of, birth: birth.clone() },
+        };
+        new_dir.create_with_id(transaction, *id).await?;
+    }
+
+    // The restored dirs do not exist anywhere else, so the restored dirents cannot
+    // create cycles, and we need to create many dirents with a child_dir
+    sqlx::query!("SET LOCAL stash.unsafe_internal_dirent_creation = 1").execute(&mut **transaction).await?;
+    for dirent in dirents {
+        dirent.create(transaction).await?;
+    }
+    Ok(())
+}
+
+/// A dirent found by `walk`, along with the inode it points to
+#[derive(Debug, PartialEq, Eq)]
+pub struct WalkEntry {
+    /// The path from the dir walked from to the dirent, with components separated by `/`
+    pub path: String,
+    /// The directory entry
+    pub dirent: Dirent,
+    /// The inode the entry points to, or `None` if the entry is from the past and the
+    /// inode has since been deleted
+    pub inode: Option<Inode>,
+}
+
+#[derive(Debug)]
+struct WalkRow {
+    parent: i64,
+    basename: String,
+    child_dir: Option<i64>,
+    child_file: Option<i64>,
+    child_symlink: Option<i64>,
+    path: Vec<String>,
+    mtime: Option<DateTime<Utc>>,
+    birth_time: Option<DateTime<Utc>>,
+    birth_version: Option<i16>,
+    birth_hostname: Option<String>,
+    size: Option<i64>,
+    executable: Option<bool>,
+    b3sum: Option<Vec<u8>>,
+    target: Option<String>,
+}
+
+impl TryFrom<WalkRow> for WalkEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(row: WalkRow) -> Result<WalkEntry> {
+        let child: InodeId = InodeTuple(row.child_dir, row.child_file, row.child_symlink).try_into()?;
+        let inode = match (row.mtime, row.birth_time, row.birth_version, row.birth_hostname) {
+            (Some(mtime), Some(time), Some(version), Some(hostname)) => {
+                let birth = Birth { time, version, hostname };
+                Some(match child {
+                    InodeId::Dir(id) => Inode::Dir(Dir { id, mtime, birth }),
+                    InodeId::File(id) => Inode::File(File {
+                        id,
+                        mtime,
+                        birth,
+                        size: row.size.ok_or_else(|| anyhow!("walk: no size for file {id}"))?,
+                        executable: row.executable.ok_or_else(|| anyhow!("walk: no executable for file {id}"))?,
+                        b3sum: row.b3sum.map(|o| o.try_into().expect("b3sum from postgres wasn't 32 bytes?")),
+                    }),
+                    InodeId::Symlink(id) => Inode::Symlink(Symlink {
+                        id,
+                        mtime,
+                        birth,
+                        target: row.target.ok_or_else(|| anyhow!("walk: no target for symlink {id}"))?,
+                    }),
+                })
+            }
+            _ => None,
+        };
+        let dirent = Dirent::new(row.parent, row.basename, child);
+        Ok(WalkEntry { path: row.path.join("/"), dirent, inode })
+    }
+}
+
+/// Walk the tree below dir `dir_id` with a single recursive query, returning a stream
+/// of every dirent below it and the inode it points to, in depth-first order with
+/// the entries of each dir sorted by basename. If `as_of` is given, walk the dirents
+/// and dirs that existed at that time instead; files and symlinks are always as they
+/// are now.
+pub fn walk<'a>(
+    transaction: &'a mut Transaction<'_, Postgres>,
+    dir_id: i64,
+    as_of: Option<DateTime<Utc>>,
+) -> BoxStream<'a, Result<WalkEntry>> {
+    // `child_dir IS DISTINCT FROM 1` filters out the root directory self-reference.
+    // Ordering by the array of path components puts each dir right before its descendants.
+    let rows = match as_of {
+        None => {
+            sqlx::query_as!(WalkRow, r#"
+                WITH RECURSIVE tree AS (
+                    SELECT parent, basename, child_dir, child_file, child_symlink, ARRAY[basename::text] AS path
+                    FROM stash.dirents
+                    WHERE parent = $1 AND child_dir IS DISTINCT FROM 1
+                    UNION ALL
+                    SELECT dirents.parent, dirents.basename, dirents.child_dir, dirents.child_file, dirents.child_symlink,
+                           tree.path || dirents.basename::text
+                    FROM stash.dirents
+                    JOIN tree ON dirents.parent = tree.child_dir
+                    WHERE dirents.child_dir IS DISTINCT FROM 1
+                )
+                SELECT
+                    tree.parent AS "parent!", tree.basename AS "basename!",
+                    tree.child_dir, tree.child_file, tree.child_symlink, tree.path AS "path!",
+                    COALESCE(dirs.mtime, files.mtime, symlinks.mtime) AS mtime,
+                    COALESCE(dirs.birth_time, files.birth_time, symlinks.birth_time) AS birth_time,
+                    COALESCE(dirs.birth_version, files.birth_version, symlinks.birth_version) AS birth_version,
+                    COALESCE(dirs.birth_hostname, files.birth_hostname, symlinks.birth_hostname) AS birth_hostname,
+                    files.size AS "size?", files.executable AS "executable?", files.b3sum AS "b3sum?", symlinks.target AS "target?"
+                FROM tree
+                LEFT JOIN stash.dirs ON dirs.id = tree.child_dir
+                LEFT JOIN stash.files ON files.id = tree.child_file
+                LEFT JOIN stash.symlinks ON symlinks.id = tree.child_symlink
+                ORDER BY tree.path"#,
+                dir_id
+            ).fetch(&mut **transaction)
+        }
+        Some(as_of) => {
+            sqlx::query_as!(WalkRow, r#"
+                WITH RECURSIVE tree AS (
+                    SELECT parent, basename, child_dir, child_file, child_symlink, ARRAY[basename::text] AS path
+                    FROM stash.dirents__as_of($2)
+                    WHERE parent = $1 AND child_dir IS DISTINCT FROM 1
+                    UNION ALL
+                    SELECT dirents.parent, dirents.basename, dirents.child_dir, dirents.child_file, dirents.child_symlink,
+                           tree.path || dirents.basename::text
+                    FROM stash.dirents__as_of($2) AS dirents
+                    JOIN tree ON dirents.parent = tree.child_dir
+                    WHERE dirents.child_dir IS DISTINCT FROM 1
+                )
+                SELECT
+                    tree.parent AS "parent!", tree.basename AS "basename!",
+                    tree.child_dir, tree.child_file, tree.child_symlink, tree.path AS "path!",
+                    COALESCE(dirs.mtime, files.mtime, symlinks.mtime) AS mtime,
+                    COALESCE(dirs.birth_time, files.birth_time, symlinks.birth_time) AS birth_time,
+                    COALESCE(dirs.birth_version, files.birth_version, symlinks.birth_version) AS birth_version,
+                    COALESCE(dirs.birth_hostname, files.birth_hostname, symlinks.birth_hostname) AS birth_hostname,
+                    files.size AS "size?", files.executable AS "executable?", files.b3sum AS "b3sum?", symlinks.target AS "target?"
+                FROM tree
+                LEFT JOIN stash.dirs__as_of($2) AS dirs ON dirs.id = tree.child_dir
+                LEFT JOIN stash.files ON files.id = tree.child_file
+                LEFT JOIN stash.symlinks ON symlinks.id = tree.child_symlink
+                ORDER BY tree.path"#,
+                dir_id, as_of
+            ).fetch(&mut **transaction)
+        }
+    };
+    rows.map(|result| WalkEntry::try_from(result?)).boxed()
+}
+
+/// The number and total size of some distinct files
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct StorageUsage {
+    /// The kind of storage: inline, fofs, gdrive, namedfiles, or internetarchive
+    pub kind: String,
+    /// The fofs pile id or google_domain id
+    pub id: Option<i64>,
+    /// The number of files with this storage
+    pub files: i64,
+    /// The total size of the files with this storage
+    pub bytes: i64,
+}
+
+/// The number and total size of the distinct files below some dir, in total and by storage
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct DiskUsage {
+    /// The number of files, counting each file once even if more than one dirent points to it
+    pub files: i64,
+    /// The total size of the files
+    pub bytes: i64,
+    /// The files and bytes for each storage, ordered by kind and id.
+    /// A file with more than one storage is counted once for each.
+    pub storages: Vec<StorageUsage>,
+}
+
+/// Return the number and total size of the distinct files below dir `dir_id`, in total and
+/// broken down by storage, computed with a single query.
+pub async fn disk_usage(transaction: &mut Transaction<'_, Postgres>, dir_id: i64) -> Result<DiskUsage> {
+    // `child_dir <> 1` filters out the root directory self-reference.
+    // `section` puts the 'total' row first.
+    let rows = sqlx::query!(r#"
+        WITH RECURSIVE tree AS (
+            SELECT $1::bigint AS dir_id
+            UNION ALL
+            SELECT dirents.child_dir
+            FROM stash.dirents
+            JOIN tree ON dirents.parent = tree.dir_id
+            WHERE dirents.child_dir IS NOT NULL AND dirents.child_dir <> 1
+        ),
+        tree_files AS (
+            SELECT DISTINCT files.id, files.size
+            FROM stash.dirents
+            JOIN tree ON dirents.parent = tree.dir_id
+            JOIN stash.files ON files.id = dirents.child_file
+        ),
+        usage AS (
+            SELECT 0 AS section, 'total' AS kind, NULL::bigint AS id, COUNT(*) AS files, COALESCE(SUM(size), 0)::bigint AS bytes
+            FROM tree_files
+            UNION ALL
+            SELECT 1, 'inline', NULL, COUNT(*), SUM(size)::bigint
+            FROM tree_files
+            WHERE EXISTS (SELECT 1 FROM stash.storage_inline WHERE file_id = tree_files.id)
+            HAVING COUNT(*) > 0
+            UNION ALL
+            SELECT 1, 'fofs', pile_id, COUNT(*), SUM(size)::bigint
+            FROM (
+                SELECT DISTINCT tree_files.id, tree_files.size, cells.pile_id::bigint
+                FROM tree_files
+                JOIN stash.storage_fofs ON storage_fofs.file_id = tree_files.id
+                JOIN stash.cells ON cells.id = storage_fofs.cell_id
+            ) AS fofs
+            GROUP BY pile_id
+            UNION ALL
+            SELECT 1, 'gdrive', google_domain, COUNT(*), SUM(size)::bigint
+            FROM (
+                SELECT DISTINCT tree_files.id, tree_files.size, storage_gdrive.google_domain::bigint
+                FROM tree_files
+                JOIN stash.storage_gdrive ON storage_gdrive.file_id = tree_files.id
+            ) AS gdrive
+            GROUP BY google_domain
+            UNION ALL
+            SELECT 1, 'internetarchive', NULL, COUNT(*), SUM(size)::bigint
+            FROM tree_files
+            WHERE EXISTS (SELECT 1 FROM stash.storage_internetarchive WHERE file_id = tree_files.id)
+            HAVING COUNT(*) > 0
+            UNION ALL
+            SELECT 1, 'namedfiles', NULL, COUNT(*), SUM(size)::bigint
+            FROM tree_files
+            WHERE EXISTS (SELECT 1 FROM stash.storage_namedfiles WHERE file_id = tree_files.id)
+            HAVING COUNT(*) > 0
+        )
+        SELECT kind AS "kind!", id, files AS "files!", bytes AS "bytes!"
+        FROM usage
+        ORDER BY section, kind, id"#,
+        dir_id
+    ).fetch_all(&mut **transaction).await?;
+
+    let mut rows = rows.into_iter();
+    let total = rows.next().ok_or_else(|| anyhow!("disk_usage: no total row"))?;
+    ensure!(total.kind == "total", "disk_usage: expected the total row first, got {:?}", total.kind);
+    let storages = rows
+        .map(|row| StorageUsage { kind: row.kind, id: row.id, files: row.files, bytes: row.bytes })
+        .collect();
+    Ok(DiskUsage { files: total.files, bytes: total.bytes, storages })
+}
+
 /// Takes a dir id and walks up to the root of the filesystem (dir id 1).
 /// Returns a list of path segments needed to reach the dir id from the root.
 pub async fn get_path_segments_from_root_to_dir(transaction: &mut Transaction<'_, Postgres>, mut target_dir: i64) -> Result<Vec<String>> {
@@ -98,7 +543,9 @@ mod tests {
     use crate::db::inode;
     use crate::db::dirent::Dirent;
     use crate::db::dirent::tests::make_basename;
+    use crate::util;
     use chrono::Utc;
+    use futures::TryStreamExt;
     use sqlx::Pool;
 
     mod api {
@@ -137,18 +584,18 @@ mod tests {
 
             // resolve_inode returns the base_dir if there are no components to walk
             let no_components: Vec<&str> = vec![];
-            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &no_components).await?, InodeId::Dir(root_dir.id));
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &no_components, None).await?, InodeId::Dir(root_dir.id));
 
             // resolve_inode returns an InodeId::Dir if segments point to a dir
-            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_dir"]).await?, InodeId::Dir(child_dir.id));
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_dir"], None).await?, InodeId::Dir(child_dir.id));
 
             // resolve_inode returns an InodeId::File if segments point to a file
-            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_file"]).await?, InodeId::File(child_file.id));
-            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_dir", "child_file"]).await?, InodeId::File(child_file.id));
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_file"], None).await?, InodeId::File(child_file.id));
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_dir", "child_file"], None).await?, InodeId::File(child_file.id));
 
             // resolve_inode returns an InodeId::Symlink if segments point to a symlink
-            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_symlink"]).await?, InodeId::Symlink(child_symlink.id));
-            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_dir", "child_symlink"]).await?, InodeId::Symlink(child_symlink.id));
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_symlink"], None).await?, InodeId::Symlink(child_symlink.id));
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_dir", "child_symlink"], None).await?, InodeId::Symlink(child_symlink.id));
 
             // resolve_inode returns an error if some segment is not found
             for (parent, segments) in [
@@ -156,7 +603,7 @@ mod tests {
                 (root_dir.id, vec!["nonexistent"]),
                 (child_dir.id, vec!["child_dir", "nonexistent"]),
             ] {
-                let result = resolve_inode(&mut transaction, root_dir.id, &segments).await;
+                let result = resolve_inode(&mut transaction, root_dir.id, &segments, None).await;
                 assert_eq!(
                     result.expect_err("expected an error").to_string(),
                     format!("no such dirent {:?} under dir {parent:?}", segments.last().unwrap())
@@ -168,7 +615,7 @@ mod tests {
                 (root_dir.id, InodeId::File(child_file.id), vec!["child_file", "further"]),
                 (root_dir.id, InodeId::Symlink(child_symlink.id), vec!["child_symlink", "further"]),
             ] {
-                let result = resolve_inode(&mut transaction, parent, &segments).await;
+                let result = resolve_inode(&mut transaction, parent, &segments, None).await;
                 assert_eq!(
                     result.expect_err("expected an error").to_string(),
                     format!("{not_a_dir:?} is not a dir")
@@ -178,6 +625,52 @@ mod tests {
             Ok(())
         }
 
+        #[tokio::test]
+        async fn test_resolve_dirents() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let (root_dir, child_dir, child_file, _) = set_up_tree(&pool).await?;
+
+            let mut transaction = pool.begin().await?;
+            let before_remove = sqlx::query_scalar!(r#"SELECT now() AS "now!""#).fetch_one(&mut *transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            Dirent::remove_by_parent_basename(&mut transaction, child_dir.id, "child_file").await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+
+            // resolve_dirents returns no dirents if there are no components to walk
+            let no_components: Vec<&str> = vec![];
+            assert_eq!(resolve_dirents(&mut transaction, root_dir.id, &no_components, None).await?, vec![]);
+
+            // resolve_dirents returns the dirent for every segment
+            assert_eq!(resolve_dirents(&mut transaction, root_dir.id, &["child_dir", "child_symlink"], None).await?.len(), 2);
+            assert_eq!(
+                resolve_dirents(&mut transaction, root_dir.id, &["child_dir", "child_file"], Some(before_remove)).await?,
+                vec![
+                    Dirent::new(root_dir.id, "child_dir", InodeId::Dir(child_dir.id)),
+                    Dirent::new(child_dir.id, "child_file", InodeId::File(child_file.id)),
+                ]
+            );
+
+            // resolve_dirents reports the first segment that is not found as a TraversalError::NoDirent
+            let err = resolve_dirents(&mut transaction, root_dir.id, &["child_dir", "child_file", "further"], None).await
+                .expect_err("expected an error");
+            match err.downcast_ref::<TraversalError>() {
+                Some(TraversalError::NoDirent { parent, basename }) => {
+                    assert_eq!(*parent, child_dir.id);
+                    assert_eq!(basename, "child_file");
+                }
+                other => panic!("expected TraversalError::NoDirent, got {other:?}"),
+            }
+
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
+
         #[tokio::test]
         async fn test_resolve_dirent() -> Result<()> {
             let pool = new_primary_pool().await;
@@ -233,6 +726,272 @@ mod tests {
             Ok(())
         }
 
+        #[tokio::test]
+        async fn test_walk() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let (root_dir, child_dir, child_file, child_symlink) = set_up_tree(&pool).await?;
+            let mut transaction = pool.begin().await?;
+            let before_remove = sqlx::query_scalar!(r#"SELECT now() AS "now!""#).fetch_one(&mut *transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let entries: Vec<WalkEntry> = walk(&mut transaction, root_dir.id, None).try_collect().await?;
+            let summary: Vec<(&str, InodeId, bool)> = entries.iter()
+                .map(|entry| (entry.path.as_str(), entry.dirent.child, entry.inode.is_some()))
+                .collect();
+            assert_eq!(summary, vec![
+                ("child_dir", InodeId::Dir(child_dir.id), true),
+                ("child_dir/child_file", InodeId::File(child_file.id), true),
+                ("child_dir/child_symlink", InodeId::Symlink(child_symlink.id), true),
+                ("child_file", InodeId::File(child_file.id), true),
+                ("child_symlink", InodeId::Symlink(child_symlink.id), true),
+            ]);
+            assert_eq!(entries[0].dirent, Dirent::new(root_dir.id, "child_dir", InodeId::Dir(child_dir.id)));
+            assert_eq!(entries[1].inode, Some(Inode::File(inode::File::find_by_ids(&mut transaction, &[child_file.id]).await?.remove(0))));
+            assert_eq!(entries[2].inode, Some(Inode::Symlink(inode::Symlink::find_by_ids(&mut transaction, &[child_symlink.id]).await?.remove(0))));
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let dirent = Dirent::find_by_parent_and_basename(&mut transaction, root_dir.id, "child_dir").await?.unwrap();
+            remove_tree(&mut transaction, &dirent).await?;
+            transaction.commit().await?;
+
+            // A walk as of before the removal finds the removed subtree, but not the deleted dir
+            let mut transaction = pool.begin().await?;
+            let entries: Vec<WalkEntry> = walk(&mut transaction, root_dir.id, None).try_collect().await?;
+            assert_eq!(entries.len(), 2);
+            let entries: Vec<WalkEntry> = walk(&mut transaction, root_dir.id, Some(before_remove)).try_collect().await?;
+            assert_eq!(entries.len(), 5);
+            assert_eq!(entries[0].inode, None);
+            assert!(entries[1].inode.is_some());
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_make_dirs() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let (root_dir, _, _, _) = set_up_tree(&pool).await?;
+
+            // make_dirs uses the given mtimes for new dirs, and walks through existing dirs
+            let mtime = util::without_nanos(Utc::now() - chrono::Duration::days(1));
+            let mut transaction = pool.begin().await?;
+            let new_dir = make_dirs(&mut transaction, root_dir.id, &["child_dir", "new_dir"], Some(&[Utc::now(), mtime])).await?;
+            transaction.commit().await?;
+            let mut transaction = pool.begin().await?;
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_dir", "new_dir"], None).await?, new_dir);
+            assert_eq!(inode::Dir::find_by_ids(&mut transaction, &[new_dir.dir_id()?]).await?[0].mtime, mtime);
+
+            // make_dirs requires one mtime per path component
+            let result = make_dirs(&mut transaction, root_dir.id, &["a", "b"], Some(&[mtime])).await;
+            assert_eq!(result.expect_err("expected an error").to_string(), "make_dirs: got 1 mtimes for 2 path components");
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_remove_tree_and_find_removed_tree() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let (root_dir, child_dir, child_file, child_symlink) = set_up_tree(&pool).await?;
+
+            // Nothing to find while the dirent still exists
+            let mut transaction = pool.begin().await?;
+            let result = find_removed_tree(&mut transaction, root_dir.id, "child_dir", None).await;
+            assert_eq!(
+                result.expect_err("expected an error").to_string(),
+                format!("dirent \"child_dir\" already exists under dir {:?}", root_dir.id)
+            );
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let dirent = Dirent::find_by_parent_and_basename(&mut transaction, root_dir.id, "child_dir").await?.unwrap();
+            remove_tree(&mut transaction, &dirent).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            assert!(resolve_inode(&mut transaction, root_dir.id, &["child_dir"], None).await.is_err());
+            assert_eq!(inode::Dir::find_by_ids(&mut transaction, &[child_dir.id]).await?, vec![]);
+            // Files and symlinks are not deleted
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_file"], None).await?, InodeId::File(child_file.id));
+
+            let (dirents, as_of) = find_removed_tree(&mut transaction, root_dir.id, "child_dir", None).await?;
+            assert_eq!(dirents, vec![
+                Dirent::new(root_dir.id, "child_dir", InodeId::Dir(child_dir.id)),
+                Dirent::new(child_dir.id, "child_file", InodeId::File(child_file.id)),
+                Dirent::new(child_dir.id, "child_symlink", InodeId::Symlink(child_symlink.id)),
+            ]);
+            let (dirents_as_of, _) = find_removed_tree(&mut transaction, root_dir.id, "child_dir", Some(as_of)).await?;
+            assert_eq!(dirents_as_of, dirents);
+
+            let result = find_removed_tree(&mut transaction, root_dir.id, "nonexistent", None).await;
+            assert_eq!(
+                result.expect_err("expected an error").to_string(),
+                format!("no removed dirent \"nonexistent\" under dir {:?} in history", root_dir.id)
+            );
+            transaction.commit().await?;
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_restore_tree() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let (root_dir, child_dir, child_file, child_symlink) = set_up_tree(&pool).await?;
+            let mut transaction = pool.begin().await?;
+            let old_dirs = inode::Dir::find_by_ids(&mut transaction, &[child_dir.id]).await?;
+            let dirent = Dirent::find_by_parent_and_basename(&mut transaction, root_dir.id, "child_dir").await?.unwrap();
+            remove_tree(&mut transaction, &dirent).await?;
+            transaction.commit().await?;
+
+            // Nothing is restored if a symlink in the subtree was deleted
+            let mut transaction = pool.begin().await?;
+            let (dirents, as_of) = find_removed_tree(&mut transaction, root_dir.id, "child_dir", None).await?;
+            Dirent::find_by_parent_and_basename(&mut transaction, root_dir.id, "child_symlink").await?.unwrap().remove(&mut transaction).await?;
+            inode::Symlink::delete(&mut transaction, &[child_symlink.id]).await?;
+            let result = restore_tree(&mut transaction, &dirents, as_of).await;
+            assert_eq!(
+                result.expect_err("expected an error").to_string(),
+                format!("symlink {:?} in the subtree no longer exists", child_symlink.id)
+            );
+            drop(transaction);
+
+            let mut transaction = pool.begin().await?;
+            let (dirents, as_of) = find_removed_tree(&mut transaction, root_dir.id, "child_dir", None).await?;
+            restore_tree(&mut transaction, &dirents, as_of).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_dir", "child_file"], None).await?, InodeId::File(child_file.id));
+            // The dir gets back its mtime and birth from the dirs_history table
+            assert_eq!(inode::Dir::find_by_ids(&mut transaction, &[child_dir.id]).await?, old_dirs);
+            let result = restore_tree(&mut transaction, &dirents, as_of).await;
+            assert_eq!(
+                result.expect_err("expected an error").to_string(),
+                format!("dir {:?} in the subtree still exists elsewhere", child_dir.id)
+            );
+            transaction.commit().await?;
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_move_dirent() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let (root_dir, child_dir, child_file, child_symlink) = set_up_tree(&pool).await?;
+            let mut transaction = pool.begin().await?;
+            let before_rename = sqlx::query_scalar!(r#"SELECT now() AS "now!""#).fetch_one(&mut *transaction).await?;
+            transaction.commit().await?;
+
+            // Can rename a file
+            let mut transaction = pool.begin().await?;
+            move_dirent(&mut transaction, root_dir.id, "child_file", root_dir.id, "renamed_file").await?;
+            transaction.commit().await?;
+            let mut transaction = pool.begin().await?;
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["renamed_file"], None).await?, InodeId::File(child_file.id));
+            assert!(resolve_inode(&mut transaction, root_dir.id, &["child_file"], None).await.is_err());
+            // The old name can still be resolved as of before the rename
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_file"], Some(before_rename)).await?, InodeId::File(child_file.id));
+            assert!(resolve_inode(&mut transaction, root_dir.id, &["renamed_file"], Some(before_rename)).await.is_err());
+            transaction.commit().await?;
+
+            // Cannot move onto an existing dirent
+            let mut transaction = pool.begin().await?;
+            let result = move_dirent(&mut transaction, root_dir.id, "child_symlink", child_dir.id, "child_symlink").await;
+            assert_eq!(
+                result.expect_err("expected an error").to_string(),
+                format!("dirent \"child_symlink\" already exists under dir {:?}", child_dir.id)
+            );
+            drop(transaction);
+
+            // Cannot move a nonexistent dirent
+            let mut transaction = pool.begin().await?;
+            let result = move_dirent(&mut transaction, root_dir.id, "nonexistent", child_dir.id, "new").await;
+            assert_eq!(
+                result.expect_err("expected an error").to_string(),
+                format!("no such dirent \"nonexistent\" under dir {:?}", root_dir.id)
+            );
+            drop(transaction);
+
+            // Can move a symlink to another dir
+            let mut transaction = pool.begin().await?;
+            move_dirent(&mut transaction, root_dir.id, "child_symlink", child_dir.id, "moved_symlink").await?;
+            transaction.commit().await?;
+            let mut transaction = pool.begin().await?;
+            assert_eq!(resolve_inode(&mut transaction, root_dir.id, &["child_dir", "moved_symlink"], None).await?, InodeId::Symlink(child_symlink.id));
+            transaction.commit().await?;
+
+            // Cannot move a dir into itself or one of its descendants
+            let mut transaction = pool.begin().await?;
+            let grandchild_dir = make_dirs(&mut transaction, child_dir.id, &["grandchild"], None).await?.dir_id()?;
+            transaction.commit().await?;
+            for new_parent in [child_dir.id, grandchild_dir] {
+                let mut transaction = pool.begin().await?;
+                let result = move_dirent(&mut transaction, root_dir.id, "child_dir", new_parent, "child_dir").await;
+                assert_eq!(
+                    result.expect_err("expected an error").to_string(),
+                    format!("cannot move dir {:?} into dir {new_parent:?}, which is the same dir or one of its descendants", child_dir.id)
+                );
+            }
+
+            // Can move a dir, along with its children, to another parent
+            let mut transaction = pool.begin().await?;
+            move_dirent(&mut transaction, child_dir.id, "grandchild", root_dir.id, "former_grandchild").await?;
+            transaction.commit().await?;
+            let mut transaction = pool.begin().await?;
+            move_dirent(&mut transaction, root_dir.id, "child_dir", grandchild_dir, "child_dir").await?;
+            transaction.commit().await?;
+            let mut transaction = pool.begin().await?;
+            assert_eq!(
+                resolve_inode(&mut transaction, root_dir.id, &["former_grandchild", "child_dir", "child_file"], None).await?,
+                InodeId::File(child_file.id)
+            );
+            let segments = get_path_segments_from_root_to_dir(&mut transaction, child_dir.id).await?;
+            assert_eq!(segments[1..], ["former_grandchild", "child_dir"]);
+            transaction.commit().await?;
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn test_disk_usage() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let (root_dir, child_dir, _, _) = set_up_tree(&pool).await?;
+            let mut transaction = pool.begin().await?;
+            let birth = inode::Birth::here_and_now();
+            let inline_file = inode::NewFile { size: 5, executable: false, mtime: Utc::now(), birth: birth.clone(), b3sum: None }.create(&mut transaction).await?;
+            crate::db::storage::inline::Storage { file_id: inline_file.id, content_zstd: "invalid zstd is ok".into() }.create(&mut transaction).await?;
+            let other_file = inode::NewFile { size: 7, executable: false, mtime: Utc::now(), birth, b3sum: None }.create(&mut transaction).await?;
+            Dirent::new(root_dir.id, "inline_file", InodeId::File(inline_file.id)).create(&mut transaction).await?;
+            Dirent::new(child_dir.id, "inline_file", InodeId::File(inline_file.id)).create(&mut transaction).await?;
+            Dirent::new(child_dir.id, "other_file", InodeId::File(other_file.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            // Files with more than one dirent are counted once
+            assert_eq!(disk_usage(&mut transaction, root_dir.id).await?, DiskUsage {
+                files: 3,
+                bytes: 12,
+                storages: vec![StorageUsage { kind: "inline".into(), id: None, files: 1, bytes: 5 }],
+            });
+            assert_eq!(disk_usage(&mut transaction, child_dir.id).await?, DiskUsage {
+                files: 3,
+                bytes: 12,
+                storages: vec![StorageUsage { kind: "inline".into(), id: None, files: 1, bytes: 5 }],
+            });
+            let empty_dir = inode::create_dummy_dir(&mut transaction, "test_disk_usage").await?;
+            assert_eq!(disk_usage(&mut transaction, empty_dir.id).await?, DiskUsage { files: 0, bytes: 0, storages: vec![] });
+            transaction.commit().await?;
+
+            Ok(())
+        }
+
         #[tokio::test]
         async fn test_get_path_segments_from_root_to_dir() -> Result<()> {
             let pool = new_primary_pool().await;
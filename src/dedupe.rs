@@ -0,0 +1,224 @@
+//! Finding files with identical content and pointing their dirents at one of them
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use anyhow::{ensure, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Postgres, Transaction};
+use crate::db::dirent::Dirent;
+use crate::db::inode::{File, InodeId};
+use crate::storage::StoragesDescriptor;
+
+/// Which files with the same content may be merged into one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// Files must also have the same mtime and executable bit
+    Strict,
+    /// Files must also have the same executable bit. Dirents that are pointed at
+    /// a file with a different mtime get that file's mtime.
+    IgnoreMtime,
+}
+
+/// Files with the same content whose dirents can all point to `canonical`
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateSet {
+    /// The file to keep
+    pub canonical: File,
+    /// The files that will be unreferenced once their dirents point to `canonical`
+    pub redundant: Vec<File>,
+}
+
+impl DuplicateSet {
+    /// The number of bytes stored for the redundant files, counting one copy per
+    /// inline, fofs, and gdrive storage, given the storages of each file in `storages`
+    pub fn reclaimable_bytes(&self, storages: &HashMap<i64, StoragesDescriptor>) -> i64 {
+        self.redundant.iter()
+            .map(|file| file.size * storages.get(&file.id).map_or(0, StoragesDescriptor::len) as i64)
+            .sum()
+    }
+}
+
+/// The files with the same content found by `plan`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    /// Sets of files that can be merged
+    pub sets: Vec<DuplicateSet>,
+    /// Groups of files that could be merged under the rule, but are left alone because
+    /// none of the files has every inline, fofs, and gdrive storage that the others have,
+    /// and `es gc` would delete the storages that only the redundant files have
+    pub uncovered: Vec<Vec<File>>,
+}
+
+/// Group `files` into sets of files with the same b3sum and size that can be merged
+/// under `rule`, leaving out files that cannot be merged with any other. The canonical
+/// file of each set is one whose storages according to `storages` include the storages
+/// of every other file in the set; if there are several, the one with the most storages,
+/// or the oldest if there is a tie. Groups without such a file go in `Plan::uncovered`.
+pub fn plan(files: Vec<File>, storages: &HashMap<i64, StoragesDescriptor>, rule: Rule) -> Plan {
+    let mut groups: BTreeMap<(Option<[u8; 32]>, i64, bool, Option<DateTime<Utc>>), Vec<File>> = BTreeMap::new();
+    for file in files {
+        // Files without a b3sum have unknown content
+        if file.b3sum.is_none() {
+            continue;
+        }
+        let mtime = match rule {
+            Rule::Strict => Some(file.mtime),
+            Rule::IgnoreMtime => None,
+        };
+        groups.entry((file.b3sum, file.size, file.executable, mtime)).or_default().push(file);
+    }
+    let no_storages = StoragesDescriptor { inline: false, fofs: HashSet::new(), gdrive: HashSet::new() };
+    let mut plan = Plan::default();
+    for mut files in groups.into_values().filter(|files| files.len() > 1) {
+        let mut union = no_storages.clone();
+        for file in &files {
+            let descriptor = storages.get(&file.id).unwrap_or(&no_storages);
+            union.inline |= descriptor.inline;
+            union.fofs.extend(&descriptor.fofs);
+            union.gdrive.extend(&descriptor.gdrive);
+        }
+        let canonical_idx = files.iter()
+            .enumerate()
+            .map(|(idx, file)| (idx, file, storages.get(&file.id).unwrap_or(&no_storages)))
+            .filter(|(_, _, descriptor)| union.is_subset(descriptor))
+            .max_by_key(|(_, file, descriptor)| (descriptor.len(), Reverse(file.id)))
+            .map(|(idx, _, _)| idx);
+        match canonical_idx {
+            Some(idx) => {
+                let canonical = files.remove(idx);
+                plan.sets.push(DuplicateSet { canonical, redundant: files });
+            }
+            None => plan.uncovered.push(files),
+        }
+    }
+    plan
+}
+
+/// Point every dirent of the redundant files in `set` at the canonical file instead.
+/// Returns the dirents as they were before being changed. Commit soon after calling
+/// this, because the dir rollups stay locked until then (see schema/dir_rollups.sql).
+/// Does not commit the transaction, you must do so yourself.
+pub async fn relink(transaction: &mut Transaction<'_, Postgres>, set: &DuplicateSet) -> Result<Vec<Dirent>> {
+    let canonical = &set.canonical;
+    for file in &set.redundant {
+        ensure!(file.b3sum.is_some() && file.b3sum == canonical.b3sum && file.size == canonical.size,
+            "relink: file {} does not have the same content as file {}", file.id, canonical.id);
+    }
+    let redundant_ids: Vec<i64> = set.redundant.iter().map(|file| file.id).collect();
+    let dirents = Dirent::remove_by_child_files(transaction, &redundant_ids).await?;
+    for dirent in &dirents {
+        Dirent::new(dirent.parent, dirent.basename.clone(), InodeId::File(canonical.id)).create(transaction).await?;
+    }
+    Ok(dirents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::inode::{Birth, NewFile};
+    use crate::db::inode::create_dummy_dir;
+    use crate::db::tests::new_primary_pool;
+    use crate::util;
+
+    fn file(id: i64, b3sum: Option<[u8; 32]>, mtime: DateTime<Utc>, executable: bool) -> File {
+        File { id, mtime, birth: Birth::here_and_now(), size: 10, executable, b3sum }
+    }
+
+    #[test]
+    fn test_plan() {
+        let now = util::now_no_nanos();
+        let earlier = now - chrono::Duration::days(1);
+        let files = vec![
+            file(1, Some([1; 32]), now, false),
+            file(2, Some([1; 32]), now, false),
+            file(3, Some([1; 32]), earlier, false),
+            file(4, Some([1; 32]), now, true),
+            file(5, Some([2; 32]), now, false),
+            file(6, None, now, false),
+            file(7, None, now, false),
+        ];
+        let descriptor = |inline, fofs: &[i32]| StoragesDescriptor { inline, fofs: fofs.iter().copied().collect(), gdrive: HashSet::new() };
+        let storages = HashMap::from([(1, descriptor(false, &[1])), (2, descriptor(false, &[1, 2])), (3, descriptor(false, &[1, 2, 3]))]);
+
+        // With the strict rule, only files 1 and 2 can be merged, and 2 has all of the storages
+        assert_eq!(plan(files.clone(), &storages, Rule::Strict), Plan {
+            sets: vec![DuplicateSet { canonical: files[1].clone(), redundant: vec![files[0].clone()] }],
+            uncovered: vec![],
+        });
+
+        // Ignoring mtime, file 3 joins them and has all of the storages
+        let sets = plan(files.clone(), &storages, Rule::IgnoreMtime).sets;
+        assert_eq!(sets, vec![
+            DuplicateSet { canonical: files[2].clone(), redundant: vec![files[0].clone(), files[1].clone()] },
+        ]);
+        assert_eq!(sets[0].reclaimable_bytes(&storages), 10 + 20);
+
+        // With no storages, the oldest file is kept
+        assert_eq!(plan(files.clone(), &HashMap::new(), Rule::IgnoreMtime).sets, vec![
+            DuplicateSet { canonical: files[0].clone(), redundant: vec![files[1].clone(), files[2].clone()] },
+        ]);
+
+        // The file with the most storages is not kept if another file has a storage it lacks
+        let storages = HashMap::from([(1, descriptor(true, &[])), (2, descriptor(false, &[1, 2])), (3, descriptor(true, &[1, 2]))]);
+        assert_eq!(plan(files[..3].to_vec(), &storages, Rule::IgnoreMtime).sets, vec![
+            DuplicateSet { canonical: files[2].clone(), redundant: vec![files[0].clone(), files[1].clone()] },
+        ]);
+
+        // When no file has every storage, the group is left alone
+        let storages = HashMap::from([(1, descriptor(true, &[])), (2, descriptor(false, &[1, 2]))]);
+        assert_eq!(plan(files.clone(), &storages, Rule::Strict), Plan {
+            sets: vec![],
+            uncovered: vec![vec![files[0].clone(), files[1].clone()]],
+        });
+    }
+
+    mod api {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_relink() -> Result<()> {
+            let pool = new_primary_pool().await;
+
+            let mut transaction = pool.begin().await?;
+            let b3sum = *blake3::hash(b"test_relink").as_bytes();
+            let new_file = || NewFile { executable: false, size: 11, mtime: util::now_no_nanos(), birth: Birth::here_and_now(), b3sum: Some(b3sum) };
+            let canonical = new_file().create(&mut transaction).await?;
+            let redundant = new_file().create(&mut transaction).await?;
+            let dir = create_dummy_dir(&mut transaction, "test_relink").await?;
+            Dirent::new(dir.id, "a", InodeId::File(canonical.id)).create(&mut transaction).await?;
+            Dirent::new(dir.id, "b", InodeId::File(redundant.id)).create(&mut transaction).await?;
+            Dirent::new(dir.id, "c", InodeId::File(redundant.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let set = DuplicateSet { canonical: canonical.clone(), redundant: vec![redundant.clone()] };
+            let old = relink(&mut transaction, &set).await?;
+            transaction.commit().await?;
+            assert_eq!(old, vec![
+                Dirent::new(dir.id, "b", InodeId::File(redundant.id)),
+                Dirent::new(dir.id, "c", InodeId::File(redundant.id)),
+            ]);
+
+            let mut transaction = pool.begin().await?;
+            assert_eq!(Dirent::find_by_child_files(&mut transaction, &[canonical.id]).await?, vec![
+                Dirent::new(dir.id, "a", InodeId::File(canonical.id)),
+                Dirent::new(dir.id, "b", InodeId::File(canonical.id)),
+                Dirent::new(dir.id, "c", InodeId::File(canonical.id)),
+            ]);
+            assert_eq!(Dirent::find_by_child_files(&mut transaction, &[redundant.id]).await?, vec![]);
+            // The old dirents are kept in the dirents_history table
+            for basename in ["b", "c"] {
+                let (removed, _) = Dirent::find_last_removed(&mut transaction, dir.id, basename).await?.unwrap();
+                assert_eq!(removed, Dirent::new(dir.id, basename, InodeId::File(redundant.id)));
+            }
+
+            // Files with different content are refused
+            let different = NewFile { b3sum: Some([0; 32]), ..new_file() }.create(&mut transaction).await?;
+            let set = DuplicateSet { canonical, redundant: vec![different] };
+            assert!(relink(&mut transaction, &set).await.is_err());
+            transaction.commit().await?;
+
+            Ok(())
+        }
+    }
+}
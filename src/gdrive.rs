@@ -14,6 +14,7 @@ use reqwest::StatusCode;
 use reqwest::header::HeaderMap;
 use futures::stream::Stream;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 pub use yup_oauth2::AccessToken;
 use crate::db::storage::gdrive::file::GdriveFile;
 use crate::lazy_regex;
@@ -148,6 +149,83 @@ pub async fn list_permissions(file_or_drive_id: &str, access_token: &str) -> Res
     Ok(values)
 }
 
+/// A file in a Google Drive folder, as listed by `list_folder_children`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GdriveChild {
+    /// Google Drive's file_id
+    pub id: String,
+    /// The filename
+    pub name: String,
+    /// The size of the file in bytes, absent for folders and Google Docs
+    pub size: Option<String>,
+    /// The time at which the file was created
+    pub created_time: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GdriveFileList {
+    next_page_token: Option<String>,
+    files: Vec<GdriveChild>,
+}
+
+/// List the files in a Google Drive folder, which may be in a shared drive.
+/// Trashed files are not included.
+pub async fn list_folder_children(folder_id: &str, access_token: &str) -> Result<Vec<GdriveChild>> {
+    static FOLDER_ID_RE: &Lazy<Regex> = lazy_regex!(r#"\A[-_0-9A-Za-z]{19,160}\z"#);
+    if FOLDER_ID_RE.captures(folder_id).is_none() {
+        bail!("invalid gdrive folder_id: {:?}", folder_id);
+    }
+    let query = format!("'{folder_id}' in parents and trashed = false");
+    let client = reqwest::Client::new();
+    let mut children = vec![];
+    let mut next_page_token: Option<String> = None;
+    loop {
+        let mut request = client
+            .get("https://www.googleapis.com/drive/v3/files")
+            .query(&[
+                ("q", query.as_str()),
+                ("supportsAllDrives", "true"),
+                ("includeItemsFromAllDrives", "true"),
+                ("pageSize", "1000"),
+                ("fields", "nextPageToken,files(id,name,size,createdTime)"),
+            ])
+            .header("Authorization", format!("Bearer {access_token}"));
+        if let Some(ref token) = next_page_token {
+            request = request.query(&[("pageToken", token)]);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if status != 200 {
+            bail!("expected status 200 in response to files list request, got {status}");
+        }
+        let list: GdriveFileList = response.json().await?;
+        children.extend(list.files);
+        next_page_token = list.next_page_token;
+        if next_page_token.is_none() {
+            break;
+        }
+    }
+    Ok(children)
+}
+
+/// Delete a file, which may be in a shared drive, using a specific access token
+pub async fn delete_gdrive_file_with_access_token(file_id: &str, access_token: &str) -> Result<()> {
+    let client = reqwest::Client::new();
+    let url = format!("https://www.googleapis.com/drive/v3/files/{file_id}?supportsAllDrives=true");
+    let response = client
+        .delete(url)
+        .header("Authorization", format!("Bearer {access_token}"))
+        .send().await?;
+    let status = response.status();
+    if !(status == 200 || status == 204) {
+        let body = response.text().await?;
+        bail!(GdriveDeleteError::DeleteRequestNotOk(status, body));
+    }
+    Ok(())
+}
+
 #[derive(Debug, Deserialize)]
 pub(crate) struct GdriveUploadResponse {
     pub(crate) kind: String,
@@ -26,32 +26,58 @@ enum InodeWithStorages<'a> {
     #[serde(rename = "dir")]
     Dir(&'a Dir),
     #[serde(rename = "file")]
-    File(&'a FileWithStorages<'a>),
+    File(FileWithStorages<'a>),
     #[serde(rename = "symlink")]
     Symlink(&'a Symlink),
 }
 
-/// Return information about a file, dir, or symlink in JSON format
-pub async fn json_info(inode: &Inode) -> Result<String> {
-    let fws;
-    let inode = match inode {
-        Inode::File(file) => {
-            let storage_views = get_storage_views(&[file.id]).await?;
-            fws = FileWithStorages {
-                id: file.id,
-                mtime: file.mtime,
-                birth: &file.birth,
-                size: file.size,
-                executable: file.executable,
-                storage_views,
-                b3sum: file.b3sum,
-            };
-            InodeWithStorages::File(&fws)
-        }
+#[derive(Serialize)]
+struct InodeInfo<'a> {
+    #[serde(flatten)]
+    inode: InodeWithStorages<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nlink: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct PathInfo<'a> {
+    path: &'a str,
+    #[serde(flatten)]
+    inode: InodeWithStorages<'a>,
+}
+
+fn with_storages(inode: &Inode, storage_views: Vec<StorageView>) -> InodeWithStorages<'_> {
+    match inode {
+        Inode::File(file) => InodeWithStorages::File(FileWithStorages {
+            id: file.id,
+            mtime: file.mtime,
+            birth: &file.birth,
+            size: file.size,
+            executable: file.executable,
+            storage_views,
+            b3sum: file.b3sum,
+        }),
         Inode::Dir(dir) => InodeWithStorages::Dir(dir),
         Inode::Symlink(symlink) => InodeWithStorages::Symlink(symlink),
+    }
+}
+
+/// Return information about a file, dir, or symlink in JSON format.
+/// If `nlink` is given, it is included as the number of dirents pointing to the inode.
+pub async fn json_info(inode: &Inode, nlink: Option<i64>) -> Result<String> {
+    let storage_views = match inode {
+        Inode::File(file) => get_storage_views(&[file.id]).await?,
+        _ => vec![],
     };
+    let inode = with_storages(inode, storage_views);
+    let json = serde_json::to_string_pretty(&InodeInfo { inode, nlink })?;
+    Ok(json)
+}
 
-    let json = serde_json::to_string_pretty(&inode)?;
+/// Return information about the file, dir, or symlink at `path` as one line of JSON,
+/// using the given `storage_views` for a file instead of fetching them.
+pub fn json_path_info(path: &str, inode: &Inode, storage_views: Vec<StorageView>) -> Result<String> {
+    let inode = with_storages(inode, storage_views);
+    let json = serde_json::to_string(&PathInfo { path, inode })?;
     Ok(json)
 }
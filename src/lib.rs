@@ -41,6 +41,7 @@ pub mod policy;
 pub mod gdrive;
 pub(crate) mod crypto;
 pub mod info;
+pub mod dedupe;
 pub mod oauth;
 pub mod storage;
 
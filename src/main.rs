@@ -4,32 +4,38 @@
 
 use exastash::db::storage::fofs::backfill_b3sums;
 use exastash::db::storage::{fofs, gdrive, get_storage_views, namedfiles, StorageView};
+use exastash::db::storage::report::{CellUsage, DomainUsage, OwnerUsage, PileUsage};
 use tracing::info;
 use yansi::Paint;
-use async_recursion::async_recursion;
 use clap::{ValueEnum, Subcommand, Parser};
 use anyhow::{anyhow, bail, Result};
-use chrono::Utc;
+use futures::stream::{self, TryStreamExt};
+use chrono::{DateTime, Utc};
 use tokio::fs;
 use tokio_util::codec::FramedRead;
-use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use num::rational::Ratio;
 use sqlx::{Postgres, Transaction};
 use tracing_subscriber::EnvFilter;
-use exastash::util::{commaify_i64, get_hostname, FixedReadSizeDecoder};
+use exastash::util::{self, commaify_i64, get_hostname, FixedReadSizeDecoder};
+use serde::Serialize;
 use serde_json::json;
 use exastash::db;
+use exastash::dedupe;
 use exastash::db::storage::gdrive::{file::GdriveFile, GdriveFilePlacement};
-use exastash::gdrive::{delete_shared_drive, list_shared_drives, get_shared_drive, list_permissions};
+use exastash::gdrive::{delete_shared_drive, list_shared_drives, get_shared_drive, list_permissions, list_folder_children, delete_gdrive_file_with_access_token};
 use exastash::db::inode::{InodeId, Inode, File, Dir, NewDir, Symlink, NewSymlink};
-use exastash::db::dirent::{Dirent, InodeTuple};
+use exastash::db::dirent::{Dirent, DirentVersion, InodeTuple};
 use exastash::db::google_auth::{GoogleApplicationSecret, GoogleServiceAccount};
 use exastash::db::traversal;
+use exastash::db::rollup::DirRollup;
 use exastash::path;
+use exastash::path::archive::MemberKind;
 use exastash::config;
 use exastash::policy;
-use exastash::info::json_info;
+use exastash::info::{json_info, json_path_info};
 use exastash::oauth;
 use exastash::retry::Decayer;
 use exastash::storage;
@@ -66,6 +72,21 @@ enum DirCommand {
         ids: Vec<i64>,
     },
 
+    /// Print the history of the dirents in a directory: when each one was created and removed
+    #[clap(name = "history")]
+    History {
+        #[clap(name = "DIR_ID")]
+        dir_id: i64,
+
+        /// Also print the history of every directory that was ever in the directory
+        #[clap(long, short = 'r')]
+        recursive: bool,
+
+        /// Print a JSON object per line instead of text
+        #[clap(long)]
+        json: bool,
+    },
+
     /// Print a count of the number of dirs
     Count,
 }
@@ -347,6 +368,26 @@ enum StorageCommand {
     /// fofs storage
     #[clap(subcommand, name = "fofs")]
     Fofs(FofsStorageCommand),
+
+    /// Print the number and total size of the files stored in each fofs pile, google
+    /// domain, and gdrive owner. For google domains, also print the bytes used by
+    /// AES-GCM tags and by the padding that conceals file sizes.
+    #[clap(name = "report")]
+    Report {
+        /// Also print the files stored in each fofs cell
+        #[clap(long)]
+        cells: bool,
+
+        /// Also list every gdrive_parents folder in Google Drive, using the access
+        /// token of this gdrive_owner, and print the files in each. The database
+        /// does not record which folder a gdrive file is in.
+        #[clap(long, value_name = "OWNER_ID")]
+        parents_as: Option<i32>,
+
+        /// Print a JSON object per line instead of text
+        #[clap(long)]
+        json: bool,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -358,6 +399,29 @@ enum GdriveStorageCommand {
     /// gdrive file placement commands
     #[clap(subcommand, name = "placement")]
     Placement(PlacementCommand),
+
+    /// List the files in every gdrive_parents folder and compare them against
+    /// gdrive_files. Report Drive files not in the database (orphans), and
+    /// database ids not found in any gdrive_parents folder (missing).
+    #[clap(name = "audit")]
+    Audit {
+        /// gdrive_owner whose access token is used to list and delete files
+        #[clap(name = "OWNER_ID")]
+        owner_id: i32,
+
+        /// Only report orphans created at least this many days ago, because
+        /// recently-uploaded files may not be recorded in the database yet
+        #[clap(long, default_value_t = 7)]
+        grace_days: u32,
+
+        /// Delete orphans from Google Drive
+        #[clap(long)]
+        delete_orphans: bool,
+
+        /// Print a JSON object per line instead of text
+        #[clap(long)]
+        json: bool,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -385,6 +449,35 @@ enum FofsStorageCommand {
     /// the database, set the b3sums based on the fofs files.
     #[clap(name = "backfill-b3sums")]
     BackfillB3sums,
+
+    /// Compare the files in a fofs pile on this host against the database and
+    /// report orphan files, missing files, and files with the wrong size or b3sum.
+    #[clap(name = "audit")]
+    Audit {
+        /// fofs pile id
+        #[clap(name = "PILE_ID")]
+        pile_id: i32,
+
+        /// Also compare the b3sum of each file that has the correct size
+        #[clap(long, short = 'c')]
+        checksum: bool,
+
+        /// Delete files that are not recorded in the database
+        #[clap(long)]
+        delete_orphans: bool,
+
+        /// Drop storage_fofs rows for files that are missing from the pile
+        #[clap(long)]
+        drop_missing: bool,
+
+        /// Drop storage_fofs rows for files with the wrong size or b3sum, and delete the files
+        #[clap(long)]
+        drop_corrupt: bool,
+
+        /// Print a JSON object per line instead of text
+        #[clap(long)]
+        json: bool,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -521,6 +614,56 @@ enum SortOrder {
     size,
 }
 
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[expect(non_camel_case_types)]
+enum DedupeRule {
+    /// Files must also have the same mtime and executable bit
+    strict,
+    /// Files must also have the same executable bit, and relinked dirents get the mtime of the kept file
+    ignore_mtime,
+}
+
+impl From<DedupeRule> for dedupe::Rule {
+    fn from(rule: DedupeRule) -> dedupe::Rule {
+        match rule {
+            DedupeRule::strict => dedupe::Rule::Strict,
+            DedupeRule::ignore_mtime => dedupe::Rule::IgnoreMtime,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum DedupeCommand {
+    /// Print each set of files with the same b3sum and size that dirents point to and that
+    /// can be merged under --rule, with the paths and storages of each file and the bytes
+    /// that would be reclaimed by deleting the redundant files. Sets in which no file has
+    /// every inline, fofs, and gdrive storage of the others are reported as skipped.
+    #[clap(name = "report")]
+    Report {
+        /// Which files with the same content may be merged
+        #[clap(value_enum, long, default_value = "strict")]
+        rule: DedupeRule,
+
+        /// Print a JSON object per set instead of text
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Point the dirents of the redundant files in each set at the file that is kept,
+    /// leaving the redundant files unreferenced for `es gc` to delete. The kept file has
+    /// every inline, fofs, and gdrive storage of the others; sets without such a file are skipped.
+    #[clap(name = "apply")]
+    Apply {
+        /// Which files with the same content may be merged
+        #[clap(value_enum, long, default_value = "strict")]
+        rule: DedupeRule,
+
+        /// Print what would be relinked without changing anything
+        #[clap(long, short = 'n')]
+        dry_run: bool,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum PathCommand {
     /// Print info in JSON format for a path's inode
@@ -529,6 +672,10 @@ enum PathCommand {
         /// Path to an inode to print info for, relative to cwd
         #[clap(name = "PATH")]
         paths: Vec<String>,
+
+        /// Show the tree as it was at this time, e.g. 2024-01-31T00:00:00Z or 2024-01-31
+        #[clap(long, value_parser = parse_timestamp)]
+        as_of: Option<DateTime<Utc>>,
     },
 
     /// Write the contents of a file to stdout
@@ -540,7 +687,7 @@ enum PathCommand {
     },
 
     /// Retrieve a dir, file, or symlink to the local filesystem.
-    /// Not recursive.
+    /// Dirs are retrieved recursively.
     #[clap(name = "get")]
     Get {
         /// Path to get from stash, relative to cwd
@@ -550,10 +697,18 @@ enum PathCommand {
         /// Skip retrieval if the file exists locally with a matching size and mtime
         #[clap(long, short = 's')]
         skip_if_exists: bool,
+
+        /// Number of files to retrieve at a time when retrieving a dir
+        #[clap(long, short = 'j', default_value_t = 4)]
+        jobs: usize,
+
+        /// Retrieve the tree as it was at this time, e.g. 2024-01-31T00:00:00Z or 2024-01-31
+        #[clap(long, value_parser = parse_timestamp)]
+        as_of: Option<DateTime<Utc>>,
     },
 
-    /// Create a stash file based on a local file. This also makes local file
-    /// read-only to make it more obviously immutable like the stash file.
+    /// Create a stash file, symlink, or dir based on a local one. Dirs are added
+    /// recursively, and new stash dirs get the mtimes of the local dirs.
     #[clap(name = "add")]
     Add {
         /// Path to add to stash, relative to cwd
@@ -564,9 +719,55 @@ enum PathCommand {
         #[clap(value_enum, long, short = 'e', default_value = "stop")]
         existing_file_behavior: ExistingFileBehavior,
 
-        /// Remove each local file after successfully storing it and creating a dirent
+        /// Remove each local file or symlink after successfully storing it and creating a dirent
         #[clap(long)]
         remove_local_files: bool,
+
+        /// Hash each local file first, and if an existing stash file has the same content
+        /// and mtime and already has the storages that policy.js wants, create a dirent pointing to it
+        /// instead of storing the file again. Without this, policy.js's `dedupe_new_file`
+        /// decides.
+        #[clap(long)]
+        dedupe: bool,
+    },
+
+    /// Create the dirs, files, and symlinks in a tar or zip archive under a stash dir,
+    /// without extracting the archive to disk. Tars may be compressed with gzip or zstd.
+    /// Hard links in a tar become dirents pointing to the same stash file.
+    /// Storages for each file are chosen by policy.js, as with `es x add`.
+    #[clap(name = "import-archive")]
+    ImportArchive {
+        /// Path to a local tar or zip archive
+        #[clap(name = "ARCHIVE")]
+        archive: PathBuf,
+
+        /// Path to the stash dir to create the members under, relative to cwd.
+        /// It is created if it does not exist.
+        #[clap(name = "DEST")]
+        dest: String,
+
+        /// What to do if a directory entry already exists at the corresponding stash path
+        #[clap(value_enum, long, short = 'e', default_value = "stop")]
+        existing_file_behavior: ExistingFileBehavior,
+    },
+
+    /// Write a dir as a POSIX pax tar stream to stdout, reading file content
+    /// straight from storage. Entries are named relative to the dir's parent,
+    /// like `tar c DIR`.
+    #[clap(name = "tar")]
+    Tar {
+        /// Path to a dir to write as a tar stream, relative to cwd
+        #[clap(name = "PATH")]
+        path: String,
+
+        /// Add `EXASTASH.file_id` and `EXASTASH.b3sum` pax records to each file entry.
+        /// GNU tar warns about these unknown keywords; bsdtar ignores them.
+        #[clap(long)]
+        pax_metadata: bool,
+
+        /// Write the tree as it was at this time, e.g. 2024-01-31T00:00:00Z or 2024-01-31
+        #[clap(long, value_parser = parse_timestamp)]
+        as_of: Option<DateTime<Utc>>,
     },
 
     /// List a directory
@@ -580,6 +781,10 @@ enum PathCommand {
         #[clap(long, short = 'j')]
         just_names: bool,
 
+        /// Whether to also print the number of dirents pointing to each inode
+        #[clap(long = "long", short = 'l')]
+        link_counts: bool,
+
         /// By which field to sort the output
         #[clap(value_enum, long, default_value = "name")]
         sort: SortOrder,
@@ -587,6 +792,15 @@ enum PathCommand {
         /// Whether to sort in reverse
         #[clap(long, short = 'r')]
         reverse: bool,
+
+        /// Whether to show the total size of the files below each dir, counting a file
+        /// once per dirent, instead of 0
+        #[clap(long, short = 's', conflicts_with = "as_of")]
+        dir_sizes: bool,
+
+        /// List the dir as it was at this time, e.g. 2024-01-31T00:00:00Z or 2024-01-31
+        #[clap(long, value_parser = parse_timestamp)]
+        as_of: Option<DateTime<Utc>>,
     },
 
     /// Recursively list a directory like findutils find
@@ -600,9 +814,177 @@ enum PathCommand {
         #[clap(value_enum, long, short = 't')]
         r#type: Option<FindKind>,
 
+        /// Limit output to paths whose basename matches this glob
+        #[clap(long)]
+        name: Option<path::find::Glob>,
+
+        /// Like --name, but the match is case-insensitive
+        #[clap(long, value_parser = path::find::Glob::new_case_insensitive, conflicts_with = "name")]
+        iname: Option<path::find::Glob>,
+
+        /// Limit output to files with a size of more than (+N), less than (-N), or
+        /// exactly (N) N bytes, or N units of k, M, or G with a suffix, e.g. +10M
+        #[clap(long, allow_hyphen_values = true)]
+        size: Option<path::find::SizeFilter>,
+
+        /// Limit output to paths last modified more than (+N), less than (-N), or
+        /// exactly (N) N days ago
+        #[clap(long, allow_hyphen_values = true)]
+        mtime: Option<path::find::AgeFilter>,
+
+        /// Limit output to paths last modified after this time, e.g. 2024-01-31T00:00:00Z or 2024-01-31
+        #[clap(long, value_parser = parse_timestamp)]
+        newer: Option<DateTime<Utc>>,
+
+        /// Limit output to executable files
+        #[clap(long)]
+        executable: bool,
+
+        /// Limit output to files with this b3sum
+        #[clap(long, value_parser = path::find::parse_b3sum)]
+        b3sum: Option<[u8; 32]>,
+
+        /// Limit output to files without a b3sum
+        #[clap(long, conflicts_with = "b3sum")]
+        no_b3sum: bool,
+
+        /// Limit output to files with this storage, e.g. fofs:5, gdrive:1, or inline.
+        /// Can be given more than once.
+        #[clap(long)]
+        has_storage: Vec<path::find::StorageSpec>,
+
+        /// Limit output to files without this storage, e.g. fofs:5, gdrive:1, or inline.
+        /// Can be given more than once.
+        #[clap(long)]
+        missing_storage: Vec<path::find::StorageSpec>,
+
         /// Print filenames separated by NULL instead of LF
-        #[clap(short = '0')]
+        #[clap(short = '0', conflicts_with = "json")]
         null_sep: bool,
+
+        /// Print a JSON object per line with the path, inode metadata, and storages
+        #[clap(long)]
+        json: bool,
+
+        /// Show the tree as it was at this time, e.g. 2024-01-31T00:00:00Z or 2024-01-31
+        #[clap(long, value_parser = parse_timestamp)]
+        as_of: Option<DateTime<Utc>>,
+    },
+
+    /// Print the number and total size of the files below a dir, counting each file
+    /// once even if more than one dirent points to it, broken down by storage
+    #[clap(name = "du")]
+    Du {
+        /// Path to a dir to summarize, relative to cwd
+        #[clap(name = "PATH")]
+        paths: Vec<String>,
+
+        /// Read the totals from the per-dir rollups instead, which is much faster for
+        /// large trees but counts a file once per dirent and has no breakdown by storage
+        #[clap(long)]
+        fast: bool,
+
+        /// Print a JSON object per line instead of text
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Compare local paths with their stash equivalents and print each path that
+    /// exists only locally, only in the stash, or differs in type, size, mtime,
+    /// executable bit, or symlink target. Dirs are compared recursively.
+    #[clap(name = "status")]
+    Status {
+        /// Path to compare, relative to cwd
+        #[clap(name = "PATH")]
+        paths: Vec<String>,
+
+        /// Also compare the BLAKE3 hash of each local file with the b3sum of the
+        /// stash file, when the sizes match and the stash file has a b3sum
+        #[clap(long, short = 'c')]
+        checksum: bool,
+
+        /// Print a JSON object per line instead of text
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Print a manifest of the files below a dir in the format of `b3sum`, with paths
+    /// relative to the dir, so that a copy made elsewhere can be verified with
+    /// `b3sum --check`. Files with no b3sum are left out and reported on stderr.
+    #[clap(name = "manifest")]
+    Manifest {
+        /// Path to a dir, relative to cwd
+        #[clap(name = "PATH")]
+        path: String,
+
+        /// Use the tree as it was at this time, e.g. 2024-01-31T00:00:00Z or 2024-01-31
+        #[clap(long, value_parser = parse_timestamp)]
+        as_of: Option<DateTime<Utc>>,
+    },
+
+    /// Hash the files in a local dir and compare them with the b3sums of the files
+    /// below a stash dir. Reports files that are missing locally, that differ, or
+    /// whose b3sum is unknown. Local files not in the stash are not reported.
+    #[clap(name = "check-local")]
+    CheckLocal {
+        /// Local dir to check, relative to cwd
+        #[clap(name = "LOCAL_DIR")]
+        local_dir: String,
+
+        /// Stash dir to compare with, relative to cwd. Defaults to the stash
+        /// equivalent of LOCAL_DIR.
+        #[clap(long)]
+        stash: Option<String>,
+
+        /// Number of local files to hash at a time
+        #[clap(long, short = 'j', default_value_t = 4)]
+        jobs: usize,
+
+        /// Print a JSON object per line instead of text
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Make the stash match local paths (--to-stash) or local paths match the stash
+    /// (--from-stash), based on the differences reported by `es x status`. Missing
+    /// dirs, files, and symlinks are copied and differing ones are replaced.
+    #[clap(name = "sync")]
+    Sync {
+        /// Path to sync, relative to cwd
+        #[clap(name = "PATH")]
+        paths: Vec<String>,
+
+        /// Copy from the local filesystem to the stash
+        #[clap(long, conflicts_with = "from_stash", required_unless_present = "from_stash")]
+        to_stash: bool,
+
+        /// Copy from the stash to the local filesystem
+        #[clap(long)]
+        from_stash: bool,
+
+        /// Also remove paths that do not exist in the source, and dirs that need to be
+        /// replaced by a file or symlink. Removing stash dirents does not delete files.
+        #[clap(long)]
+        delete: bool,
+
+        /// Print what would be done without doing it
+        #[clap(long, short = 'n')]
+        dry_run: bool,
+
+        /// Also compare files by BLAKE3 hash, like `es x status --checksum`
+        #[clap(long, short = 'c')]
+        checksum: bool,
+
+        /// Record each path as it is synced in this file, and skip paths already
+        /// recorded there with the same action and source size and mtime, so that
+        /// an interrupted sync can be resumed by running it again. The file is
+        /// removed after a successful sync.
+        #[clap(long)]
+        journal: Option<PathBuf>,
+
+        /// Number of files to retrieve at a time when retrieving a dir from the stash
+        #[clap(long, short = 'j', default_value_t = 4)]
+        jobs: usize,
     },
 
     /// Create a directory. This does not follow the new_dirent_requirements set in config.toml.
@@ -624,6 +1006,67 @@ enum PathCommand {
         #[clap(name = "PATH")]
         paths: Vec<String>,
     },
+
+    /// Print the history of a path: when each dirent at the path was created and
+    /// removed, and what it pointed to. The path does not need to exist now.
+    #[clap(name = "history")]
+    History {
+        /// Path to print the history of, relative to cwd
+        #[clap(name = "PATH")]
+        paths: Vec<String>,
+
+        /// Also print the history of everything that was ever in a directory at the path
+        #[clap(long, short = 'r')]
+        recursive: bool,
+
+        /// Print a JSON object per line instead of text
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Restore a removed dirent from the history of dirents, along with everything
+    /// that was below it if it was a directory. Dirents removed by `es x rm` and
+    /// `es x sync --delete` can be restored, as long as their files have not been deleted.
+    #[clap(name = "undelete")]
+    Undelete {
+        /// Path to a removed dirent to restore, relative to cwd
+        #[clap(name = "PATH")]
+        paths: Vec<String>,
+
+        /// Restore the dirent that existed at this time, instead of the most recently
+        /// removed one as it was just before its removal, e.g. 2024-01-31T00:00:00Z or 2024-01-31
+        #[clap(long, value_parser = parse_timestamp)]
+        as_of: Option<DateTime<Utc>>,
+    },
+
+    /// Move or rename a dirent. If DST is an existing directory, SRC is moved
+    /// into it. A directory cannot be moved into itself or its descendants.
+    /// For your convenience, if SRC exists in cwd and DST does not, the same
+    /// move is also done in cwd.
+    #[clap(name = "mv")]
+    Mv {
+        /// Path to a dirent to move, relative to cwd
+        #[clap(name = "SRC")]
+        src: String,
+
+        /// Path to move the dirent to, relative to cwd
+        #[clap(name = "DST")]
+        dst: String,
+    },
+
+    /// Create a new dirent at DST pointing to the same file or symlink as SRC,
+    /// like a hard link. If DST is an existing directory, the new dirent is
+    /// created inside it. Directories cannot be linked.
+    #[clap(name = "ln")]
+    Ln {
+        /// Path to a file or symlink to link to, relative to cwd
+        #[clap(name = "SRC")]
+        src: String,
+
+        /// Path at which to create the new dirent, relative to cwd
+        #[clap(name = "DST")]
+        dst: String,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -660,6 +1103,69 @@ enum ExastashCommand {
     #[clap(subcommand, name = "x")]
     Path(PathCommand),
 
+    /// Search the entire stash for dirents with a matching basename and print
+    /// their paths from the root of the stash, e.g. /a/b/c
+    #[clap(name = "search")]
+    Search {
+        /// Glob to match against basenames. A pattern without wildcards matches
+        /// any basename that contains it.
+        #[clap(name = "PATTERN")]
+        pattern: String,
+
+        /// Match the pattern regardless of case
+        #[clap(long, short = 'i')]
+        ignore_case: bool,
+
+        /// Limit output to paths pointing to inodes of this type (d = dir, f = file, s = symlink)
+        #[clap(value_enum, long, short = 't')]
+        r#type: Option<FindKind>,
+
+        /// Limit output to files with a size of more than (+N), less than (-N), or
+        /// exactly (N) N bytes, or N units of k, M, or G with a suffix, e.g. +10M
+        #[clap(long, allow_hyphen_values = true)]
+        size: Option<path::find::SizeFilter>,
+
+        /// Limit output to paths last modified more than (+N), less than (-N), or
+        /// exactly (N) N days ago
+        #[clap(long, allow_hyphen_values = true)]
+        mtime: Option<path::find::AgeFilter>,
+
+        /// Limit output to paths last modified after this time, e.g. 2024-01-31T00:00:00Z or 2024-01-31
+        #[clap(long, value_parser = parse_timestamp)]
+        newer: Option<DateTime<Utc>>,
+
+        /// Limit output to paths under this dir, given as a path from the root of the stash, e.g. /a/b
+        #[clap(long)]
+        under: Option<String>,
+
+        /// Print paths separated by NULL instead of LF
+        #[clap(short = '0', conflicts_with = "json")]
+        null_sep: bool,
+
+        /// Print a JSON object per line with the path, inode metadata, and storages
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Commands to find files with the same content and point their dirents at one of them
+    #[clap(subcommand, name = "dedupe")]
+    Dedupe(DedupeCommand),
+
+    /// Delete files and symlinks that no dirent points to, along with their storages.
+    /// Files with namedfiles or internetarchive storages, or with fofs storages on
+    /// another machine, are skipped.
+    #[clap(name = "gc")]
+    Gc {
+        /// Only collect files and symlinks born at least this many days ago, and not
+        /// pointed to by any dirent in this many days
+        #[clap(long, default_value_t = 30)]
+        grace_days: u32,
+
+        /// Print what would be deleted without deleting anything
+        #[clap(long, short = 'n')]
+        dry_run: bool,
+    },
+
     /// web server
     #[clap(name = "web")]
     Web {
@@ -671,114 +1177,1365 @@ enum ExastashCommand {
     License,
 }
 
+/// Parse a timestamp argument in RFC 3339 format, or a date that is taken to mean midnight UTC
+fn parse_timestamp(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+    Ok(DateTime::parse_from_rfc3339(s)?.with_timezone(&Utc))
+}
+
 async fn resolve_path(transaction: &mut Transaction<'_, Postgres>, root: i64, path: &str) -> Result<InodeId> {
     let path_components: Vec<&str> = if path.is_empty() {
         vec![]
     } else {
         path.split('/').collect()
     };
-    traversal::resolve_inode(transaction, root, &path_components).await
+    traversal::resolve_inode(transaction, root, &path_components, None).await
 }
 
-#[async_recursion]
-async fn walk_dir(transaction: &mut Transaction<'_, Postgres>, root: i64, segments: &[&str], dir_id: i64) -> Result<()> {
-    let path_string = match segments {
-        [] => "".into(),
-        parts => format!("{}/", parts.join("/")),
-    };
-    let dirents = Dirent::find_by_parents(transaction, &[dir_id]).await?;
-    for dirent in dirents {
+/// Resolve a DST path argument to the `(parent dir id, basename)` for a new dirent,
+/// validated against the `new_dirent_requirements` of its path root. Like coreutils,
+/// if DST is an existing dir, the new dirent goes inside it with basename `src_basename`.
+/// Also returns the local path corresponding to the new dirent.
+async fn resolve_new_dirent_destination(
+    config: &config::Config,
+    transaction: &mut Transaction<'_, Postgres>,
+    dst_arg: &str,
+    src_basename: &str,
+) -> Result<(i64, String, PathBuf)> {
+    let mut path_components = path::resolve_local_path_to_path_components(Some(dst_arg))?;
+    let (path_roots_value, idx) = path::resolve_root_of_local_path(config, &path_components)?;
+    let base_dir = path_roots_value.dir_id;
+    if let Ok(InodeId::Dir(_)) = traversal::resolve_inode(transaction, base_dir, &path_components[idx..], None).await {
+        path_components.push(src_basename.to_string());
+    }
+    let remaining_components = &path_components[idx..];
+    if remaining_components.is_empty() {
+        bail!("{dst_arg:?} is a path root and cannot be replaced");
+    }
+    path::validate_path_components(remaining_components, &path_roots_value.new_dirent_requirements)?;
+    let (basename, dir_components) = remaining_components.split_last().unwrap();
+    let parent = traversal::resolve_inode(transaction, base_dir, dir_components, None).await?.dir_id()?;
+    let local_path = PathBuf::from(format!("/{}", path_components.join("/")));
+    Ok((parent, basename.clone(), local_path))
+}
+
+/// Print the creations and removals of the dirent versions in `versions`, each paired with
+/// the path to print for it, in chronological order. If `recursive`, also print the history
+/// of every dir that was ever a child, with paths based on the first path seen for each dir.
+async fn print_dirent_history(
+    transaction: &mut Transaction<'_, Postgres>,
+    mut versions: Vec<(String, DirentVersion)>,
+    recursive: bool,
+    json: bool,
+) -> Result<()> {
+    if recursive {
+        let mut visited = HashSet::new();
+        let mut level: Vec<(i64, String)> = versions
+            .iter()
+            .filter_map(|(path, version)| version.dirent.child.dir_id().ok().map(|id| (id, path.clone())))
+            .collect();
+        while !level.is_empty() {
+            // A dir may have been moved around, but only walk each dir once
+            let mut dir_paths = HashMap::new();
+            for (dir_id, path) in level.drain(..) {
+                if visited.insert(dir_id) {
+                    dir_paths.insert(dir_id, path);
+                }
+            }
+            let dir_ids: Vec<i64> = dir_paths.keys().copied().collect();
+            for version in Dirent::find_versions_by_parents(transaction, &dir_ids).await? {
+                let path = format!("{}/{}", dir_paths.get(&version.dirent.parent).unwrap(), version.dirent.basename);
+                if let InodeId::Dir(dir_id) = version.dirent.child {
+                    level.push((dir_id, path.clone()));
+                }
+                versions.push((path, version));
+            }
+        }
+    }
+
+    let children: Vec<InodeId> = versions.iter().map(|(_, version)| version.dirent.child).collect();
+    let inodes = Inode::find_by_inode_ids(transaction, &children).await?;
+
+    // (time, whether the dirent was created rather than removed, path, child)
+    let mut events = vec![];
+    for (path, version) in &versions {
+        events.push((version.row_start, true, path, version.dirent.child));
+        if let Some(row_end) = version.row_end {
+            events.push((row_end, false, path, version.dirent.child));
+        }
+    }
+    // For a dirent replaced in a single transaction, the removal sorts before the creation
+    events.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+    for (time, created, path, child) in events {
+        // Dirs are deleted along with their dirents, so an old dir may be gone
+        let inode = inodes.get(&child);
+        let (size, b3sum, target) = match inode {
+            Some(Inode::File(file)) => (Some(file.size), file.b3sum.map(hex::encode), None),
+            Some(Inode::Symlink(symlink)) => (None, None, Some(symlink.target.as_str())),
+            _ => (None, None, None),
+        };
+        if json {
+            let j = json!({
+                "time":       time,
+                "event":      if created { "create" } else { "remove" },
+                "path":       path,
+                "dir_id":     if let InodeId::Dir(id)     = child { Some(id) } else { None },
+                "file_id":    if let InodeId::File(id)    = child { Some(id) } else { None },
+                "symlink_id": if let InodeId::Symlink(id) = child { Some(id) } else { None },
+                "size":       size,
+                "b3sum":      b3sum,
+                "target":     target,
+            });
+            println!("{j}");
+        } else {
+            let time = time.format("%Y-%m-%d %H:%M:%S%.6f");
+            let sign = if created { '+' } else { '-' };
+            let description = match child {
+                InodeId::Dir(id) => format!("dir {id}"),
+                InodeId::File(id) => {
+                    let size = size.map_or_else(|| "?".into(), commaify_i64);
+                    format!("file {id}, {size} bytes, b3sum {}", b3sum.as_deref().unwrap_or("?"))
+                }
+                InodeId::Symlink(id) => format!("symlink {id} -> {}", target.unwrap_or("?")),
+            };
+            println!("{time} {sign} {path}: {description}");
+        }
+    }
+    Ok(())
+}
+
+/// Print path info in JSON format for every dirent below dir `root`
+async fn walk_dir(transaction: &mut Transaction<'_, Postgres>, root: i64) -> Result<()> {
+    let mut entries = traversal::walk(transaction, root, None);
+    while let Some(entry) = entries.try_next().await? {
+        let child = entry.dirent.child;
         let j = json!({
             "root":       root,
-            "path":       format!("{path_string}{}", dirent.basename),
-            "dir_id":     if let InodeId::Dir(id)     = dirent.child { Some(id) } else { None },
-            "file_id":    if let InodeId::File(id)    = dirent.child { Some(id) } else { None },
-            "symlink_id": if let InodeId::Symlink(id) = dirent.child { Some(id) } else { None },
+            "path":       entry.path,
+            "dir_id":     if let InodeId::Dir(id)     = child { Some(id) } else { None },
+            "file_id":    if let InodeId::File(id)    = child { Some(id) } else { None },
+            "symlink_id": if let InodeId::Symlink(id) = child { Some(id) } else { None },
         });
         println!("{j}");
-        if let InodeId::Dir(dir_id) = dirent.child {
-            let segments = [segments, &[&dirent.basename]].concat();
-            walk_dir(transaction, root, &segments, dir_id).await?;
-        }
     }
     Ok(())
 }
 
-#[async_recursion]
-async fn x_find(
-    transaction: &mut Transaction<'_, Postgres>,
-    segments: &[&str],
-    dir_id: i64,
+/// How `x_find` selects and prints paths
+#[derive(Debug)]
+struct FindOptions {
     r#type: Option<FindKind>,
-    terminator: char
-) -> Result<()> {
-    let path_string = match segments {
-        [] => "".into(),
-        parts => format!("{}/", parts.join("/")),
-    };
-    let dirents = Dirent::find_by_parents(transaction, &[dir_id]).await?;
-    for dirent in dirents {
-        // No type filter means we output
-        let mut do_output = false;
-        if r#type.is_none() {
-            do_output = true;
-        } else {
-            // Make sure the type matches
-            match dirent.child {
-                InodeId::Dir(_)     => if r#type == Some(FindKind::d) { do_output = true; },
-                InodeId::File(_)    => if r#type == Some(FindKind::f) { do_output = true; },
-                InodeId::Symlink(_) => if r#type == Some(FindKind::s) { do_output = true; },
-            };
-        }
+    filter: path::find::Filter,
+    json: bool,
+    terminator: char,
+    now: DateTime<Utc>,
+}
 
-        if do_output {
-            print!("{path_string}{}{terminator}", dirent.basename);
+impl FindOptions {
+    fn matches_type(&self, inode_id: InodeId) -> bool {
+        match (self.r#type, inode_id) {
+            (None, _) => true,
+            (Some(FindKind::d), InodeId::Dir(_)) => true,
+            (Some(FindKind::f), InodeId::File(_)) => true,
+            (Some(FindKind::s), InodeId::Symlink(_)) => true,
+            _ => false,
         }
+    }
 
-        if let InodeId::Dir(dir_id) = dirent.child {
-            let segments = [segments, &[&dirent.basename]].concat();
-            x_find(transaction, &segments, dir_id, r#type, terminator).await?;
-        }
+    fn needs_inodes(&self) -> bool {
+        self.json || self.filter.needs_inode()
+    }
+
+    fn needs_storages(&self) -> bool {
+        self.json || self.filter.needs_storages()
+    }
+}
+
+/// Print `path` if it passes the filter in `options`
+fn find_print_if_matches(
+    path: &str,
+    basename: &str,
+    inode_id: InodeId,
+    inode: Option<&Inode>,
+    storages: Vec<StorageView>,
+    options: &FindOptions,
+) -> Result<()> {
+    if !options.matches_type(inode_id) || !options.filter.matches_name(basename) {
+        return Ok(());
+    }
+    if !options.filter.matches_inode(inode, &storages, options.now) {
+        return Ok(());
+    }
+    if options.json {
+        // With --as-of, the inode may no longer exist
+        let Some(inode) = inode else {
+            return Ok(());
+        };
+        println!("{}", json_path_info(path, inode, storages)?);
+    } else {
+        print!("{path}{}", options.terminator);
     }
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let env_filter = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("warn"))
-        .unwrap();
-    tracing_subscriber::fmt()
-        .with_writer(std::io::stderr)
-        .with_env_filter(env_filter)
-        .init();
+/// A short description of a storage, e.g. fofs:5, gdrive:1, or inline
+fn storage_label(view: &StorageView) -> String {
+    match view {
+        StorageView::Fofs(view) => format!("fofs:{}", view.pile_id),
+        StorageView::Inline(_) => "inline".into(),
+        StorageView::Gdrive(storage) => format!("gdrive:{}", storage.google_domain),
+        StorageView::NamedFiles(_) => "namedfiles".into(),
+        StorageView::InternetArchive(_) => "internetarchive".into(),
+    }
+}
 
-    // Do this first for --help to work without a database connection
-    let command = ExastashCommand::parse();
+/// Find the sets of files with the same content that can be merged under `rule`,
+/// along with the storages of every file in them
+async fn dedupe_plan(rule: dedupe::Rule) -> Result<(dedupe::Plan, HashMap<i64, Vec<StorageView>>)> {
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    let files = File::find_duplicates(&mut transaction).await?;
+    transaction.commit().await?; // close read-only transaction
+
+    let file_ids: Vec<i64> = files.iter().map(|file| file.id).collect();
+    let mut storages: HashMap<i64, Vec<StorageView>> = HashMap::new();
+    if !file_ids.is_empty() {
+        for view in get_storage_views(&file_ids).await? {
+            storages.entry(view.file_id()).or_default().push(view);
+        }
+    }
+    let descriptors = dedupe_storage_descriptors(&storages);
+    let plan = dedupe::plan(files, &descriptors, rule);
+    Ok((plan, storages))
+}
 
-    if let ExastashCommand::License = command {
-        print!("{}", include_str!("../LICENSE"));
-        return Ok(());
+/// Describe the inline, fofs, and gdrive storages of each file in `storages`
+fn dedupe_storage_descriptors(storages: &HashMap<i64, Vec<StorageView>>) -> HashMap<i64, storage::StoragesDescriptor> {
+    storages.iter().map(|(id, views)| (*id, storage::StoragesDescriptor::of_storages(views))).collect()
+}
+
+/// Print each set of files with the same content that can be merged under `rule`
+async fn dedupe_report(rule: dedupe::Rule, json: bool) -> Result<()> {
+    let (dedupe::Plan { sets, uncovered }, mut storages) = dedupe_plan(rule).await?;
+    let descriptors = dedupe_storage_descriptors(&storages);
+
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    let file_ids: Vec<i64> = sets.iter()
+        .flat_map(|set| std::iter::once(&set.canonical).chain(&set.redundant))
+        .map(|file| file.id)
+        .collect();
+    let mut dirents: HashMap<i64, Vec<Dirent>> = HashMap::new();
+    for dirent in Dirent::find_by_child_files(&mut transaction, &file_ids).await? {
+        dirents.entry(dirent.child.file_id()?).or_default().push(dirent);
     }
 
-    let mut pool = db::pgpool().await;
-    match command {
-        ExastashCommand::License => {
-            // Handled above
-            unreachable!();
-        },
-        ExastashCommand::Dir(command) => {
-            match command {
-                DirCommand::Create { parent_dir_id, basename } => {
-                    let mut transaction = pool.begin().await?;
-                    let mtime = Utc::now();
-                    let birth = db::inode::Birth::here_and_now();
-                    let dir = NewDir { mtime, birth }.create(&mut transaction).await?;
-                    Dirent::new(parent_dir_id, basename, InodeId::Dir(dir.id)).create(&mut transaction).await?;
-                    transaction.commit().await?;
-                    println!("{}", dir.id);
+    let mut stash_paths = StashPaths::default();
+    let mut total_redundant = 0;
+    let mut total_bytes = 0;
+    for set in &sets {
+        let reclaimable_bytes = set.reclaimable_bytes(&descriptors);
+        total_redundant += set.redundant.len() as i64;
+        total_bytes += reclaimable_bytes;
+        if !json {
+            println!("{} {} bytes, {} bytes reclaimable",
+                set.canonical.b3sum.map(hex::encode).unwrap_or_default(),
+                commaify_i64(set.canonical.size), commaify_i64(reclaimable_bytes));
+        }
+        let mut files_j = vec![];
+        for (keep, file) in std::iter::once((true, &set.canonical)).chain(set.redundant.iter().map(|file| (false, file))) {
+            let mut paths = vec![];
+            for dirent in dirents.get(&file.id).map_or(&[][..], Vec::as_slice) {
+                paths.push(stash_paths.path(&mut transaction, dirent).await?);
+            }
+            let file_storages = storages.remove(&file.id).unwrap_or_default();
+            if json {
+                files_j.push(json!({
+                    "id": file.id,
+                    "keep": keep,
+                    "mtime": file.mtime,
+                    "executable": file.executable,
+                    "storages": file_storages,
+                    "paths": paths,
+                }));
+            } else {
+                let labels: Vec<String> = file_storages.iter().map(storage_label).collect();
+                println!("  {} file {} mtime {} executable {} storages [{}]",
+                    if keep { "keep  " } else { "relink" }, file.id, file.mtime, file.executable, labels.join(", "));
+                for path in paths {
+                    println!("    {path}");
                 }
-                DirCommand::Delete { dir_id } => {
+            }
+        }
+        if json {
+            let j = json!({
+                "b3sum": set.canonical.b3sum.map(hex::encode),
+                "size": set.canonical.size,
+                "reclaimable_bytes": reclaimable_bytes,
+                "files": files_j,
+            });
+            println!("{j}");
+        }
+    }
+    transaction.commit().await?; // close read-only transaction
+
+    // Relinking these would leave storages that only the redundant files have for `es gc` to delete
+    let skip_reason = "no file has every inline, fofs, and gdrive storage of the others";
+    for files in &uncovered {
+        let b3sum = files[0].b3sum.map(hex::encode);
+        if json {
+            let files_j: Vec<_> = files.iter().map(|file| json!({
+                "id": file.id,
+                "mtime": file.mtime,
+                "executable": file.executable,
+                "storages": storages.get(&file.id).map_or(&[][..], Vec::as_slice),
+            })).collect();
+            let j = json!({
+                "b3sum": b3sum,
+                "size": files[0].size,
+                "skipped": skip_reason,
+                "files": files_j,
+            });
+            println!("{j}");
+        } else {
+            println!("{} {} bytes, skipped: {skip_reason}", b3sum.unwrap_or_default(), commaify_i64(files[0].size));
+            for file in files {
+                let labels: Vec<String> = storages.get(&file.id).map_or(&[][..], Vec::as_slice).iter().map(storage_label).collect();
+                println!("  file {} mtime {} executable {} storages [{}]", file.id, file.mtime, file.executable, labels.join(", "));
+            }
+        }
+    }
+
+    if !json {
+        println!("total: {} sets, {} redundant files, {} bytes reclaimable, {} sets skipped",
+            commaify_i64(sets.len() as i64), commaify_i64(total_redundant), commaify_i64(total_bytes),
+            commaify_i64(uncovered.len() as i64));
+    }
+    Ok(())
+}
+
+/// Point the dirents of the redundant files in each set of files with the same
+/// content that can be merged under `rule` at the file that is kept
+async fn dedupe_apply(rule: dedupe::Rule, dry_run: bool) -> Result<()> {
+    let (dedupe::Plan { sets, uncovered }, _) = dedupe_plan(rule).await?;
+    let pool = db::pgpool().await;
+    let verb = if dry_run { "would relink" } else { "relinked" };
+    for files in &uncovered {
+        let ids: Vec<i64> = files.iter().map(|file| file.id).collect();
+        println!("skipping files {ids:?} because none of them has every inline, fofs, and gdrive storage of the others");
+    }
+    let mut total_dirents = 0;
+    for set in &sets {
+        let redundant_ids: Vec<i64> = set.redundant.iter().map(|file| file.id).collect();
+        let mut transaction = pool.begin().await?;
+        let dirents = if dry_run {
+            let dirents = Dirent::find_by_child_files(&mut transaction, &redundant_ids).await?;
+            transaction.commit().await?; // close read-only transaction
+            dirents
+        } else {
+            let dirents = dedupe::relink(&mut transaction, set).await?;
+            transaction.commit().await?;
+            dirents
+        };
+        println!("{verb} {} dirents from files {redundant_ids:?} to file {}", dirents.len(), set.canonical.id);
+        total_dirents += dirents.len() as i64;
+    }
+    println!("total: {verb} {} dirents in {} sets; `es gc` deletes the redundant files after its grace period",
+        commaify_i64(total_dirents), commaify_i64(sets.len() as i64));
+    Ok(())
+}
+
+/// Builds paths from the root of the stash, caching the path segments to each dir
+/// because many of the dirents we need paths for are often in the same dir
+#[derive(Debug, Default)]
+struct StashPaths {
+    dir_segments: HashMap<i64, Vec<String>>,
+}
+
+impl StashPaths {
+    /// Return the path segments from the root of the stash to dir `dir_id`
+    async fn segments(&mut self, transaction: &mut Transaction<'_, Postgres>, dir_id: i64) -> Result<&[String]> {
+        if !self.dir_segments.contains_key(&dir_id) {
+            let segments = traversal::get_path_segments_from_root_to_dir(transaction, dir_id).await?;
+            self.dir_segments.insert(dir_id, segments);
+        }
+        Ok(&self.dir_segments[&dir_id])
+    }
+
+    /// Return the path from the root of the stash to `dirent`, e.g. /a/b/c
+    async fn path(&mut self, transaction: &mut Transaction<'_, Postgres>, dirent: &Dirent) -> Result<String> {
+        let mut path = String::new();
+        for segment in self.segments(transaction, dirent.parent).await?.iter().chain([&dirent.basename]) {
+            path.push('/');
+            path.push_str(segment);
+        }
+        Ok(path)
+    }
+}
+
+/// Print the path from the root of the stash of every dirent that passes the filter
+/// in `options`, which must have a name glob, optionally limited to the dirents
+/// under the dir at stash path `under`.
+async fn search(
+    transaction: &mut Transaction<'_, Postgres>,
+    under: Option<&str>,
+    options: &FindOptions,
+) -> Result<()> {
+    let glob = options.filter.name.as_ref().ok_or_else(|| anyhow!("search: need a name glob"))?;
+    let under = match under {
+        Some(under) => {
+            let components: Vec<String> = under.split('/').filter(|c| !c.is_empty()).map(String::from).collect();
+            traversal::resolve_inode(transaction, 1, &components, None).await?.dir_id()?
+        }
+        None => 1,
+    };
+
+    // The LIKE pattern may match more than the glob, so filter again by name
+    let dirents: Vec<Dirent> = Dirent::find_by_basename_like(transaction, &glob.like_pattern(), glob.is_case_insensitive(), under).await?
+        .into_iter()
+        .filter(|dirent| options.matches_type(dirent.child) && options.filter.matches_name(&dirent.basename))
+        .collect();
+
+    let inode_ids: Vec<InodeId> = dirents.iter().map(|dirent| dirent.child).collect();
+    let inodes = if options.needs_inodes() {
+        Inode::find_by_inode_ids(transaction, &inode_ids).await?
+    } else {
+        HashMap::new()
+    };
+    let mut storages: HashMap<i64, Vec<StorageView>> = HashMap::new();
+    if options.needs_storages() {
+        let file_ids: Vec<i64> = inode_ids.iter().filter_map(|inode_id| inode_id.file_id().ok()).collect();
+        if !file_ids.is_empty() {
+            for view in get_storage_views(&file_ids).await? {
+                storages.entry(view.file_id()).or_default().push(view);
+            }
+        }
+    }
+
+    let mut stash_paths = StashPaths::default();
+    for dirent in dirents {
+        let path = stash_paths.path(transaction, &dirent).await?;
+        let storages = match dirent.child {
+            InodeId::File(id) => storages.remove(&id).unwrap_or_default(),
+            _ => vec![],
+        };
+        find_print_if_matches(&path, &dirent.basename, dirent.child, inodes.get(&dirent.child), storages, options)?;
+    }
+    Ok(())
+}
+
+/// Print the paths below dir `dir_id` that pass the filter in `options`,
+/// prefixing each path with `path_arg`.
+async fn x_find(
+    transaction: &mut Transaction<'_, Postgres>,
+    path_arg: &str,
+    dir_id: i64,
+    options: &FindOptions,
+    as_of: Option<DateTime<Utc>>,
+) -> Result<()> {
+    // Fetch storages for many files at once instead of per dirent
+    let mut chunks = traversal::walk(transaction, dir_id, as_of).try_chunks(1000);
+    while let Some(entries) = chunks.try_next().await.map_err(|err| err.1)? {
+        let mut storages: HashMap<i64, Vec<StorageView>> = HashMap::new();
+        if options.needs_storages() {
+            let file_ids: Vec<i64> = entries.iter()
+                .filter(|entry| options.filter.matches_name(&entry.dirent.basename))
+                .filter_map(|entry| entry.dirent.child.file_id().ok())
+                .collect();
+            if !file_ids.is_empty() {
+                for view in get_storage_views(&file_ids).await? {
+                    storages.entry(view.file_id()).or_default().push(view);
+                }
+            }
+        }
+        for entry in entries {
+            let path = format!("{path_arg}/{}", entry.path);
+            let storages = match entry.dirent.child {
+                InodeId::File(id) => storages.remove(&id).unwrap_or_default(),
+                _ => vec![],
+            };
+            find_print_if_matches(&path, &entry.dirent.basename, entry.dirent.child, entry.inode.as_ref(), storages, options)?;
+        }
+    }
+    Ok(())
+}
+
+/// Retrieve stash file `file` to `local_path`, with its executable bit and mtime.
+/// If `skip_if_exists`, skip the retrieval if a local file with a matching size and mtime exists.
+async fn get_file(file: &File, local_path: &Path, skip_if_exists: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if skip_if_exists {
+        match fs::metadata(local_path).await {
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    bail!(err);
+                }
+            }
+            Ok(attr) => {
+                let metadata: storage::RelevantFileMetadata = attr.try_into()?;
+                if file.mtime == metadata.mtime && file.size == metadata.size {
+                    info!(?local_path, "file already exists locally with matching size and mtime");
+
+                    let permissions = std::fs::Permissions::from_mode(
+                        if file.executable { 0o770 } else { 0o660 }
+                    );
+                    fs::set_permissions(local_path, permissions).await?;
+
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Remove any existing file to reset permissions
+    if let Err(err) = fs::remove_file(local_path).await {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            bail!(err);
+        }
+    }
+
+    if let Some(dir_path) = local_path.parent() {
+        fs::create_dir_all(dir_path).await?;
+    }
+
+    let mut local_file = fs::File::create(local_path).await?;
+    let (stream, file) = storage::read::read(file.id).await?;
+    storage::read::write_stream_to_sink(stream, &mut local_file).await?;
+
+    if file.executable {
+        let permissions = std::fs::Permissions::from_mode(0o770);
+        fs::set_permissions(local_path, permissions).await?;
+    }
+
+    let mtime = filetime::FileTime::from_system_time(file.mtime.into());
+    filetime::set_file_mtime(local_path, mtime)?;
+    Ok(())
+}
+
+/// Create a local symlink at `local_path` with the target and mtime of stash symlink `symlink`,
+/// replacing any existing file or symlink at `local_path`.
+async fn get_symlink(symlink: &Symlink, local_path: &Path) -> Result<()> {
+    let up_to_date = match fs::read_link(local_path).await {
+        Ok(target) => target.to_str() == Some(&symlink.target),
+        Err(_) => false,
+    };
+    if !up_to_date {
+        if let Err(err) = fs::remove_file(local_path).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                bail!(err);
+            }
+        }
+        if let Some(dir_path) = local_path.parent() {
+            fs::create_dir_all(dir_path).await?;
+        }
+        fs::symlink(&symlink.target, local_path).await?;
+    }
+    let mtime = filetime::FileTime::from_system_time(symlink.mtime.into());
+    filetime::set_symlink_file_times(local_path, mtime, mtime)?;
+    Ok(())
+}
+
+/// Retrieve stash dir `dir_id` and all of its descendants to `local_path`, retrieving up
+/// to `jobs` files at a time. Dir mtimes are applied after their children are written.
+/// If `as_of` is given, retrieve the tree and dir mtimes as they were at that time; dirs
+/// that are missing from the dirs_history table are created without setting their mtimes.
+async fn get_dir(dir_id: i64, local_path: &Path, skip_if_exists: bool, jobs: usize, as_of: Option<DateTime<Utc>>) -> Result<()> {
+    let pool = db::pgpool().await;
+
+    // Collect the entire tree before doing the unpredictably-long read operations
+    let mut transaction = pool.begin().await?;
+    let roots = match as_of {
+        None => Dir::find_by_ids(&mut transaction, &[dir_id]).await?,
+        Some(as_of) => Dir::find_by_ids_as_of(&mut transaction, &[dir_id], as_of).await?,
+    };
+    let root_mtime = roots.into_iter().next().map(|dir| dir.mtime);
+    let mut dirs = vec![(local_path.to_path_buf(), root_mtime)];
+    let mut files = vec![];
+    let mut symlinks = vec![];
+    let mut entries = traversal::walk(&mut transaction, dir_id, as_of);
+    while let Some(entry) = entries.try_next().await? {
+        let path = local_path.join(&entry.path);
+        match (entry.dirent.child, entry.inode) {
+            (InodeId::Dir(_), inode) => dirs.push((path, inode.map(|inode| inode.mtime()))),
+            (_, Some(Inode::File(file))) => files.push((path, file)),
+            (_, Some(Inode::Symlink(symlink))) => symlinks.push((path, symlink)),
+            (inode_id, _) => bail!("{:?} for {:?} not found in database", inode_id, path),
+        }
+    }
+    drop(entries);
+    transaction.commit().await?; // close read-only transaction
+
+    for (path, _) in &dirs {
+        fs::create_dir_all(path).await?;
+    }
+    for (path, symlink) in &symlinks {
+        get_symlink(symlink, path).await?;
+    }
+    stream::iter(files.into_iter().map(Ok))
+        .try_for_each_concurrent(jobs, |(path, file)| async move {
+            get_file(&file, &path, skip_if_exists).await
+        }).await?;
+
+    // `dirs` is in depth-first order with each dir before its descendants, so
+    // reversing it sets the mtime of each dir after the mtimes of all of its descendants.
+    for (path, mtime) in dirs.iter().rev() {
+        if let Some(mtime) = mtime {
+            let mtime = filetime::FileTime::from_system_time((*mtime).into());
+            filetime::set_file_mtime(path, mtime)?;
+        }
+    }
+    Ok(())
+}
+
+/// If a dirent named `basename` exists under `dir_id`, apply `behavior` to it.
+/// Returns `true` if the caller should go ahead and create a new dirent there.
+async fn handle_existing_dirent(
+    transaction: &mut Transaction<'_, Postgres>,
+    dir_id: i64,
+    basename: &str,
+    stash_path: &[String],
+    behavior: &ExistingFileBehavior,
+) -> Result<bool> {
+    if let Some(existing) = Dirent::find_by_parent_and_basename(transaction, dir_id, basename).await? {
+        match behavior {
+            ExistingFileBehavior::stop => {
+                bail!("{:?} already exists as {:?}", stash_path, existing);
+            }
+            ExistingFileBehavior::skip => {
+                eprintln!("{stash_path:?} already exists as {existing:?}");
+                return Ok(false);
+            }
+            ExistingFileBehavior::replace => {
+                if let InodeId::Dir(_) = existing.child {
+                    bail!("{:?} already exists as {:?} and a dir cannot be replaced", stash_path, existing);
+                }
+                eprintln!("{stash_path:?} already exists as {existing:?} but replacing as requested");
+                existing.remove(transaction).await?;
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Return the mtimes of the local dirs at `path_components[..=i]`, for each `i` in `idx..path_components.len()`
+async fn local_dir_mtimes(path_components: &[String], idx: usize) -> Result<Vec<DateTime<Utc>>> {
+    let mut mtimes = vec![];
+    for i in idx..path_components.len() {
+        let local_path = format!("/{}", path_components[..=i].join("/"));
+        let attr = fs::metadata(&local_path).await?;
+        mtimes.push(util::without_nanos(attr.modified()?.into()));
+    }
+    Ok(mtimes)
+}
+
+/// Call `f` until it succeeds, waiting longer after each failure, for up to 30 tries.
+/// `description` is used in the message printed after each failure.
+async fn with_retries<T, F, Fut>(description: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let initial_delay = std::time::Duration::new(60, 0);
+    let maximum_delay = std::time::Duration::new(1800, 0);
+    let mut decayer = Decayer::new(initial_delay, Ratio::new(3, 2), maximum_delay);
+    let mut tries = 30;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                tries -= 1;
+                if tries == 0 {
+                    bail!(err);
+                }
+                let delay = decayer.decay();
+                eprintln!("{description} failed, {tries} tries left (next in {} sec): {err:?}", delay.as_secs());
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Create a stash file based on the local file at `local_path`, retrying on failure.
+/// Returns the id of the new file.
+async fn store_local_file(local_path: &str, metadata: &storage::RelevantFileMetadata, desired: &storage::StoragesDescriptor) -> Result<i64> {
+    with_retries(
+        &format!("storage::write::create_stash_file_from_local_file({local_path:?}, ...)"),
+        || storage::write::create_stash_file_from_local_file(local_path.to_string(), metadata, desired),
+    ).await
+}
+
+/// Add the local file, symlink, or dir at `path_arg` to the stash, recursing into dirs.
+/// New stash dirs get the mtimes of the corresponding local dirs.
+/// If `remove_local_files`, local files and symlinks are removed after their dirents are committed.
+/// If `dedupe`, or if policy.js's `dedupe_new_file` says so, new dirents point to existing
+/// files with the same content and mtime when possible instead of storing the local files again.
+async fn add_path(
+    config: &config::Config,
+    policy: &policy::Policy,
+    path_arg: &str,
+    behavior: &ExistingFileBehavior,
+    remove_local_files: bool,
+    dedupe: bool,
+) -> Result<()> {
+    // We need one transaction per new directory below, due to `dirents_check_insert_or_delete`.
+
+    let pool = db::pgpool().await;
+    let path_components = path::resolve_local_path_to_path_components(Some(path_arg))?;
+    let (path_roots_value, idx) = path::resolve_root_of_local_path(config, &path_components)?;
+    let base_dir = path_roots_value.dir_id;
+    let mut transaction = pool.begin().await?;
+    let components_to_base_dir = traversal::get_path_segments_from_root_to_dir(&mut transaction, base_dir).await?;
+    transaction.commit().await?; // close read-only transaction
+
+    // Walk depth-first, adding each dir before its children
+    let mut stack = vec![path_components];
+    while let Some(components) = stack.pop() {
+        let local_path = format!("/{}", components.join("/"));
+        let remaining_components = &components[idx..];
+        path::validate_path_components(remaining_components, &path_roots_value.new_dirent_requirements)?;
+        let stash_path = [&components_to_base_dir, remaining_components].concat();
+        let attr = fs::symlink_metadata(&local_path).await?;
+        let file_type = attr.file_type();
+
+        if !file_type.is_dir() && !file_type.is_file() && !file_type.is_symlink() {
+            eprintln!("skipping {local_path:?} because it is not a dir, file, or symlink");
+            continue;
+        }
+        let Some((basename, dir_components)) = remaining_components.split_last() else {
+            if !file_type.is_dir() {
+                bail!("{local_path:?} is a path root but not a dir");
+            }
+            // Adding a path root itself, which is already a stash dir, so just add the children
+            push_local_children(&mut stack, &components, &local_path).await?;
+            continue;
+        };
+        let mut dir_mtimes = local_dir_mtimes(&components[..components.len() - 1], idx).await?;
+
+        if file_type.is_dir() {
+            let mut transaction = pool.begin().await?;
+            let dir_id = traversal::make_dirs(&mut transaction, base_dir, dir_components, Some(&dir_mtimes)).await?.dir_id()?;
+            let existing = Dirent::find_by_parent_and_basename(&mut transaction, dir_id, basename).await?;
+            let is_existing_dir = matches!(existing, Some(Dirent { child: InodeId::Dir(_), .. }));
+            if !is_existing_dir && !handle_existing_dirent(&mut transaction, dir_id, basename, &stash_path, behavior).await? {
+                transaction.commit().await?;
+                continue;
+            }
+            dir_mtimes.push(util::without_nanos(attr.modified()?.into()));
+            traversal::make_dirs(&mut transaction, base_dir, remaining_components, Some(&dir_mtimes)).await?;
+            transaction.commit().await?;
+            push_local_children(&mut stack, &components, &local_path).await?;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&local_path).await?;
+            let target = target.to_str().ok_or_else(|| anyhow!("target of symlink {local_path:?} is not UTF-8"))?.to_string();
+            let mtime = util::without_nanos(attr.modified()?.into());
+            let mut transaction = pool.begin().await?;
+            let dir_id = traversal::make_dirs(&mut transaction, base_dir, dir_components, Some(&dir_mtimes)).await?.dir_id()?;
+            if !handle_existing_dirent(&mut transaction, dir_id, basename, &stash_path, behavior).await? {
+                transaction.commit().await?;
+                continue;
+            }
+            let birth = db::inode::Birth::here_and_now();
+            let symlink = NewSymlink { mtime, birth, target }.create(&mut transaction).await?;
+            Dirent::new(dir_id, basename, InodeId::Symlink(symlink.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            if remove_local_files {
+                info!(?local_path, "removing local symlink after committing to database");
+                fs::remove_file(&local_path).await?;
+            }
+        } else {
+            let metadata: storage::RelevantFileMetadata = (&attr).try_into()?;
+            let mut transaction = pool.begin().await?;
+            let dir_id = traversal::make_dirs(&mut transaction, base_dir, dir_components, Some(&dir_mtimes)).await?.dir_id()?;
+            let proceed = handle_existing_dirent(&mut transaction, dir_id, basename, &stash_path, behavior).await?;
+            transaction.commit().await?;
+            if !proceed {
+                continue;
+            }
+
+            let stash_path: Vec<&str> = stash_path.iter().map(String::as_str).collect();
+            let desired = policy.new_file_storages(&stash_path, &metadata)?;
+            let existing_file_id = if dedupe || policy.dedupe_new_file(&stash_path, &metadata)? {
+                storage::write::find_existing_file_for_local_file(Path::new(&local_path), &metadata, &desired).await?
+            } else {
+                None
+            };
+            let file_id = match existing_file_id {
+                Some(file_id) => {
+                    info!(?local_path, file_id, "linking to existing file with the same content instead of storing");
+                    file_id
+                }
+                None => store_local_file(&local_path, &metadata, &desired).await?,
+            };
+
+            let mut transaction = pool.begin().await?;
+            Dirent::new(dir_id, basename, InodeId::File(file_id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            if remove_local_files {
+                info!(?local_path, "removing local file after committing to database");
+                fs::remove_file(&local_path).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create the members of the tar or zip archive at `archive_path` under the stash dir
+/// at local path argument `dest_arg`, creating the dir if needed. New stash dirs get the
+/// mtimes of the corresponding dir members, if the archive has them before their children.
+/// File content is read straight from the archive, so unlike `add_path`, storing a
+/// file is not retried. A hard link member gets a dirent pointing to the stash file
+/// of the file member it links to.
+async fn import_archive(
+    config: &config::Config,
+    policy: &policy::Policy,
+    archive_path: &Path,
+    dest_arg: &str,
+    behavior: &ExistingFileBehavior,
+) -> Result<()> {
+    let pool = db::pgpool().await;
+    let path_components = path::resolve_local_path_to_path_components(Some(dest_arg))?;
+    let (path_roots_value, idx) = path::resolve_root_of_local_path(config, &path_components)?;
+    let base_dir = path_roots_value.dir_id;
+    let dest_components = &path_components[idx..];
+    path::validate_path_components(dest_components, &path_roots_value.new_dirent_requirements)?;
+    let mut transaction = pool.begin().await?;
+    let components_to_base_dir = traversal::get_path_segments_from_root_to_dir(&mut transaction, base_dir).await?;
+    let dest_id = traversal::make_dirs(&mut transaction, base_dir, dest_components, None).await?.dir_id()?;
+    transaction.commit().await?;
+
+    // The stash file for each file member, for the hard links to it later in the archive
+    let mut file_ids: HashMap<Vec<String>, i64> = HashMap::new();
+    let mut members = path::archive::read_archive(archive_path.to_path_buf());
+    while let Some(member) = members.recv().await {
+        let member = member?;
+        path::validate_path_components(&member.path, &path_roots_value.new_dirent_requirements)?;
+        let stash_path = [components_to_base_dir.as_slice(), dest_components, member.path.as_slice()].concat();
+        let Some((basename, dir_components)) = member.path.split_last() else {
+            // The archive's own top-level dir, e.g. "./"
+            continue;
+        };
+
+        let mut transaction = pool.begin().await?;
+        let dir_id = traversal::make_dirs(&mut transaction, dest_id, dir_components, None).await?.dir_id()?;
+        match member.kind {
+            MemberKind::Dir => {
+                let existing = Dirent::find_by_parent_and_basename(&mut transaction, dir_id, basename).await?;
+                let is_existing_dir = matches!(existing, Some(Dirent { child: InodeId::Dir(_), .. }));
+                if !is_existing_dir && handle_existing_dirent(&mut transaction, dir_id, basename, &stash_path, behavior).await? {
+                    traversal::make_dirs(&mut transaction, dir_id, &[basename], Some(&[member.mtime])).await?;
+                }
+                transaction.commit().await?;
+            }
+            MemberKind::Symlink { target } => {
+                if !handle_existing_dirent(&mut transaction, dir_id, basename, &stash_path, behavior).await? {
+                    transaction.commit().await?;
+                    continue;
+                }
+                let birth = db::inode::Birth::here_and_now();
+                let symlink = NewSymlink { mtime: member.mtime, birth, target }.create(&mut transaction).await?;
+                Dirent::new(dir_id, basename, InodeId::Symlink(symlink.id)).create(&mut transaction).await?;
+                transaction.commit().await?;
+            }
+            MemberKind::File { size, executable, content } => {
+                let proceed = handle_existing_dirent(&mut transaction, dir_id, basename, &stash_path, behavior).await?;
+                transaction.commit().await?;
+                if !proceed {
+                    continue;
+                }
+
+                let metadata = storage::RelevantFileMetadata { size: size as i64, mtime: member.mtime, executable };
+                let stash_path: Vec<&str> = stash_path.iter().map(String::as_str).collect();
+                let desired = policy.new_file_storages(&stash_path, &metadata)?;
+                let file_id = storage::write::create_stash_file_from_reader(content, &metadata, &desired).await?;
+
+                let mut transaction = pool.begin().await?;
+                Dirent::new(dir_id, basename, InodeId::File(file_id)).create(&mut transaction).await?;
+                transaction.commit().await?;
+                file_ids.insert(member.path.clone(), file_id);
+            }
+            MemberKind::HardLink { target } => {
+                let Some(&file_id) = file_ids.get(&target) else {
+                    eprintln!("skipping hard link {:?} to {:?} because that member was not imported as a file",
+                        member.path.join("/"), target.join("/"));
+                    transaction.commit().await?;
+                    continue;
+                };
+                if handle_existing_dirent(&mut transaction, dir_id, basename, &stash_path, behavior).await? {
+                    Dirent::new(dir_id, basename, InodeId::File(file_id)).create(&mut transaction).await?;
+                }
+                transaction.commit().await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolve local path argument `path_arg` to its stash equivalent inode, or `None`
+/// if some path component does not exist in the stash
+async fn resolve_local_path_arg_if_exists(
+    config: &config::Config,
+    transaction: &mut Transaction<'_, Postgres>,
+    path_arg: &str,
+) -> Result<Option<InodeId>> {
+    match path::resolve_local_path_arg(config, transaction, Some(path_arg), None).await {
+        Ok(inode_id) => Ok(Some(inode_id)),
+        Err(err) if err.downcast_ref::<traversal::TraversalError>().is_some() => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Retrieve the stash equivalent of local path `local_path` to `local_path`,
+/// retrying on failure
+async fn get_path(config: &config::Config, local_path: &Path, jobs: usize) -> Result<()> {
+    let pool = db::pgpool().await;
+    let path_arg = local_path.to_str().ok_or_else(|| anyhow!("could not convert path {:?} to UTF-8", local_path))?;
+    let mut transaction = pool.begin().await?;
+    let inode_id = path::resolve_local_path_arg(config, &mut transaction, Some(path_arg), None).await?;
+    let mut inodes = Inode::find_by_inode_ids(&mut transaction, &[inode_id]).await?;
+    transaction.commit().await?; // close read-only transaction
+    let inode = inodes.remove(&inode_id).ok_or_else(|| anyhow!("{:?} not found in database", inode_id))?;
+    with_retries(&format!("get_path({local_path:?}, ...)"), || async {
+        match &inode {
+            Inode::Dir(dir) => get_dir(dir.id, local_path, true, jobs, None).await,
+            Inode::File(file) => get_file(file, local_path, false).await,
+            Inode::Symlink(symlink) => get_symlink(symlink, local_path).await,
+        }
+    }).await
+}
+
+/// Remove local dir, file, or symlink `local_path`, including the contents of a dir
+async fn remove_local_path(local_path: &Path) -> Result<()> {
+    if fs::symlink_metadata(local_path).await?.is_dir() {
+        fs::remove_dir_all(local_path).await?;
+    } else {
+        fs::remove_file(local_path).await?;
+    }
+    Ok(())
+}
+
+/// Remove the stash equivalent of local path argument `path_arg`, including the
+/// subtree if it is a dir. Like `es x rm`, this does not delete any files or symlinks.
+async fn remove_stash_path(config: &config::Config, path_arg: &str) -> Result<()> {
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    let path_components = path::resolve_local_path_to_path_components(Some(path_arg))?;
+    let (path_roots_value, idx) = path::resolve_root_of_local_path(config, &path_components)?;
+    let dirent = traversal::resolve_dirent(&mut transaction, path_roots_value.dir_id, &path_components[idx..]).await?;
+    traversal::remove_tree(&mut transaction, &dirent).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Restore the dirent at local path argument `path_arg` from the dirents_history table,
+/// along with the subtree below it if it is a dir. See `traversal::find_removed_tree`
+/// for how `as_of` is used.
+async fn undelete_path(config: &config::Config, path_arg: &str, as_of: Option<DateTime<Utc>>) -> Result<()> {
+    let pool = db::pgpool().await;
+    let path_components = path::resolve_local_path_to_path_components(Some(path_arg))?;
+    let (path_roots_value, idx) = path::resolve_root_of_local_path(config, &path_components)?;
+    let Some((basename, dir_components)) = path_components[idx..].split_last() else {
+        bail!("{path_arg:?} is a path root and cannot be undeleted");
+    };
+    let mut transaction = pool.begin().await?;
+    let parent = traversal::resolve_inode(&mut transaction, path_roots_value.dir_id, dir_components, None).await?.dir_id()?;
+    let (dirents, as_of) = traversal::find_removed_tree(&mut transaction, parent, basename, as_of).await?;
+    traversal::restore_tree(&mut transaction, &dirents, as_of).await
+        .map_err(|err| anyhow!("cannot undelete {path_arg:?}: {err}"))?;
+    transaction.commit().await?;
+    eprintln!("restored {:?} under dir {:?} with {} dirent(s) below it as of {as_of}", basename, parent, dirents.len() - 1);
+    Ok(())
+}
+
+/// Carry out sync action `action` for local path `local_path` and its stash equivalent
+async fn do_sync_action(
+    config: &config::Config,
+    policy: &policy::Policy,
+    local_path: &Path,
+    direction: path::sync::Direction,
+    action: path::sync::Action,
+    jobs: usize,
+) -> Result<()> {
+    use path::sync::{Action, Direction};
+
+    let path_arg = local_path.to_str().ok_or_else(|| anyhow!("could not convert path {:?} to UTF-8", local_path))?;
+    match (direction, action) {
+        (_, Action::Skip { .. }) => {}
+        (Direction::ToStash, Action::Copy) => {
+            add_path(config, policy, path_arg, &ExistingFileBehavior::stop, false, false).await?;
+        }
+        (Direction::ToStash, Action::Replace) => {
+            add_path(config, policy, path_arg, &ExistingFileBehavior::replace, false, false).await?;
+        }
+        (Direction::ToStash, Action::Delete) => {
+            remove_stash_path(config, path_arg).await?;
+        }
+        (Direction::ToStash, Action::DeleteAndCopy) => {
+            remove_stash_path(config, path_arg).await?;
+            add_path(config, policy, path_arg, &ExistingFileBehavior::stop, false, false).await?;
+        }
+        (Direction::FromStash, Action::Copy) => {
+            get_path(config, local_path, jobs).await?;
+        }
+        // Remove first because the local path may be of a different type
+        (Direction::FromStash, Action::Replace | Action::DeleteAndCopy) => {
+            remove_local_path(local_path).await?;
+            get_path(config, local_path, jobs).await?;
+        }
+        (Direction::FromStash, Action::Delete) => {
+            remove_local_path(local_path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Return the journal entry for syncing `local_path` in `direction` with `action`,
+/// with the size and mtime of the source as they are now
+async fn sync_journal_entry(
+    config: &config::Config,
+    transaction: &mut Transaction<'_, Postgres>,
+    local_path: &Path,
+    direction: path::sync::Direction,
+    action: path::sync::Action,
+) -> Result<path::sync::JournalEntry> {
+    let (size, mtime) = match direction {
+        path::sync::Direction::ToStash => match fs::symlink_metadata(local_path).await {
+            Ok(attr) => (attr.is_file().then(|| attr.len() as i64), Some(attr.modified()?.into())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (None, None),
+            Err(err) => return Err(err.into()),
+        },
+        path::sync::Direction::FromStash => {
+            let path_arg = local_path.to_str().ok_or_else(|| anyhow!("could not convert path {:?} to UTF-8", local_path))?;
+            match resolve_local_path_arg_if_exists(config, transaction, path_arg).await? {
+                Some(inode_id) => {
+                    let mut inodes = Inode::find_by_inode_ids(transaction, &[inode_id]).await?;
+                    let inode = inodes.remove(&inode_id).ok_or_else(|| anyhow!("{:?} not found in database", inode_id))?;
+                    (inode.size(), Some(inode.mtime()))
+                }
+                None => (None, None),
+            }
+        }
+    };
+    Ok(path::sync::JournalEntry { path: local_path.to_path_buf(), action: action.to_string(), size, mtime })
+}
+
+/// Push the path components of the children of local dir `local_path` onto `stack`,
+/// such that they are popped in sorted order.
+async fn push_local_children(stack: &mut Vec<Vec<String>>, components: &[String], local_path: &str) -> Result<()> {
+    let mut names = vec![];
+    let mut entries = fs::read_dir(local_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().into_string()
+            .map_err(|name| anyhow!("filename {name:?} in {local_path:?} is not UTF-8"))?;
+        names.push(name);
+    }
+    names.sort_by(|a, b| b.cmp(a));
+    for name in names {
+        let mut child = components.to_vec();
+        child.push(name);
+        stack.push(child);
+    }
+    Ok(())
+}
+
+/// Find files and symlinks that no dirent points to or pointed to in the last
+/// `grace_days`, print how many bytes they take up in each storage, and unless
+/// `dry_run`, delete them and their storages.
+async fn gc(grace_days: u32, dry_run: bool) -> Result<()> {
+    let pool = db::pgpool().await;
+    let cutoff = Utc::now() - chrono::Duration::days(grace_days.into());
+    let mut transaction = pool.begin().await?;
+    let file_ids = File::find_unreferenced_ids(&mut transaction, cutoff).await?;
+    let symlink_ids = Symlink::find_unreferenced_ids(&mut transaction, cutoff).await?;
+    let files = File::find_by_ids(&mut transaction, &file_ids).await?;
+    transaction.commit().await?; // close read-only transaction
+
+    let my_hostname = get_hostname();
+    // (file count, bytes) for each storage, and for each reason a file was skipped
+    let mut breakdown: BTreeMap<String, (i64, i64)> = BTreeMap::new();
+    let mut collectable: Vec<i64> = vec![];
+    let mut total_bytes = 0;
+    for chunk in files.chunks(1000) {
+        let ids: Vec<i64> = chunk.iter().map(|file| file.id).collect();
+        let mut views_by_file: HashMap<i64, Vec<StorageView>> = HashMap::new();
+        for view in get_storage_views(&ids).await? {
+            views_by_file.entry(view.file_id()).or_default().push(view);
+        }
+        for file in chunk {
+            let views = views_by_file.remove(&file.id).unwrap_or_default();
+            let mut keys = vec![];
+            let mut skip_reason = None;
+            for view in &views {
+                match view {
+                    StorageView::Inline(..) => {
+                        keys.push("inline".to_string());
+                    }
+                    StorageView::Fofs(fofs::StorageView { pile_id, pile_hostname, .. }) => {
+                        if *pile_hostname != my_hostname {
+                            skip_reason = Some(format!("fofs pile on {pile_hostname}"));
+                        }
+                        keys.push(format!("fofs pile {pile_id}"));
+                    }
+                    StorageView::Gdrive(gdrive::Storage { google_domain, .. }) => {
+                        keys.push(format!("gdrive domain {google_domain}"));
+                    }
+                    StorageView::NamedFiles(..) => {
+                        skip_reason = Some("namedfiles".to_string());
+                    }
+                    StorageView::InternetArchive(..) => {
+                        skip_reason = Some("internetarchive".to_string());
+                    }
+                }
+            }
+            if let Some(reason) = skip_reason {
+                keys = vec![format!("skipped: {reason}")];
+            } else {
+                if views.is_empty() {
+                    keys.push("no storage".to_string());
+                }
+                total_bytes += file.size;
+                collectable.push(file.id);
+            }
+            for key in keys {
+                let entry = breakdown.entry(key).or_default();
+                entry.0 += 1;
+                entry.1 += file.size;
+            }
+        }
+    }
+
+    for (key, (count, bytes)) in &breakdown {
+        println!("{key}: {} files, {} bytes", commaify_i64(*count), commaify_i64(*bytes));
+    }
+    println!("total: {} files, {} bytes, {} symlinks",
+        commaify_i64(collectable.len() as i64), commaify_i64(total_bytes), commaify_i64(symlink_ids.len() as i64));
+    if dry_run {
+        return Ok(());
+    }
+
+    for file_id in collectable {
+        // We seem to not be able to delete stuff from our shared drives,
+        // and we'll be fully deleted by Google soon anyway...
+        let delete_google_drive_files = false;
+        storage::delete::delete_unreferenced_file(file_id, delete_google_drive_files).await?;
+    }
+    for chunk in symlink_ids.chunks(1000) {
+        let mut transaction = pool.begin().await?;
+        let inode_ids: Vec<InodeId> = chunk.iter().copied().map(InodeId::Symlink).collect();
+        let counts = Dirent::count_by_children(&mut transaction, &inode_ids).await?;
+        let ids: Vec<i64> = chunk.iter().copied().filter(|id| !counts.contains_key(&InodeId::Symlink(*id))).collect();
+        Symlink::delete(&mut transaction, &ids).await?;
+        transaction.commit().await?;
+    }
+
+    Ok(())
+}
+
+/// Compare the files in every gdrive_parents folder against gdrive_files, printing
+/// orphans created before the grace period and database ids not seen in any folder.
+async fn gdrive_audit(owner_id: i32, grace_days: u32, delete_orphans: bool, json: bool) -> Result<()> {
+    let Some(access_token) = storage::read::get_one_access_token(owner_id).await? else {
+        bail!("no access token for owner_id={owner_id}");
+    };
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    let parents = gdrive::GdriveParent::find_all(&mut transaction).await?;
+    let known_ids: HashSet<String> = GdriveFile::find_all_ids(&mut transaction).await?.into_iter().collect();
+    transaction.commit().await?; // close read-only transaction
+
+    let cutoff = Utc::now() - chrono::Duration::days(grace_days.into());
+    let mut seen_ids = HashSet::new();
+    for parent in &parents {
+        info!(name = parent.name, parent = parent.parent, "listing gdrive parent");
+        for child in list_folder_children(&parent.parent, &access_token).await? {
+            seen_ids.insert(child.id.clone());
+            if known_ids.contains(&child.id) || child.created_time >= cutoff {
+                continue;
+            }
+            if json {
+                let j = json!({
+                    "problem": "orphan",
+                    "id": child.id,
+                    "name": child.name,
+                    "parent": parent.name,
+                    "created_time": child.created_time,
+                    "size": child.size,
+                });
+                println!("{j}");
+            } else {
+                let size = child.size.as_deref().unwrap_or("?");
+                println!("orphan   {} {:?} in {} (created {}, {size} bytes)", child.id, child.name, parent.name, child.created_time);
+            }
+            if delete_orphans {
+                delete_gdrive_file_with_access_token(&child.id, &access_token).await?;
+            }
+        }
+    }
+
+    let mut missing: Vec<&String> = known_ids.difference(&seen_ids).collect();
+    missing.sort();
+    for id in missing {
+        if json {
+            println!("{}", json!({"problem": "missing", "id": id}));
+        } else {
+            println!("missing  {id}");
+        }
+    }
+    Ok(())
+}
+
+fn print_report_row<T: Serialize>(section: &str, row: &T) -> Result<()> {
+    let mut j = serde_json::to_value(row)?;
+    j["section"] = json!(section);
+    println!("{j}");
+    Ok(())
+}
+
+async fn storage_report(cells: bool, parents_as: Option<i32>, json: bool) -> Result<()> {
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    let pile_usages = PileUsage::find_all(&mut transaction).await?;
+    let cell_usages = if cells { CellUsage::find_all(&mut transaction).await? } else { vec![] };
+    let domain_usages = DomainUsage::find_all(&mut transaction).await?;
+    let owner_usages = OwnerUsage::find_all(&mut transaction).await?;
+    let parents = if parents_as.is_some() { gdrive::GdriveParent::find_all(&mut transaction).await? } else { vec![] };
+    let known_ids: HashSet<String> = if parents_as.is_some() {
+        GdriveFile::find_all_ids(&mut transaction).await?.into_iter().collect()
+    } else {
+        HashSet::new()
+    };
+    transaction.commit().await?; // close read-only transaction
+
+    let c = commaify_i64;
+    if json {
+        for usage in &pile_usages {
+            print_report_row("pile", usage)?;
+        }
+        for usage in &cell_usages {
+            print_report_row("cell", usage)?;
+        }
+        for usage in &domain_usages {
+            print_report_row("domain", usage)?;
+        }
+        for usage in &owner_usages {
+            print_report_row("owner", usage)?;
+        }
+    } else {
+        println!("{:>6} {:<32} {:>11} {:>13} {:>19} {:>11} {:>19}",
+            "pile", "location", "full/cells", "files", "bytes", "open files", "open bytes");
+        for usage in &pile_usages {
+            let location = format!("{}:{}", usage.hostname, usage.path);
+            let location = if usage.offline { format!("{location} (offline)") } else { location };
+            let cells = format!("{}/{}", c(usage.full_cells), c(usage.cells));
+            println!("{:>6} {:<32} {:>11} {:>13} {:>19} {:>11} {:>19}",
+                usage.pile_id, location, cells, c(usage.files), c(usage.bytes),
+                c(usage.files_in_open_cells), c(usage.bytes_in_open_cells));
+        }
+        if cells {
+            println!();
+            println!("{:>6} {:>9} {:>5} {:>13} {:>19}", "pile", "cell", "full", "files", "bytes");
+            for usage in &cell_usages {
+                println!("{:>6} {:>9} {:>5} {:>13} {:>19}",
+                    usage.pile_id, usage.cell_id, usage.full, c(usage.files), c(usage.bytes));
+            }
+        }
+        println!();
+        println!("{:>6} {:<24} {:>13} {:>19} {:>19} {:>15} {:>15}",
+            "domain", "name", "files", "content bytes", "stored bytes", "gcm tag bytes", "padding bytes");
+        for usage in &domain_usages {
+            println!("{:>6} {:<24} {:>13} {:>19} {:>19} {:>15} {:>15}",
+                usage.domain_id, usage.domain, c(usage.files), c(usage.content_bytes),
+                c(usage.stored_bytes), c(usage.gcm_tag_bytes), c(usage.padding_bytes));
+        }
+        println!();
+        println!("{:>6} {:<40} {:>6} {:>13} {:>19}", "owner", "name", "domain", "gdrive files", "bytes");
+        for usage in &owner_usages {
+            let owner_id = usage.owner_id.map_or_else(|| "-".into(), |id| id.to_string());
+            let owner = usage.owner.as_deref().unwrap_or("(no recorded owner)");
+            let domain_id = usage.domain_id.map_or_else(|| "-".into(), |id| id.to_string());
+            println!("{:>6} {:<40} {:>6} {:>13} {:>19}", owner_id, owner, domain_id, c(usage.gdrive_files), c(usage.bytes));
+        }
+    }
+
+    let Some(owner_id) = parents_as else {
+        return Ok(());
+    };
+    let Some(access_token) = storage::read::get_one_access_token(owner_id).await? else {
+        bail!("no access token for owner_id={owner_id}");
+    };
+    if !json {
+        println!();
+        println!("{:<24} {:>5} {:>13} {:>19} {:>13}", "parent", "full", "files", "bytes", "unknown files");
+    }
+    for parent in &parents {
+        info!(name = parent.name, parent = parent.parent, "listing gdrive parent");
+        let children = list_folder_children(&parent.parent, &access_token).await?;
+        let files = children.len() as i64;
+        let bytes: i64 = children.iter()
+            .filter_map(|child| child.size.as_deref())
+            .map(str::parse::<i64>)
+            .sum::<Result<i64, _>>()?;
+        // Files in the folder that are not in gdrive_files, see `es storage gdrive audit`
+        let unknown_files = children.iter().filter(|child| !known_ids.contains(&child.id)).count() as i64;
+        if json {
+            let j = json!({
+                "section": "parent",
+                "name": parent.name,
+                "parent": parent.parent,
+                "full": parent.full,
+                "files": files,
+                "bytes": bytes,
+                "unknown_files": unknown_files,
+            });
+            println!("{j}");
+        } else {
+            println!("{:<24} {:>5} {:>13} {:>19} {:>13}", parent.name, parent.full, c(files), c(bytes), c(unknown_files));
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let env_filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new("warn"))
+        .unwrap();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(env_filter)
+        .init();
+
+    // Do this first for --help to work without a database connection
+    let command = ExastashCommand::parse();
+
+    if let ExastashCommand::License = command {
+        print!("{}", include_str!("../LICENSE"));
+        return Ok(());
+    }
+
+    let mut pool = db::pgpool().await;
+    match command {
+        ExastashCommand::License => {
+            // Handled above
+            unreachable!();
+        },
+        ExastashCommand::Dir(command) => {
+            match command {
+                DirCommand::Create { parent_dir_id, basename } => {
+                    let mut transaction = pool.begin().await?;
+                    let mtime = Utc::now();
+                    let birth = db::inode::Birth::here_and_now();
+                    let dir = NewDir { mtime, birth }.create(&mut transaction).await?;
+                    Dirent::new(parent_dir_id, basename, InodeId::Dir(dir.id)).create(&mut transaction).await?;
+                    transaction.commit().await?;
+                    println!("{}", dir.id);
+                }
+                DirCommand::Delete { dir_id } => {
                     let mut transaction = pool.begin().await?;
                     Dirent::remove_by_child_dir(&mut transaction, dir_id).await?;
                     Dir::delete(&mut transaction, &[dir_id]).await?;
@@ -790,10 +2547,19 @@ async fn main() -> Result<()> {
                     let inodes = Inode::find_by_inode_ids(&mut transaction, &inode_ids).await?;
                     for inode_id in inode_ids {
                         let inode = inodes.get(&inode_id).ok_or_else(|| anyhow!("{:?} not found in database", inode_id))?;
-                        println!("{}", json_info(inode).await?);
+                        println!("{}", json_info(inode, None).await?);
                     }
                     transaction.commit().await?; // close read-only transaction
                 }
+                DirCommand::History { dir_id, recursive, json } => {
+                    let mut transaction = pool.begin().await?;
+                    let versions = Dirent::find_versions_by_parents(&mut transaction, &[dir_id]).await?
+                        .into_iter()
+                        .map(|version| (version.dirent.basename.clone(), version))
+                        .collect();
+                    print_dirent_history(&mut transaction, versions, recursive, json).await?;
+                    transaction.commit().await?; // close read-only transaction
+                }
                 DirCommand::Count => {
                     let mut transaction = pool.begin().await?;
                     let count = Dir::count(&mut transaction).await?;
@@ -899,7 +2665,7 @@ async fn main() -> Result<()> {
                     let inodes = Inode::find_by_inode_ids(&mut transaction, &inode_ids).await?;
                     for inode_id in inode_ids {
                         let inode = inodes.get(&inode_id).ok_or_else(|| anyhow!("{:?} not found in database", inode_id))?;
-                        println!("{}", json_info(inode).await?);
+                        println!("{}", json_info(inode, None).await?);
                     }
                     transaction.commit().await?; // close read-only transaction
                 }
@@ -941,7 +2707,7 @@ async fn main() -> Result<()> {
                     let inodes = Inode::find_by_inode_ids(&mut transaction, &inode_ids).await?;
                     for inode_id in inode_ids {
                         let inode = inodes.get(&inode_id).ok_or_else(|| anyhow!("{:?} not found in database", inode_id))?;
-                        println!("{}", json_info(inode).await?);
+                        println!("{}", json_info(inode, None).await?);
                     }
                     transaction.commit().await?; // close read-only transaction
                 }
@@ -968,7 +2734,7 @@ async fn main() -> Result<()> {
                 }
                 DirentCommand::List { ids } => {
                     let mut transaction = pool.begin().await?;
-                    let dirents = Dirent::find_by_parents(&mut transaction, &ids).await?;
+                    let dirents = Dirent::find_by_parents(&mut transaction, &ids, None).await?;
                     transaction.commit().await?; // close read-only transaction
                     for dirent in dirents {
                         let j = json!({
@@ -983,7 +2749,7 @@ async fn main() -> Result<()> {
                 }
                 DirentCommand::Walk { id } => {
                     let mut transaction = pool.begin().await?;
-                    walk_dir(&mut transaction, id, &[], id).await?;
+                    walk_dir(&mut transaction, id).await?;
                     transaction.commit().await?; // close read-only transaction
                 }
                 DirentCommand::Resolve { kind, root, paths } => {
@@ -1052,6 +2818,9 @@ async fn main() -> Result<()> {
         }
         ExastashCommand::Storage(command) => {
             match command {
+                StorageCommand::Report { cells, parents_as, json } => {
+                    storage_report(cells, parents_as, json).await?;
+                }
                 StorageCommand::NamedFiles(command) => {
                     match command {
                         NamedFilesStorageCommand::Create { file_id, location, pathname } => {
@@ -1073,6 +2842,28 @@ async fn main() -> Result<()> {
                             let hostname = get_hostname();
                             backfill_b3sums(&hostname).await?;
                         }
+                        FofsStorageCommand::Audit { pile_id, checksum, delete_orphans, drop_missing, drop_corrupt, json } => {
+                            let mut transaction = pool.begin().await?;
+                            let pile = fofs::Pile::find_by_ids(&mut transaction, &[pile_id]).await?
+                                .pop().ok_or_else(|| anyhow!("fofs pile with id={pile_id} not found"))?;
+                            transaction.commit().await?; // close read-only transaction
+                            for problem in fofs::audit_pile(&pile, checksum).await? {
+                                if json {
+                                    println!("{}", serde_json::to_string(&problem)?);
+                                } else {
+                                    println!("{problem}");
+                                }
+                                let fix = match problem {
+                                    fofs::AuditProblem::Orphan { .. } => delete_orphans,
+                                    fofs::AuditProblem::Missing { .. } => drop_missing,
+                                    fofs::AuditProblem::WrongSize { .. } |
+                                    fofs::AuditProblem::B3sumMismatch { .. } => drop_corrupt,
+                                };
+                                if fix {
+                                    problem.fix().await?;
+                                }
+                            }
+                        }
                     }
                 }
                 StorageCommand::Gdrive(command) => {
@@ -1090,6 +2881,9 @@ async fn main() -> Result<()> {
                                 }
                             }
                         }
+                        GdriveStorageCommand::Audit { owner_id, grace_days, delete_orphans, json } => {
+                            gdrive_audit(owner_id, grace_days, delete_orphans, json).await?;
+                        }
                         GdriveStorageCommand::Internal(command) => {
                             match command {
                                 InternalCommand::File(command) => {
@@ -1168,18 +2962,22 @@ async fn main() -> Result<()> {
         }
         ExastashCommand::Path(command) => {
             match command {
-                PathCommand::Info { paths: path_args } => {
+                PathCommand::Info { paths: path_args, as_of } => {
                     let config = config::get_config()?;
                     let mut inode_ids = vec![];
                     let mut transaction = pool.begin().await?;
                     for path_arg in path_args {
-                        let inode_id = path::resolve_local_path_arg(&config, &mut transaction, Some(&path_arg)).await?;
+                        let inode_id = path::resolve_local_path_arg(&config, &mut transaction, Some(&path_arg), as_of).await?;
                         inode_ids.push(inode_id);
                     }
                     let inodes = Inode::find_by_inode_ids(&mut transaction, &inode_ids).await?;
+                    let link_counts = Dirent::count_by_children(&mut transaction, &inode_ids).await?;
                     for inode_id in inode_ids {
-                        let inode = inodes.get(&inode_id).unwrap();
-                        println!("{}", json_info(inode).await?);
+                        // Dirs are deleted along with their dirents, so an old dir may be gone
+                        let inode = inodes.get(&inode_id).ok_or_else(|| anyhow!("{:?} not found in database", inode_id))?;
+                        // Link counts are always current
+                        let nlink = if as_of.is_none() { link_counts.get(&inode_id).copied().or(Some(0)) } else { None };
+                        println!("{}", json_info(inode, nlink).await?);
                     }
                     transaction.commit().await?; // close read-only transaction
                 }
@@ -1190,7 +2988,7 @@ async fn main() -> Result<()> {
                     // Resolve all paths to inodes before doing the unpredictably-long read operations,
                     // during which files could be renamed.
                     for path_arg in path_args {
-                        let file_id = path::resolve_local_path_arg(&config, &mut transaction, Some(&path_arg)).await?.file_id()?;
+                        let file_id = path::resolve_local_path_arg(&config, &mut transaction, Some(&path_arg), None).await?.file_id()?;
                         file_ids.push(file_id);
                     }
                     transaction.commit().await?; // close read-only transaction
@@ -1200,166 +2998,62 @@ async fn main() -> Result<()> {
                         storage::read::write_stream_to_sink(stream, &mut stdout).await?;
                     }
                 }
-                PathCommand::Get { paths: path_args, skip_if_exists } => {
-                    use std::os::unix::fs::PermissionsExt;
-
+                PathCommand::Get { paths: path_args, skip_if_exists, jobs, as_of } => {
                     let config = config::get_config()?;
-                    let mut retrievals = vec![];
+                    let mut inode_ids = vec![];
                     let mut transaction = pool.begin().await?;
                     // Resolve all paths to inodes before doing the unpredictably-long read operations,
                     // during which files could be renamed.
                     for path_arg in &path_args {
-                        let inode_id = path::resolve_local_path_arg(&config, &mut transaction, Some(path_arg)).await?;
-                        retrievals.push((inode_id, path_arg));
+                        let inode_id = path::resolve_local_path_arg(&config, &mut transaction, Some(path_arg), as_of).await?;
+                        inode_ids.push(inode_id);
                     }
-                    for (inode_id, path_arg) in retrievals {
-                        match inode_id {
-                            InodeId::Dir(_) => {
-                                unimplemented!();
-                            }
-                            InodeId::File(file_id) => {
-                                if skip_if_exists {
-                                    match fs::metadata(path_arg).await {
-                                        Err(err) => {
-                                            if err.kind() != std::io::ErrorKind::NotFound {
-                                                bail!(err);
-                                            }
-                                        }
-                                        Ok(attr) => {
-                                            let metadata: storage::RelevantFileMetadata = attr.try_into()?;
-                                            let files = File::find_by_ids(&mut transaction, &[file_id]).await?;
-                                            let file = files.first().ok_or_else(|| {
-                                                anyhow!("database unexpectedly missing file id={}", file_id)
-                                            })?;
-                                            if file.mtime == metadata.mtime && file.size == metadata.size {
-                                                info!(?path_arg, "file already exists locally with matching size and mtime");
-
-                                                let permissions = std::fs::Permissions::from_mode(
-                                                    if file.executable { 0o770 } else { 0o660 }
-                                                );
-                                                fs::set_permissions(&path_arg, permissions).await?;
-
-                                                continue;
-                                            }
-                                        }
-                                    }
-                                }
-
-                                // Remove any existing file to reset permissions
-                                if let Err(err) = tokio::fs::remove_file(&path_arg).await {
-                                    if err.kind() != std::io::ErrorKind::NotFound {
-                                        bail!(err);
-                                    }
-                                }
-
-                                // TODO: do this properly and apply dir mtimes from the database
-                                let path_buf = PathBuf::from(path_arg);
-                                let dir_path = path_buf.parent().unwrap();
-                                tokio::fs::create_dir_all(&dir_path).await?;
-
-                                let mut local_file = tokio::fs::File::create(&path_arg).await?;
-                                let (stream, file) = storage::read::read(file_id).await?;
-                                storage::read::write_stream_to_sink(stream, &mut local_file).await?;
-
-                                if file.executable {
-                                    let permissions = std::fs::Permissions::from_mode(0o770);
-                                    fs::set_permissions(&path_arg, permissions).await?;
-                                }
-
-                                let mtime = filetime::FileTime::from_system_time(file.mtime.into());
-                                filetime::set_file_mtime(path_arg, mtime)?;
-                            }
-                            InodeId::Symlink(_) => {
-                                unimplemented!();
-                            }
+                    let inodes = Inode::find_by_inode_ids(&mut transaction, &inode_ids).await?;
+                    transaction.commit().await?; // close read-only transaction
+                    for (inode_id, path_arg) in inode_ids.iter().zip(&path_args) {
+                        let local_path = Path::new(path_arg);
+                        if let InodeId::Dir(dir_id) = inode_id {
+                            get_dir(*dir_id, local_path, skip_if_exists, jobs, as_of).await?;
+                            continue;
+                        }
+                        match inodes.get(inode_id).ok_or_else(|| anyhow!("{:?} not found in database", inode_id))? {
+                            Inode::File(file) => get_file(file, local_path, skip_if_exists).await?,
+                            Inode::Symlink(symlink) => get_symlink(symlink, local_path).await?,
+                            Inode::Dir(_) => unreachable!(),
                         }
                     }
+                }
+                PathCommand::ImportArchive { archive, dest, existing_file_behavior } => {
+                    let config = config::get_config()?;
+                    let policy = policy::get_policy()?;
+                    import_archive(&config, &policy, &archive, &dest, &existing_file_behavior).await?;
+                }
+                PathCommand::Tar { path: path_arg, pax_metadata, as_of } => {
+                    if std::io::stdout().is_terminal() {
+                        bail!("refusing to write a tar stream to a terminal");
+                    }
+                    let config = config::get_config()?;
+                    let mut transaction = pool.begin().await?;
+                    let dir_id = path::resolve_local_path_arg(&config, &mut transaction, Some(&path_arg), as_of).await?.dir_id()?;
                     transaction.commit().await?; // close read-only transaction
+                    let components = path::resolve_local_path_to_path_components(Some(&path_arg))?;
+                    let root_name = components.last().ok_or_else(|| anyhow!("cannot tar the root of the filesystem"))?;
+                    let stdout = tokio::io::BufWriter::new(tokio::io::stdout());
+                    path::tar::write_dir(dir_id, root_name, as_of, pax_metadata, stdout).await?;
                 }
-                PathCommand::Add { paths: path_args, existing_file_behavior: already_exists_behavior, remove_local_files } => {
-                    // We need one transaction per new directory below, due to `dirents_check_insert_or_delete`.
-
+                PathCommand::Add { paths: path_args, existing_file_behavior, remove_local_files, dedupe } => {
                     let config = config::get_config()?;
                     let policy = policy::get_policy()?;
                     for path_arg in &path_args {
-                        let mut transaction = pool.begin().await?;
-                        let path_components = path::resolve_local_path_to_path_components(Some(path_arg))?;
-                        let (path_roots_value, idx) = path::resolve_root_of_local_path(&config, &path_components)?;
-                        let base_dir = path_roots_value.dir_id;
-                        let remaining_components = &path_components[idx..];
-                        path::validate_path_components(remaining_components, &path_roots_value.new_dirent_requirements)?;
-                        let components_to_base_dir = traversal::get_path_segments_from_root_to_dir(&mut transaction, base_dir).await?;
-                        let stash_path = [&components_to_base_dir, remaining_components].concat();
-
-                        let attr = fs::metadata(path_arg).await?;
-                        let metadata: storage::RelevantFileMetadata = (&attr).try_into()?;
-                        if attr.is_file() {
-                            let stash_path: Vec<&str> = stash_path.iter().map(String::as_str).collect();
-
-                            let basename = remaining_components.last().unwrap();
-                            let dir_components = &remaining_components[..remaining_components.len() - 1];
-                            // TODO: do this properly and use the mtimes of the local dirs
-                            let dir_id = traversal::make_dirs(&mut transaction, base_dir, dir_components).await?.dir_id()?;
-                            if let Some(existing) = Dirent::find_by_parent_and_basename(&mut transaction, dir_id, basename).await? {
-                                match already_exists_behavior {
-                                    ExistingFileBehavior::stop => {
-                                        bail!("{:?} already exists as {:?}", stash_path, existing);
-                                    }
-                                    ExistingFileBehavior::skip => {
-                                        eprintln!("{stash_path:?} already exists as {existing:?}");
-                                        continue;
-                                    }
-                                    ExistingFileBehavior::replace => {
-                                        eprintln!("{stash_path:?} already exists as {existing:?} but replacing as requested");
-                                        existing.remove(&mut transaction).await?;
-                                    }
-                                }
-                            }
-                            transaction.commit().await?;
-
-                            let desired = policy.new_file_storages(&stash_path, &metadata)?;
-
-                            let initial_delay = std::time::Duration::new(60, 0);
-                            let maximum_delay = std::time::Duration::new(1800, 0);
-                            let mut decayer = Decayer::new(initial_delay, Ratio::new(3, 2), maximum_delay);
-                            let mut tries = 30;
-                            let file_id = loop {
-                                match storage::write::create_stash_file_from_local_file(path_arg.clone(), &metadata, &desired).await {
-                                    Ok(id) => break id,
-                                    Err(err) => {
-                                        tries -= 1;
-                                        if tries == 0 {
-                                            bail!(err);
-                                        }
-                                        let delay = decayer.decay();
-                                        eprintln!("storage::write::create_stash_file_from_local_file({path_arg:?}, ...) failed, {tries} tries left \
-                                                   (next in {} sec): {err:?}", delay.as_secs());
-                                        tokio::time::sleep(delay).await;
-                                    }
-                                }
-                            };
-
-                            let child = InodeId::File(file_id);
-                            transaction = pool.begin().await?;
-                            Dirent::new(dir_id, basename, child).create(&mut transaction).await?;
-                        } else {
-                            bail!("can only add a file right now")
-                        }
-
-                        transaction.commit().await?;
-
-                        if remove_local_files {
-                            info!(?path_arg, "removing local file after committing to database");
-                            fs::remove_file(path_arg).await?;
-                        }
+                        add_path(&config, &policy, path_arg, &existing_file_behavior, remove_local_files, dedupe).await?;
                     }
                 }
-                PathCommand::Ls { path: path_arg, just_names, sort, reverse } => {
+                PathCommand::Ls { path: path_arg, just_names, link_counts, sort, reverse, dir_sizes, as_of } => {
                     let config = config::get_config()?;
                     let mut transaction = pool.begin().await?;
-                    let inode_id = path::resolve_local_path_arg(&config, &mut transaction, path_arg.as_deref()).await?;
+                    let inode_id = path::resolve_local_path_arg(&config, &mut transaction, path_arg.as_deref(), as_of).await?;
                     let dir_id = inode_id.dir_id()?;
-                    let mut dirents = Dirent::find_by_parents(&mut transaction, &[dir_id]).await?;
+                    let mut dirents = Dirent::find_by_parents(&mut transaction, &[dir_id], as_of).await?;
                     // In this case, there is no need to retrieve the inodes
                     let inodes = if just_names && sort == SortOrder::name {
                         HashMap::new()
@@ -1367,11 +3061,27 @@ async fn main() -> Result<()> {
                         let children: Vec<InodeId> = dirents.iter().map(|dirent| dirent.child).collect();
                         Inode::find_by_inode_ids(&mut transaction, &children).await?
                     };
+                    let nlinks = if link_counts && !just_names {
+                        let children: Vec<InodeId> = dirents.iter().map(|dirent| dirent.child).collect();
+                        Dirent::count_by_children(&mut transaction, &children).await?
+                    } else {
+                        HashMap::new()
+                    };
+                    let rollups = if dir_sizes && !just_names {
+                        let child_dirs: Vec<i64> = dirents.iter().filter_map(|dirent| dirent.child.dir_id().ok()).collect();
+                        DirRollup::find_recursive_by_dir_ids(&mut transaction, &child_dirs).await?
+                    } else {
+                        HashMap::new()
+                    };
                     transaction.commit().await?; // close read-only transaction
+                    let size_of = |inode_id: InodeId| match inode_id {
+                        InodeId::Dir(id) => rollups.get(&id).map(|rollup| rollup.bytes),
+                        _ => inodes.get(&inode_id).and_then(|inode| inode.size()),
+                    };
                     match sort {
                         SortOrder::name  => { dirents.sort_by(|d1, d2| d1.basename.cmp(&d2.basename)) },
-                        SortOrder::mtime => { dirents.sort_by_key(|dirent| inodes.get(&dirent.child).unwrap().mtime()) },
-                        SortOrder::size  => { dirents.sort_by_key(|dirent| inodes.get(&dirent.child).unwrap().size()) },
+                        SortOrder::mtime => { dirents.sort_by_key(|dirent| inodes.get(&dirent.child).map(|inode| inode.mtime())) },
+                        SortOrder::size  => { dirents.sort_by_key(|dirent| size_of(dirent.child)) },
                     }
                     if reverse {
                         dirents.reverse();
@@ -1381,11 +3091,19 @@ async fn main() -> Result<()> {
                             println!("{}", dirent.basename);
                             continue;
                         }
+                        if link_counts {
+                            let nlink = nlinks.get(&dirent.child).copied().unwrap_or(0);
+                            print!("{nlink:>4} ");
+                        }
+                        if !inodes.contains_key(&dirent.child) {
+                            // With --as-of, the inode may have been deleted since
+                            let suffix = if let InodeId::Dir(_) = dirent.child { "/" } else { "" };
+                            println!("{:>18} {:16} {}{suffix}", "?", "?", dirent.basename);
+                            continue;
+                        }
                         match dirent.child {
                             inode @ InodeId::Dir(_) => {
-                                let size = 0;
-                                // We're in the same transaction, so database should really have
-                                // returned all the inodes we asked for, therefore .unwrap()
+                                let size = commaify_i64(size_of(inode).unwrap_or(0));
                                 let dir = inodes.get(&inode).unwrap().dir().unwrap();
                                 let mtime = dir.mtime.format("%Y-%m-%d %H:%M");
                                 println!("{size:>18} {mtime} {}/", Paint::blue(&dirent.basename));
@@ -1409,7 +3127,10 @@ async fn main() -> Result<()> {
                         }
                     }
                 }
-                PathCommand::Find { paths: path_args, r#type, null_sep } => {
+                PathCommand::Find {
+                    paths: path_args, r#type, name, iname, size, mtime, newer, executable, b3sum, no_b3sum,
+                    has_storage, missing_storage, null_sep, json, as_of,
+                } => {
                     // find in cwd if no path args
                     let mut path_args = path_args.clone();
                     if path_args.is_empty() {
@@ -1422,20 +3143,190 @@ async fn main() -> Result<()> {
                     // Resolve all root paths to inodes before doing the walk operations,
                     // during which files could be renamed.
                     for path_arg in path_args {
-                        let dir_id = path::resolve_local_path_arg(&config, &mut transaction, Some(&path_arg)).await?.dir_id()?;
+                        let dir_id = path::resolve_local_path_arg(&config, &mut transaction, Some(&path_arg), as_of).await?.dir_id()?;
                         roots.push((dir_id, path_arg));
                     }
 
+                    let filter = path::find::Filter {
+                        name: name.or(iname), size, mtime, newer, executable, b3sum, no_b3sum, has_storage, missing_storage,
+                    };
                     let terminator = if null_sep { '\0' } else { '\n' };
+                    let options = FindOptions { r#type, filter, json, terminator, now: Utc::now() };
                     for (dir_id, path_arg) in roots {
-                        if r#type.is_none() || r#type == Some(FindKind::d) {
-                            // Print the top-level dir like findutils find
-                            print!("{path_arg}{terminator}");
+                        // Print the top-level dir like findutils find
+                        let basename = Path::new(&path_arg).file_name().and_then(|name| name.to_str()).unwrap_or(&path_arg);
+                        let inode_id = InodeId::Dir(dir_id);
+                        let inode = if options.needs_inodes() {
+                            Inode::find_by_inode_ids(&mut transaction, &[inode_id]).await?.remove(&inode_id)
+                        } else {
+                            None
+                        };
+                        find_print_if_matches(&path_arg, basename, inode_id, inode.as_ref(), vec![], &options)?;
+                        x_find(&mut transaction, &path_arg, dir_id, &options, as_of).await?;
+                    }
+                    transaction.commit().await?; // close read-only transaction
+                }
+                PathCommand::Du { paths: path_args, fast, json } => {
+                    // du of cwd if no path args
+                    let mut path_args = path_args.clone();
+                    if path_args.is_empty() {
+                        path_args.push(String::from("."));
+                    }
+
+                    let config = config::get_config()?;
+                    let mut transaction = pool.begin().await?;
+                    for path_arg in path_args {
+                        let dir_id = path::resolve_local_path_arg(&config, &mut transaction, Some(&path_arg), None).await?.dir_id()?;
+                        let usage = if fast {
+                            let rollup = DirRollup::find_recursive_by_dir_ids(&mut transaction, &[dir_id]).await?
+                                .remove(&dir_id)
+                                .ok_or_else(|| anyhow!("no rollup for dir {dir_id}"))?;
+                            traversal::DiskUsage { files: rollup.files, bytes: rollup.bytes, storages: vec![] }
+                        } else {
+                            traversal::disk_usage(&mut transaction, dir_id).await?
+                        };
+                        if json {
+                            let j = json!({
+                                "path": path_arg,
+                                "files": usage.files,
+                                "bytes": usage.bytes,
+                                "storages": usage.storages,
+                            });
+                            println!("{j}");
+                        } else {
+                            println!("{path_arg}: {} files, {} bytes", commaify_i64(usage.files), commaify_i64(usage.bytes));
+                            for storage in &usage.storages {
+                                let label = match storage.id {
+                                    Some(id) => format!("{}:{id}", storage.kind),
+                                    None => storage.kind.clone(),
+                                };
+                                println!("  {label}: {} files, {} bytes", commaify_i64(storage.files), commaify_i64(storage.bytes));
+                            }
                         }
-                        x_find(&mut transaction, &[&path_arg], dir_id, r#type, terminator).await?;
                     }
                     transaction.commit().await?; // close read-only transaction
                 }
+                PathCommand::Status { paths: path_args, checksum, json } => {
+                    // status of cwd if no path args
+                    let mut path_args = path_args.clone();
+                    if path_args.is_empty() {
+                        path_args.push(String::from("."));
+                    }
+
+                    let config = config::get_config()?;
+                    let mut transaction = pool.begin().await?;
+                    for path_arg in path_args {
+                        let stash_inode = resolve_local_path_arg_if_exists(&config, &mut transaction, &path_arg).await?;
+                        path::status::status(&mut transaction, Path::new(&path_arg), stash_inode, checksum, |entry| {
+                            if json {
+                                println!("{}", serde_json::to_string(&entry)?);
+                            } else {
+                                println!("{entry}");
+                            }
+                            Ok(())
+                        }).await?;
+                    }
+                    transaction.commit().await?; // close read-only transaction
+                }
+                PathCommand::Manifest { path: path_arg, as_of } => {
+                    let config = config::get_config()?;
+                    let mut transaction = pool.begin().await?;
+                    let dir_id = path::resolve_local_path_arg(&config, &mut transaction, Some(&path_arg), as_of).await?.dir_id()?;
+                    let files = path::manifest::files_below(&mut transaction, dir_id, as_of).await?;
+                    transaction.commit().await?; // close read-only transaction
+                    let mut unknown = 0;
+                    for (path, file) in &files {
+                        match path::manifest::file_b3sum(file) {
+                            Some(b3sum) => println!("{}", path::manifest::manifest_line(&b3sum, path)),
+                            None => {
+                                eprintln!("no b3sum for file id={}: {}", file.id, path);
+                                unknown += 1;
+                            }
+                        }
+                    }
+                    if unknown > 0 {
+                        bail!("{} file(s) with no b3sum were left out of the manifest", unknown);
+                    }
+                }
+                PathCommand::CheckLocal { local_dir, stash, jobs, json } => {
+                    let config = config::get_config()?;
+                    let mut transaction = pool.begin().await?;
+                    let stash_arg = stash.as_deref().unwrap_or(&local_dir);
+                    let dir_id = path::resolve_local_path_arg(&config, &mut transaction, Some(stash_arg), None).await?.dir_id()?;
+                    let files = path::manifest::files_below(&mut transaction, dir_id, None).await?;
+                    transaction.commit().await?; // close read-only transaction
+                    let mut problems = 0;
+                    path::manifest::check_local(Path::new(&local_dir), files, jobs, |entry| {
+                        if json {
+                            println!("{}", serde_json::to_string(&entry)?);
+                        } else {
+                            println!("{entry}");
+                        }
+                        problems += 1;
+                        Ok(())
+                    }).await?;
+                    if problems > 0 {
+                        bail!("{} file(s) could not be verified", problems);
+                    }
+                }
+                PathCommand::Sync { paths: path_args, to_stash, from_stash: _, delete, dry_run, checksum, journal: journal_path, jobs } => {
+                    // sync cwd if no path args
+                    let mut path_args = path_args.clone();
+                    if path_args.is_empty() {
+                        path_args.push(String::from("."));
+                    }
+
+                    let direction = if to_stash { path::sync::Direction::ToStash } else { path::sync::Direction::FromStash };
+                    let config = config::get_config()?;
+                    let policy = policy::get_policy()?;
+                    let mut journal = match &journal_path {
+                        Some(journal_path) if !dry_run => Some(path::sync::Journal::open(journal_path)?),
+                        _ => None,
+                    };
+                    for path_arg in path_args {
+                        // Find all the differences before syncing anything
+                        let mut entries = vec![];
+                        let mut transaction = pool.begin().await?;
+                        let stash_inode = resolve_local_path_arg_if_exists(&config, &mut transaction, &path_arg).await?;
+                        path::status::status(&mut transaction, Path::new(&path_arg), stash_inode, checksum, |entry| {
+                            entries.push(entry);
+                            Ok(())
+                        }).await?;
+                        let mut planned = vec![];
+                        for entry in entries {
+                            let action = path::sync::plan(&entry, direction, delete);
+                            let journal_entry = match journal {
+                                Some(_) => Some(sync_journal_entry(&config, &mut transaction, &entry.path, direction, action).await?),
+                                None => None,
+                            };
+                            planned.push((entry, action, journal_entry));
+                        }
+                        transaction.commit().await?; // close read-only transaction
+
+                        for (entry, action, journal_entry) in planned {
+                            if let (Some(journal), Some(journal_entry)) = (&journal, &journal_entry) {
+                                if journal.is_done(journal_entry) {
+                                    continue;
+                                }
+                            }
+                            match action {
+                                path::sync::Action::Skip { reason } => println!("{action:<15}  {} ({reason})", entry.path.display()),
+                                _ => println!("{action:<15}  {}", entry.path.display()),
+                            }
+                            if dry_run {
+                                continue;
+                            }
+                            do_sync_action(&config, &policy, &entry.path, direction, action, jobs).await?;
+                            if let (Some(journal), Some(journal_entry)) = (&mut journal, journal_entry) {
+                                journal.record(journal_entry)?;
+                            }
+                        }
+                    }
+                    if let (Some(journal_path), false) = (journal_path, dry_run) {
+                        drop(journal);
+                        fs::remove_file(journal_path).await?;
+                    }
+                }
                 PathCommand::Mkdir { paths: path_args } => {
                     // We need one transaction per new directory below, due to `dirents_check_insert_or_delete`.
 
@@ -1448,7 +3339,7 @@ async fn main() -> Result<()> {
                         let base_dir = path_roots_value.dir_id;
                         let remaining_components = &path_components[idx..];
                         path::validate_path_components(remaining_components, &path_roots_value.new_dirent_requirements)?;
-                        traversal::make_dirs(&mut transaction, base_dir, remaining_components).await?;
+                        traversal::make_dirs(&mut transaction, base_dir, remaining_components, None).await?;
                         transaction.commit().await?;
 
                         // For convenience, also create the corresponding directory on the local filesystem
@@ -1476,8 +3367,108 @@ async fn main() -> Result<()> {
                         transaction.commit().await?;
                     }
                 }
+                PathCommand::History { paths: path_args, recursive, json } => {
+                    let config = config::get_config()?;
+                    let mut transaction = pool.begin().await?;
+                    for path_arg in path_args {
+                        let path_components = path::resolve_local_path_to_path_components(Some(&path_arg))?;
+                        let (path_roots_value, idx) = path::resolve_root_of_local_path(&config, &path_components)?;
+                        let base_dir = path_roots_value.dir_id;
+                        let versions = match path_components[idx..].split_last() {
+                            // A path root has no dirent of its own with a history, so show its children
+                            None => {
+                                Dirent::find_versions_by_parents(&mut transaction, &[base_dir]).await?
+                                    .into_iter()
+                                    .map(|version| (format!("{path_arg}/{}", version.dirent.basename), version))
+                                    .collect()
+                            }
+                            Some((basename, dir_components)) => {
+                                let parent = traversal::resolve_inode(&mut transaction, base_dir, dir_components, None).await?.dir_id()?;
+                                Dirent::find_versions_by_parent_and_basename(&mut transaction, parent, basename).await?
+                                    .into_iter()
+                                    .map(|version| (path_arg.clone(), version))
+                                    .collect()
+                            }
+                        };
+                        print_dirent_history(&mut transaction, versions, recursive, json).await?;
+                    }
+                    transaction.commit().await?; // close read-only transaction
+                }
+                PathCommand::Undelete { paths: path_args, as_of } => {
+                    let config = config::get_config()?;
+                    for path_arg in path_args {
+                        undelete_path(&config, &path_arg, as_of).await?;
+                    }
+                }
+                PathCommand::Mv { src: src_arg, dst: dst_arg } => {
+                    let config = config::get_config()?;
+                    let mut transaction = pool.begin().await?;
+                    let src_components = path::resolve_local_path_to_path_components(Some(&src_arg))?;
+                    let (path_roots_value, idx) = path::resolve_root_of_local_path(&config, &src_components)?;
+                    let dirent = traversal::resolve_dirent(&mut transaction, path_roots_value.dir_id, &src_components[idx..]).await?;
+                    let (new_parent, new_basename, local_dst) =
+                        resolve_new_dirent_destination(&config, &mut transaction, &dst_arg, &dirent.basename).await?;
+                    traversal::move_dirent(&mut transaction, dirent.parent, &dirent.basename, new_parent, &new_basename).await?;
+                    transaction.commit().await?;
+
+                    // For convenience, also do the move on the local filesystem
+                    let local_src = PathBuf::from(format!("/{}", src_components.join("/")));
+                    if fs::symlink_metadata(&local_src).await.is_ok() {
+                        if fs::symlink_metadata(&local_dst).await.is_ok() {
+                            eprintln!("not moving local {local_src:?} because {local_dst:?} already exists");
+                        } else {
+                            if let Some(local_dst_parent) = local_dst.parent() {
+                                fs::create_dir_all(local_dst_parent).await?;
+                            }
+                            fs::rename(&local_src, &local_dst).await?;
+                        }
+                    }
+                }
+                PathCommand::Ln { src: src_arg, dst: dst_arg } => {
+                    let config = config::get_config()?;
+                    let mut transaction = pool.begin().await?;
+                    let src_components = path::resolve_local_path_to_path_components(Some(&src_arg))?;
+                    let (path_roots_value, idx) = path::resolve_root_of_local_path(&config, &src_components)?;
+                    let dirent = traversal::resolve_dirent(&mut transaction, path_roots_value.dir_id, &src_components[idx..]).await?;
+                    if let InodeId::Dir(_) = dirent.child {
+                        bail!("cannot link {src_arg:?} because it is a directory");
+                    }
+                    let (parent, basename, _) =
+                        resolve_new_dirent_destination(&config, &mut transaction, &dst_arg, &dirent.basename).await?;
+                    if let Some(existing) = Dirent::find_by_parent_and_basename(&mut transaction, parent, &basename).await? {
+                        bail!("{dst_arg:?} already exists as {existing:?}");
+                    }
+                    Dirent::new(parent, basename, dirent.child).create(&mut transaction).await?;
+                    transaction.commit().await?;
+                }
             }
         }
+        ExastashCommand::Search { pattern, ignore_case, r#type, size, mtime, newer, under, null_sep, json } => {
+            // Like locate, a pattern without wildcards matches anywhere in the basename
+            let mut glob = path::find::Glob::new(&pattern, ignore_case);
+            if glob.is_literal() {
+                glob = path::find::Glob::new(&format!("*{pattern}*"), ignore_case);
+            }
+            let filter = path::find::Filter { name: Some(glob), size, mtime, newer, ..Default::default() };
+            let terminator = if null_sep { '\0' } else { '\n' };
+            let options = FindOptions { r#type, filter, json, terminator, now: Utc::now() };
+            let mut transaction = pool.begin().await?;
+            search(&mut transaction, under.as_deref(), &options).await?;
+            transaction.commit().await?; // close read-only transaction
+        }
+        ExastashCommand::Dedupe(command) => {
+            match command {
+                DedupeCommand::Report { rule, json } => {
+                    dedupe_report(rule.into(), json).await?;
+                }
+                DedupeCommand::Apply { rule, dry_run } => {
+                    dedupe_apply(rule.into(), dry_run).await?;
+                }
+            }
+        }
+        ExastashCommand::Gc { grace_days, dry_run } => {
+            gc(grace_days, dry_run).await?;
+        }
         ExastashCommand::Web { port } => {
             exastash::web::run(port).await?;
         }
@@ -2,6 +2,7 @@
 //! a partial mirror on the local filesystem
 
 use anyhow::{anyhow, bail};
+use chrono::{DateTime, Utc};
 use sqlx::{Postgres, Transaction};
 use anyhow::Result;
 use crate::config::{Config, PathRootsValue};
@@ -9,6 +10,12 @@ use crate::db::inode::InodeId;
 use crate::db::traversal;
 use crate::util;
 
+pub mod archive;
+pub mod find;
+pub mod manifest;
+pub mod status;
+pub mod sync;
+pub mod tar;
 mod windows_compatible;
 
 /// Resolve some local absolute path to a root directory and path components that can
@@ -43,16 +50,17 @@ pub fn resolve_root_of_local_path<S: AsRef<str> + ToString + Clone>(
     bail!("no entry in path_roots could serve as the base dir for {}", path);
 }
 
-/// Resolve some local absolute path to its exastash equivalent
+/// Resolve some local absolute path to its exastash equivalent, as of time `as_of` if given
 pub async fn resolve_local_absolute_path<S: AsRef<str> + ToString + Clone>(
     config: &Config,
     transaction: &mut Transaction<'_, Postgres>,
     path_components: &[S],
+    as_of: Option<DateTime<Utc>>,
 ) -> Result<InodeId>
 {
     let (path_roots_value, idx) = resolve_root_of_local_path(config, path_components)?;
     let root_dir = path_roots_value.dir_id;
-    traversal::resolve_inode(transaction, root_dir, &path_components[idx..]).await
+    traversal::resolve_inode(transaction, root_dir, &path_components[idx..], as_of).await
 }
 
 /// Resolve some local relative path argument to normalized path components
@@ -69,23 +77,25 @@ pub fn resolve_local_path_to_path_components(path_arg: Option<&str>) -> Result<V
     Ok(util::utf8_path_to_components(s))
 }
 
-/// Resolve normalized path components to its exastash equivalent inode
+/// Resolve normalized path components to its exastash equivalent inode, as of time `as_of` if given
 pub async fn resolve_path_components<S: AsRef<str> + ToString + Clone>(
     config: &Config,
     transaction: &mut Transaction<'_, Postgres>,
     path_components: &[S],
+    as_of: Option<DateTime<Utc>>,
 ) -> Result<InodeId> {
-    resolve_local_absolute_path(config, transaction, path_components).await
+    resolve_local_absolute_path(config, transaction, path_components, as_of).await
 }
 
-/// Resolve some local relative path argument to its exastash equivalent inode
+/// Resolve some local relative path argument to its exastash equivalent inode, as of time `as_of` if given
 pub async fn resolve_local_path_arg(
     config: &Config,
     transaction: &mut Transaction<'_, Postgres>,
-    path_arg: Option<&str>
+    path_arg: Option<&str>,
+    as_of: Option<DateTime<Utc>>,
 ) -> Result<InodeId> {
     let path_components = resolve_local_path_to_path_components(path_arg)?;
-    resolve_path_components(config, transaction, &path_components).await
+    resolve_path_components(config, transaction, &path_components, as_of).await
 }
 
 /// Validate path components and return an error if any path component is invalid given `validators`.
@@ -0,0 +1,427 @@
+//! Reading the members of a tar or zip archive without extracting it to disk
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tracing::warn;
+use crate::util;
+
+/// How much member content to buffer between the thread reading the archive and the reader of the member
+const MEMBER_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The kind of an archive member, with its content if it is a file
+#[derive(Debug)]
+pub enum MemberKind {
+    /// A directory
+    Dir,
+    /// A regular file. `content` must be read to the end or dropped before the next
+    /// member can be received.
+    File {
+        /// Size of the file in bytes
+        size: u64,
+        /// Whether the file is executable
+        executable: bool,
+        /// The content of the file
+        content: DuplexStream,
+    },
+    /// A symbolic link
+    Symlink {
+        /// Target path
+        target: String,
+    },
+    /// A hard link to a file member earlier in the archive
+    HardLink {
+        /// The path components of the linked member, without any `.` or `..` components
+        target: Vec<String>,
+    },
+}
+
+/// A member of an archive
+#[derive(Debug)]
+pub struct Member {
+    /// The path components of the member, without any `.` or `..` components
+    pub path: Vec<String>,
+    /// Modification time, precision only up to microseconds
+    pub mtime: DateTime<Utc>,
+    /// The kind of member
+    pub kind: MemberKind,
+}
+
+/// The format of an archive, detected from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A zip file
+    Zip,
+    /// A gzip-compressed tar
+    TarGzip,
+    /// A zstd-compressed tar
+    TarZstd,
+    /// An uncompressed tar
+    Tar,
+}
+
+/// Detect the format of an archive from its first bytes
+pub fn detect_format(magic: &[u8]) -> ArchiveFormat {
+    if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06") {
+        ArchiveFormat::Zip
+    } else if magic.starts_with(&[0x1f, 0x8b]) {
+        ArchiveFormat::TarGzip
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        ArchiveFormat::TarZstd
+    } else {
+        ArchiveFormat::Tar
+    }
+}
+
+/// Split a member path into components, dropping `.` components and trailing slashes.
+/// Absolute paths and paths with `..` components are rejected, because they could
+/// escape the destination dir.
+pub fn member_path_components(path: &Path) -> Result<Vec<String>> {
+    let mut components = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                let name = name.to_str().ok_or_else(|| anyhow!("archive member path {path:?} is not UTF-8"))?;
+                components.push(name.to_string());
+            }
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                bail!("archive member path {path:?} is absolute or has a '..' component");
+            }
+        }
+    }
+    Ok(components)
+}
+
+/// Read the members of the tar (optionally gzip- or zstd-compressed) or zip archive
+/// at `path` in a blocking thread, sending them in archive order. Members that are not
+/// dirs, regular files, symlinks, or hard links are skipped with a warning. The thread stops at
+/// the first error, which is sent as the last item.
+pub fn read_archive(path: PathBuf) -> mpsc::Receiver<Result<Member>> {
+    let (tx, rx) = mpsc::channel(1);
+    let handle = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let sender = MemberSender { tx, handle };
+        if let Err(err) = read_archive_blocking(&path, &sender) {
+            // The receiver may be gone, in which case nobody wants the error
+            let _ = sender.tx.blocking_send(Err(err));
+        }
+    });
+    rx
+}
+
+struct MemberSender {
+    tx: mpsc::Sender<Result<Member>>,
+    handle: Handle,
+}
+
+impl MemberSender {
+    /// Send a member that has no content. Returns `false` if the receiver is gone.
+    fn send(&self, path: Vec<String>, mtime: DateTime<Utc>, kind: MemberKind) -> bool {
+        self.tx.blocking_send(Ok(Member { path, mtime, kind })).is_ok()
+    }
+
+    /// Send a file member and copy `reader` to its content. If the receiver drops the
+    /// content early, the rest of `reader` is read and discarded. Returns `false` if
+    /// the receiver is gone.
+    fn send_file(&self, path: Vec<String>, mtime: DateTime<Utc>, size: u64, mode: u32, reader: &mut impl Read) -> Result<bool> {
+        let (mut writer, content) = tokio::io::duplex(MEMBER_BUFFER_SIZE);
+        let kind = MemberKind::File { size, executable: mode & 0o100 != 0, content };
+        if !self.send(path, mtime, kind) {
+            return Ok(false);
+        }
+        let mut buf = vec![0; 65536];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            if self.handle.block_on(writer.write_all(&buf[..n])).is_err() {
+                io::copy(reader, &mut io::sink())?;
+                break;
+            }
+        }
+        // Ignore errors here too, because the content may have been dropped after reading all of it
+        let _ = self.handle.block_on(writer.shutdown());
+        Ok(true)
+    }
+}
+
+fn read_archive_blocking(path: &Path, sender: &MemberSender) -> Result<()> {
+    let mut file = File::open(path)?;
+    let mut magic = [0; 4];
+    let n = file.read(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    match detect_format(&magic[..n]) {
+        ArchiveFormat::Zip => read_zip(file, sender),
+        ArchiveFormat::TarGzip => read_tar(flate2::read::MultiGzDecoder::new(BufReader::new(file)), sender),
+        ArchiveFormat::TarZstd => read_tar(zstd::stream::read::Decoder::new(file)?, sender),
+        ArchiveFormat::Tar => read_tar(file, sender),
+    }
+}
+
+fn read_tar(reader: impl Read, sender: &MemberSender) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let member_path = entry.path()?.into_owned();
+        let path = member_path_components(&member_path)?;
+        let pax_mtime = match entry.pax_extensions()? {
+            Some(mut extensions) => extensions.find(|ext| ext.as_ref().is_ok_and(|ext| ext.key_bytes() == b"mtime")).transpose()?,
+            None => None,
+        };
+        let mtime = match pax_mtime {
+            Some(ext) => ext.value().ok().and_then(parse_pax_mtime),
+            None => Utc.timestamp_opt(entry.header().mtime()? as i64, 0).single(),
+        }.ok_or_else(|| anyhow!("archive member {member_path:?} has an invalid or out-of-range mtime"))?;
+        let entry_type = entry.header().entry_type();
+        let sent = match entry_type {
+            tar::EntryType::Directory => sender.send(path, mtime, MemberKind::Dir),
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let mode = entry.header().mode()?;
+                let size = entry.size();
+                sender.send_file(path, mtime, size, mode, &mut entry)?
+            }
+            tar::EntryType::Symlink => {
+                let target = entry.link_name()?
+                    .ok_or_else(|| anyhow!("archive member {member_path:?} is a symlink without a target"))?;
+                let target = target.to_str()
+                    .ok_or_else(|| anyhow!("target of archive member {member_path:?} is not UTF-8"))?
+                    .to_string();
+                sender.send(path, mtime, MemberKind::Symlink { target })
+            }
+            tar::EntryType::Link => {
+                let target = entry.link_name()?
+                    .ok_or_else(|| anyhow!("archive member {member_path:?} is a hard link without a target"))?;
+                let target = member_path_components(&target)?;
+                sender.send(path, mtime, MemberKind::HardLink { target })
+            }
+            _ => {
+                warn!(?member_path, ?entry_type, "skipping archive member that is not a dir, regular file, symlink, or hard link");
+                true
+            }
+        };
+        if !sent {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Parse the value of a pax `mtime` record, a decimal number of seconds since the epoch
+/// such as "1600000000.5" or "-1.5", truncating it to microseconds
+fn parse_pax_mtime(value: &str) -> Option<DateTime<Utc>> {
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value),
+    };
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    if secs.is_empty() || !secs.bytes().all(|b| b.is_ascii_digit()) || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let frac = format!("{:0<6}", &frac[..frac.len().min(6)]);
+    let micros = secs.parse::<i64>().ok()?.checked_mul(1_000_000)?.checked_add(frac.parse::<i64>().ok()?)?;
+    Utc.timestamp_micros(if negative { -micros } else { micros }).single()
+}
+
+/// The mtime of a zip member, from its extended timestamp if it has one,
+/// otherwise from its MS-DOS timestamp, which is in local time
+fn zip_mtime(file: &zip::read::ZipFile<'_>) -> Option<DateTime<Utc>> {
+    for field in file.extra_data_fields() {
+        if let zip::extra_fields::ExtraField::ExtendedTimestamp(timestamp) = field {
+            if let Some(mod_time) = timestamp.mod_time() {
+                return Utc.timestamp_opt(mod_time.into(), 0).single();
+            }
+        }
+    }
+    let dos = file.last_modified()?;
+    let naive = NaiveDate::from_ymd_opt(dos.year().into(), dos.month().into(), dos.day().into())?
+        .and_hms_opt(dos.hour().into(), dos.minute().into(), dos.second().into())?;
+    Local.from_local_datetime(&naive).earliest().map(|local| local.with_timezone(&Utc))
+}
+
+fn read_zip(file: File, sender: &MemberSender) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+    for i in 0..archive.len() {
+        let mut zip_file = archive.by_index(i)?;
+        let name = zip_file.name().to_string();
+        let path = member_path_components(Path::new(&name))?;
+        let mtime = zip_mtime(&zip_file).map_or_else(Utc::now, util::without_nanos);
+        let mode = zip_file.unix_mode().unwrap_or(0o644);
+        let sent = if zip_file.is_dir() {
+            sender.send(path, mtime, MemberKind::Dir)
+        } else if mode & 0o170000 == 0o120000 {
+            // Zip stores the target of a symlink as its content
+            let mut target = String::new();
+            zip_file.read_to_string(&mut target)?;
+            sender.send(path, mtime, MemberKind::Symlink { target })
+        } else {
+            let size = zip_file.size();
+            sender.send_file(path, mtime, size, mode, &mut zip_file)?
+        };
+        if !sent {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use chrono::{Datelike, Timelike};
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format(b"PK\x03\x04"), ArchiveFormat::Zip);
+        assert_eq!(detect_format(&[0x1f, 0x8b, 0x08, 0x00]), ArchiveFormat::TarGzip);
+        assert_eq!(detect_format(&[0x28, 0xb5, 0x2f, 0xfd]), ArchiveFormat::TarZstd);
+        assert_eq!(detect_format(b"dir/"), ArchiveFormat::Tar);
+        assert_eq!(detect_format(b""), ArchiveFormat::Tar);
+    }
+
+    #[test]
+    fn test_member_path_components() -> Result<()> {
+        assert_eq!(member_path_components(Path::new("./a/b/"))?, vec!["a", "b"]);
+        assert_eq!(member_path_components(Path::new("a/./b"))?, vec!["a", "b"]);
+        assert!(member_path_components(Path::new("/a")).is_err());
+        assert!(member_path_components(Path::new("a/../../b")).is_err());
+        Ok(())
+    }
+
+    /// Receive every member, reading the content of files
+    async fn read_members(path: PathBuf) -> Result<Vec<(Vec<String>, i64, String)>> {
+        let mut members = vec![];
+        let mut rx = read_archive(path);
+        while let Some(member) = rx.recv().await {
+            let member = member?;
+            let description = match member.kind {
+                MemberKind::Dir => "dir".into(),
+                MemberKind::Symlink { target } => format!("symlink -> {target}"),
+                MemberKind::HardLink { target } => format!("hard link -> {}", target.join("/")),
+                MemberKind::File { size, executable, mut content } => {
+                    let mut s = String::new();
+                    content.read_to_string(&mut s).await?;
+                    assert_eq!(s.len() as u64, size);
+                    format!("file executable={executable} {s}")
+                }
+            };
+            members.push((member.path, member.mtime.timestamp(), description));
+        }
+        Ok(members)
+    }
+
+    fn expected() -> Vec<(Vec<String>, i64, String)> {
+        vec![
+            (vec!["d".into()], 1_600_000_000, "dir".into()),
+            (vec!["d".into(), "f".into()], 1_600_000_002, "file executable=true hello".into()),
+            (vec!["d".into(), "l".into()], 1_600_000_004, "symlink -> f".into()),
+        ]
+    }
+
+    fn tar_bytes() -> Result<Vec<u8>> {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_mtime(1_600_000_000);
+        header.set_size(0);
+        builder.append_data(&mut header, "./d/", io::empty())?;
+        let mut header = tar::Header::new_ustar();
+        header.set_mode(0o755);
+        header.set_mtime(1_600_000_002);
+        header.set_size(5);
+        builder.append_data(&mut header, "d/f", &b"hello"[..])?;
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_mtime(1_600_000_004);
+        header.set_size(0);
+        builder.append_link(&mut header, "d/l", "f")?;
+        builder.append_pax_extensions([("mtime", &b"1600000006.25"[..])])?;
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_mtime(1_600_000_006);
+        header.set_size(0);
+        builder.append_link(&mut header, "d/h", "./d/f")?;
+        Ok(builder.into_inner()?)
+    }
+
+    fn expected_tar() -> Vec<(Vec<String>, i64, String)> {
+        let mut members = expected();
+        members.push((vec!["d".into(), "h".into()], 1_600_000_006, "hard link -> d/f".into()));
+        members
+    }
+
+    #[test]
+    fn test_parse_pax_mtime() {
+        let micros = |value| parse_pax_mtime(value).map(|mtime| mtime.timestamp_micros());
+        assert_eq!(micros("1600000000"), Some(1_600_000_000_000_000));
+        assert_eq!(micros("1600000000.25"), Some(1_600_000_000_250_000));
+        assert_eq!(micros("1600000000.1234567"), Some(1_600_000_000_123_456));
+        assert_eq!(micros("-1.5"), Some(-1_500_000));
+        assert_eq!(micros("1."), Some(1_000_000));
+        assert_eq!(micros(""), None);
+        assert_eq!(micros("1.5e3"), None);
+        assert_eq!(micros("--1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_tar() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(&tar_bytes()?)?;
+        assert_eq!(read_members(file.path().into()).await?, expected_tar());
+
+        // The mtime in a pax record has sub-second precision
+        let mut rx = read_archive(file.path().into());
+        let mut last = None;
+        while let Some(member) = rx.recv().await {
+            last = Some(member?);
+        }
+        assert_eq!(last.unwrap().mtime.timestamp_subsec_micros(), 250_000);
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        let mut encoder = flate2::write::GzEncoder::new(&mut file, flate2::Compression::default());
+        encoder.write_all(&tar_bytes()?)?;
+        encoder.finish()?;
+        assert_eq!(read_members(file.path().into()).await?, expected_tar());
+
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(&zstd::stream::encode_all(tar_bytes()?.as_slice(), 3)?)?;
+        assert_eq!(read_members(file.path().into()).await?, expected_tar());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_zip() -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        let mut writer = zip::ZipWriter::new(&mut file);
+        let options = |mode, mtime| {
+            let local = DateTime::from_timestamp(mtime, 0).unwrap().with_timezone(&Local);
+            let mtime = zip::DateTime::from_date_and_time(
+                local.year() as u16, local.month() as u8, local.day() as u8,
+                local.hour() as u8, local.minute() as u8, local.second() as u8,
+            ).unwrap();
+            zip::write::SimpleFileOptions::default().unix_permissions(mode).last_modified_time(mtime)
+        };
+        writer.add_directory("d/", options(0o755, 1_600_000_000))?;
+        writer.start_file("d/f", options(0o755, 1_600_000_002))?;
+        writer.write_all(b"hello")?;
+        writer.add_symlink("d/l", "f", options(0o777, 1_600_000_004))?;
+        writer.finish()?;
+        assert_eq!(read_members(file.path().into()).await?, expected());
+
+        // Members after the one the receiver stopped at are not read
+        let mut rx = read_archive(file.path().into());
+        let member = rx.recv().await.unwrap()?;
+        assert_eq!(member.path, vec!["d"]);
+        drop(rx);
+        Ok(())
+    }
+}
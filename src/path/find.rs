@@ -0,0 +1,529 @@
+//! findutils-style predicates for `es x find`
+
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use crate::db::inode::Inode;
+use crate::db::storage::StorageView;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum GlobToken {
+    Literal(char),
+    AnyChar,
+    AnyString,
+    Class(Vec<(char, char)>),
+    NegatedClass(Vec<(char, char)>),
+}
+
+impl GlobToken {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            GlobToken::Literal(l) => *l == c,
+            GlobToken::AnyChar => true,
+            GlobToken::AnyString => unreachable!(),
+            GlobToken::Class(ranges) => ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)),
+            GlobToken::NegatedClass(ranges) => !ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)),
+        }
+    }
+}
+
+/// A shell glob pattern supporting `*`, `?`, `[...]` character classes
+/// (negated with `!` or `^`), and `\` escapes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Glob {
+    tokens: Vec<GlobToken>,
+    case_insensitive: bool,
+}
+
+impl Glob {
+    /// Parse a glob `pattern`
+    pub fn new(pattern: &str, case_insensitive: bool) -> Glob {
+        let pattern = if case_insensitive { pattern.to_lowercase() } else { pattern.to_string() };
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = vec![];
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '*' => tokens.push(GlobToken::AnyString),
+                '?' => tokens.push(GlobToken::AnyChar),
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    tokens.push(GlobToken::Literal(chars[i]));
+                }
+                '[' => {
+                    if let Some((token, end)) = Self::parse_class(&chars, i) {
+                        tokens.push(token);
+                        i = end;
+                    } else {
+                        tokens.push(GlobToken::Literal('['));
+                    }
+                }
+                c => tokens.push(GlobToken::Literal(c)),
+            }
+            i += 1;
+        }
+        Glob { tokens, case_insensitive }
+    }
+
+    /// Parse a glob `pattern` that matches regardless of case
+    pub fn new_case_insensitive(pattern: &str) -> Result<Glob> {
+        Ok(Glob::new(pattern, true))
+    }
+
+    /// Parse the class starting at `chars[start] == '['`, returning the token
+    /// and the index of the closing `]`, or `None` if the class is not closed
+    fn parse_class(chars: &[char], start: usize) -> Option<(GlobToken, usize)> {
+        let mut i = start + 1;
+        let negated = matches!(chars.get(i), Some('!' | '^'));
+        if negated {
+            i += 1;
+        }
+        let mut ranges = vec![];
+        let mut first = true;
+        while i < chars.len() {
+            let c = chars[i];
+            // A `]` right after the opening `[` or `[!` is a literal
+            if c == ']' && !first {
+                let token = if negated { GlobToken::NegatedClass(ranges) } else { GlobToken::Class(ranges) };
+                return Some((token, i));
+            }
+            first = false;
+            if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&hi| hi != ']') {
+                ranges.push((c, chars[i + 2]));
+                i += 3;
+            } else {
+                ranges.push((c, c));
+                i += 1;
+            }
+        }
+        None
+    }
+
+    /// Whether the whole of `name` matches the pattern
+    pub fn matches(&self, name: &str) -> bool {
+        let name: Vec<char> = if self.case_insensitive {
+            name.to_lowercase().chars().collect()
+        } else {
+            name.chars().collect()
+        };
+        let tokens = &self.tokens;
+        let (mut t, mut n) = (0, 0);
+        // The position of the last `*` and the position in `name` it is matching up to
+        let mut backtrack: Option<(usize, usize)> = None;
+        while n < name.len() {
+            if t < tokens.len() && tokens[t] == GlobToken::AnyString {
+                backtrack = Some((t, n));
+                t += 1;
+            } else if t < tokens.len() && tokens[t].matches(name[n]) {
+                t += 1;
+                n += 1;
+            } else if let Some((star_t, star_n)) = backtrack {
+                backtrack = Some((star_t, star_n + 1));
+                t = star_t + 1;
+                n = star_n + 1;
+            } else {
+                return false;
+            }
+        }
+        tokens[t..].iter().all(|token| *token == GlobToken::AnyString)
+    }
+
+    /// Whether the match ignores case
+    pub fn is_case_insensitive(&self) -> bool {
+        self.case_insensitive
+    }
+
+    /// Whether the pattern has no wildcards or character classes
+    pub fn is_literal(&self) -> bool {
+        self.tokens.iter().all(|token| matches!(token, GlobToken::Literal(_)))
+    }
+
+    /// A SQL `LIKE` pattern (with `\` as the escape character) that matches at least
+    /// every name this glob matches. Character classes become `_`, so candidates
+    /// found with it must still be checked with `matches`.
+    pub fn like_pattern(&self) -> String {
+        let mut pattern = String::new();
+        for token in &self.tokens {
+            match token {
+                GlobToken::Literal(c) => {
+                    if matches!(c, '%' | '_' | '\\') {
+                        pattern.push('\\');
+                    }
+                    pattern.push(*c);
+                }
+                GlobToken::AnyString => pattern.push('%'),
+                GlobToken::AnyChar | GlobToken::Class(_) | GlobToken::NegatedClass(_) => pattern.push('_'),
+            }
+        }
+        pattern
+    }
+}
+
+impl FromStr for Glob {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Glob> {
+        Ok(Glob::new(pattern, false))
+    }
+}
+
+/// A comparison against a number, as in findutils: `+N` for more than N,
+/// `-N` for less than N, and `N` for exactly N
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumberComparison {
+    /// More than N
+    MoreThan(i64),
+    /// Less than N
+    LessThan(i64),
+    /// Exactly N
+    Exactly(i64),
+}
+
+impl NumberComparison {
+    /// Whether `number` satisfies the comparison
+    pub fn matches(&self, number: i64) -> bool {
+        match *self {
+            NumberComparison::MoreThan(n) => number > n,
+            NumberComparison::LessThan(n) => number < n,
+            NumberComparison::Exactly(n) => number == n,
+        }
+    }
+}
+
+impl FromStr for NumberComparison {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<NumberComparison> {
+        let parse = |n: &str| n.parse::<i64>().map_err(|_| anyhow!("expected a number in {s:?}"));
+        Ok(if let Some(n) = s.strip_prefix('+') {
+            NumberComparison::MoreThan(parse(n)?)
+        } else if let Some(n) = s.strip_prefix('-') {
+            NumberComparison::LessThan(parse(n)?)
+        } else {
+            NumberComparison::Exactly(parse(s)?)
+        })
+    }
+}
+
+/// A size comparison like findutils' `-size`, e.g. `+10M`. The unit is one of
+/// `c` for bytes (the default), `k` for KiB, `M` for MiB, or `G` for GiB, and
+/// sizes are rounded up to the unit before comparing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeFilter {
+    comparison: NumberComparison,
+    unit: i64,
+}
+
+impl SizeFilter {
+    /// Whether a file of `size` bytes satisfies the comparison
+    pub fn matches(&self, size: i64) -> bool {
+        let units = (size + self.unit - 1) / self.unit;
+        self.comparison.matches(units)
+    }
+}
+
+impl FromStr for SizeFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SizeFilter> {
+        let (number, unit) = match s.chars().last() {
+            Some('c') => (&s[..s.len() - 1], 1),
+            Some('k') => (&s[..s.len() - 1], 1 << 10),
+            Some('M') => (&s[..s.len() - 1], 1 << 20),
+            Some('G') => (&s[..s.len() - 1], 1 << 30),
+            _ => (s, 1),
+        };
+        Ok(SizeFilter { comparison: number.parse()?, unit })
+    }
+}
+
+/// An age comparison in whole days like findutils' `-mtime`, e.g. `-7` for
+/// modified in the last 7 days
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AgeFilter(NumberComparison);
+
+impl AgeFilter {
+    /// Whether something last modified at `mtime` satisfies the comparison at time `now`
+    pub fn matches(&self, mtime: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        // Like findutils, any fraction of a day is ignored
+        let days = (now - mtime).num_seconds().div_euclid(86400);
+        self.0.matches(days)
+    }
+}
+
+impl FromStr for AgeFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<AgeFilter> {
+        Ok(AgeFilter(s.parse()?))
+    }
+}
+
+/// A kind of storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// fofs, optionally identified by pile id
+    Fofs,
+    /// Inline in the database
+    Inline,
+    /// Google Drive, optionally identified by google_domain id
+    Gdrive,
+    /// namedfiles
+    NamedFiles,
+    /// Internet Archive
+    InternetArchive,
+}
+
+/// A storage kind with an optional id, e.g. `fofs:5`, `gdrive:1`, or `inline`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageSpec {
+    /// The kind of storage
+    pub kind: StorageKind,
+    /// The fofs pile id or google_domain id
+    pub id: Option<i64>,
+}
+
+impl StorageSpec {
+    /// Whether `view` is a storage of this kind and id
+    pub fn matches(&self, view: &StorageView) -> bool {
+        let (kind, id) = match view {
+            StorageView::Fofs(view) => (StorageKind::Fofs, Some(view.pile_id.into())),
+            StorageView::Inline(_) => (StorageKind::Inline, None),
+            StorageView::Gdrive(storage) => (StorageKind::Gdrive, Some(storage.google_domain.into())),
+            StorageView::NamedFiles(_) => (StorageKind::NamedFiles, None),
+            StorageView::InternetArchive(_) => (StorageKind::InternetArchive, None),
+        };
+        kind == self.kind && (self.id.is_none() || self.id == id)
+    }
+}
+
+impl FromStr for StorageSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<StorageSpec> {
+        let (kind, id) = match s.split_once(':') {
+            Some((kind, id)) => (kind, Some(id.parse::<i64>().map_err(|_| anyhow!("expected a number after ':' in {s:?}"))?)),
+            None => (s, None),
+        };
+        let kind = match kind {
+            "fofs" => StorageKind::Fofs,
+            "inline" => StorageKind::Inline,
+            "gdrive" => StorageKind::Gdrive,
+            "namedfiles" => StorageKind::NamedFiles,
+            "internetarchive" => StorageKind::InternetArchive,
+            _ => bail!("unknown storage type {kind:?}, expected fofs, inline, gdrive, namedfiles, or internetarchive"),
+        };
+        if id.is_some() && !matches!(kind, StorageKind::Fofs | StorageKind::Gdrive) {
+            bail!("only fofs and gdrive storages have ids, got {s:?}");
+        }
+        Ok(StorageSpec { kind, id })
+    }
+}
+
+/// Parse a b3sum given as 64 hex digits
+pub fn parse_b3sum(s: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(s)?;
+    bytes.try_into().map_err(|_| anyhow!("expected 64 hex digits for a b3sum, got {s:?}"))
+}
+
+/// Predicates that must all be satisfied for `find` to print a path.
+/// Predicates on size, b3sum, and storages only match files.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Basename matches this glob
+    pub name: Option<Glob>,
+    /// Size satisfies this comparison
+    pub size: Option<SizeFilter>,
+    /// Age of mtime satisfies this comparison
+    pub mtime: Option<AgeFilter>,
+    /// mtime is after this time
+    pub newer: Option<DateTime<Utc>>,
+    /// File is executable
+    pub executable: bool,
+    /// File has this b3sum
+    pub b3sum: Option<[u8; 32]>,
+    /// File has no b3sum
+    pub no_b3sum: bool,
+    /// File has all of these storages
+    pub has_storage: Vec<StorageSpec>,
+    /// File has none of these storages
+    pub missing_storage: Vec<StorageSpec>,
+}
+
+impl Filter {
+    /// Whether `matches` needs the inode
+    pub fn needs_inode(&self) -> bool {
+        self.size.is_some() || self.mtime.is_some() || self.newer.is_some() ||
+            self.executable || self.b3sum.is_some() || self.no_b3sum || self.needs_storages()
+    }
+
+    /// Whether `matches` needs the storages of a file
+    pub fn needs_storages(&self) -> bool {
+        !self.has_storage.is_empty() || !self.missing_storage.is_empty()
+    }
+
+    /// Whether the predicates on the basename are satisfied
+    pub fn matches_name(&self, basename: &str) -> bool {
+        self.name.as_ref().map_or(true, |glob| glob.matches(basename))
+    }
+
+    /// Whether the predicates on the inode are satisfied, given the `inode` if
+    /// `needs_inode`, and the `storages` of a file if `needs_storages`
+    pub fn matches_inode(&self, inode: Option<&Inode>, storages: &[StorageView], now: DateTime<Utc>) -> bool {
+        if !self.needs_inode() {
+            return true;
+        }
+        let Some(inode) = inode else {
+            return false;
+        };
+        if let Some(age) = self.mtime {
+            if !age.matches(inode.mtime(), now) {
+                return false;
+            }
+        }
+        if let Some(newer) = self.newer {
+            if inode.mtime() <= newer {
+                return false;
+            }
+        }
+        let needs_file = self.size.is_some() || self.executable || self.b3sum.is_some() || self.no_b3sum || self.needs_storages();
+        if !needs_file {
+            return true;
+        }
+        let Inode::File(file) = inode else {
+            return false;
+        };
+        self.size.map_or(true, |size| size.matches(file.size)) &&
+            (!self.executable || file.executable) &&
+            self.b3sum.map_or(true, |b3sum| file.b3sum == Some(b3sum)) &&
+            (!self.no_b3sum || file.b3sum.is_none()) &&
+            self.has_storage.iter().all(|spec| storages.iter().any(|view| spec.matches(view))) &&
+            !self.missing_storage.iter().any(|spec| storages.iter().any(|view| spec.matches(view)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::inode::{Birth, File, Symlink};
+    use crate::db::storage::{fofs, inline};
+
+    #[test]
+    fn test_glob() {
+        for (pattern, name, expected) in [
+            ("*", "", true),
+            ("*", "anything", true),
+            ("*.txt", "a.txt", true),
+            ("*.txt", "a.txt.gz", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("*a*b*", "xxaxxbxx", true),
+            ("*a*b*", "xxbxxaxx", false),
+            ("[abc]x", "bx", true),
+            ("[!abc]x", "bx", false),
+            ("[a-z]1", "q1", true),
+            ("[]]", "]", true),
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("[", "[", true),
+            ("ü*", "über", true),
+        ] {
+            assert_eq!(Glob::new(pattern, false).matches(name), expected, "{pattern:?} {name:?}");
+        }
+        assert!(!Glob::new("*.TXT", false).matches("a.txt"));
+        assert!(Glob::new("*.TXT", true).matches("a.txt"));
+        assert!(Glob::new("*.txt", true).matches("A.TXT"));
+    }
+
+    #[test]
+    fn test_glob_like_pattern() {
+        for (pattern, expected) in [
+            ("abc", "abc"),
+            ("*.txt", "%.txt"),
+            ("a?[bc][!d]", "a___"),
+            ("100%_\\\\", "100\\%\\_\\\\"),
+        ] {
+            assert_eq!(Glob::new(pattern, false).like_pattern(), expected, "{pattern:?}");
+        }
+        assert!(Glob::new("abc", false).is_literal());
+        assert!(Glob::new("a\\*c", false).is_literal());
+        assert!(!Glob::new("a*c", false).is_literal());
+        assert_eq!(Glob::new("ABC", true).like_pattern(), "abc");
+    }
+
+    #[test]
+    fn test_size_filter() -> Result<()> {
+        assert!("100".parse::<SizeFilter>()?.matches(100));
+        assert!("+99c".parse::<SizeFilter>()?.matches(100));
+        assert!(!"-100".parse::<SizeFilter>()?.matches(100));
+        // Rounded up to the unit
+        assert!("1k".parse::<SizeFilter>()?.matches(1));
+        assert!("1k".parse::<SizeFilter>()?.matches(1024));
+        assert!(!"1k".parse::<SizeFilter>()?.matches(1025));
+        assert!("+1M".parse::<SizeFilter>()?.matches((1 << 20) + 1));
+        assert!("-1G".parse::<SizeFilter>()?.matches(0));
+        assert!("x".parse::<SizeFilter>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_age_filter() -> Result<()> {
+        let now = Utc::now();
+        let mtime = now - chrono::Duration::hours(36);
+        assert!("1".parse::<AgeFilter>()?.matches(mtime, now));
+        assert!("+0".parse::<AgeFilter>()?.matches(mtime, now));
+        assert!("-2".parse::<AgeFilter>()?.matches(mtime, now));
+        assert!(!"-1".parse::<AgeFilter>()?.matches(mtime, now));
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_spec() -> Result<()> {
+        assert_eq!("fofs:5".parse::<StorageSpec>()?, StorageSpec { kind: StorageKind::Fofs, id: Some(5) });
+        assert_eq!("gdrive".parse::<StorageSpec>()?, StorageSpec { kind: StorageKind::Gdrive, id: None });
+        assert!("inline:1".parse::<StorageSpec>().is_err());
+        assert!("s3".parse::<StorageSpec>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter() -> Result<()> {
+        let now = Utc::now();
+        let birth = Birth::here_and_now();
+        let file = Inode::File(File { id: 1, mtime: now, birth: birth.clone(), size: 10, executable: true, b3sum: None });
+        let symlink = Inode::Symlink(Symlink { id: 1, mtime: now, birth, target: "x".into() });
+        let fofs_storage = StorageView::Fofs(fofs::StorageView {
+            file_id: 1, cell_id: 1, pile_id: 5, files_per_cell: 10, pile_hostname: "host".into(), pile_path: "/".into(), offline: false,
+        });
+        let inline_storage = StorageView::Inline(inline::Storage { file_id: 1, content_zstd: vec![] });
+        let storages = [fofs_storage, inline_storage];
+
+        let filter = Filter::default();
+        assert!(!filter.needs_inode());
+        assert!(filter.matches_name("anything"));
+        assert!(filter.matches_inode(None, &[], now));
+
+        let filter = Filter { name: Some("*.txt".parse()?), ..Filter::default() };
+        assert!(filter.matches_name("a.txt"));
+        assert!(!filter.matches_name("a.jpg"));
+
+        let filter = Filter { size: Some("+5".parse()?), executable: true, no_b3sum: true, ..Filter::default() };
+        assert!(filter.matches_inode(Some(&file), &[], now));
+        assert!(!filter.matches_inode(Some(&symlink), &[], now));
+        assert!(!filter.matches_inode(None, &[], now));
+
+        let filter = Filter { mtime: Some("-1".parse()?), ..Filter::default() };
+        assert!(filter.matches_inode(Some(&symlink), &[], now));
+
+        let filter = Filter { has_storage: vec!["fofs:5".parse()?], missing_storage: vec!["gdrive".parse()?], ..Filter::default() };
+        assert!(filter.matches_inode(Some(&file), &storages, now));
+        let filter = Filter { has_storage: vec!["fofs:6".parse()?], ..Filter::default() };
+        assert!(!filter.matches_inode(Some(&file), &storages, now));
+        let filter = Filter { missing_storage: vec!["inline".parse()?], ..Filter::default() };
+        assert!(!filter.matches_inode(Some(&file), &storages, now));
+
+        let filter = Filter { b3sum: Some([0; 32]), ..Filter::default() };
+        assert!(!filter.matches_inode(Some(&file), &storages, now));
+        Ok(())
+    }
+}
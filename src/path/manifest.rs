@@ -0,0 +1,220 @@
+//! b3sum manifests of stash dirs, and verifying local copies of stash dirs
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::Serialize;
+use sqlx::{Postgres, Transaction};
+use tokio::fs;
+use crate::blake3::{b3sum_bytes, b3sum_local_file};
+use crate::db::inode::{File, Inode};
+use crate::db::traversal;
+
+/// Return every file below dir `dir_id` as of time `as_of` if given, with its path
+/// relative to the dir, sorted by path. A file is returned once for each dirent.
+pub async fn files_below(
+    transaction: &mut Transaction<'_, Postgres>,
+    dir_id: i64,
+    as_of: Option<DateTime<Utc>>,
+) -> Result<Vec<(String, File)>> {
+    let mut files = vec![];
+    let mut entries = traversal::walk(transaction, dir_id, as_of);
+    while let Some(entry) = entries.try_next().await? {
+        if let Some(Inode::File(file)) = entry.inode {
+            files.push((entry.path, file));
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files)
+}
+
+/// Return the BLAKE3 hash of the content of `file`, or `None` if it is not known.
+/// Empty files are stored without a b3sum, so theirs is the hash of no bytes.
+pub fn file_b3sum(file: &File) -> Option<[u8; 32]> {
+    if file.size == 0 {
+        return Some(*b3sum_bytes(b"").as_bytes());
+    }
+    file.b3sum
+}
+
+/// Format a line of a manifest that `b3sum --check` accepts: the hex hash, two spaces,
+/// and the path. Like b3sum, a path containing a backslash or newline is escaped and
+/// the line is prefixed with a backslash.
+pub fn manifest_line(b3sum: &[u8; 32], path: &str) -> String {
+    let hash = hex::encode(b3sum);
+    if path.contains(['\\', '\n']) {
+        let escaped = path.replace('\\', "\\\\").replace('\n', "\\n");
+        format!("\\{hash}  {escaped}")
+    } else {
+        format!("{hash}  {path}")
+    }
+}
+
+/// Why a stash file could not be verified against a local copy
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// The stash file has no b3sum to compare against
+    Unknown,
+    /// The local file has a different size or BLAKE3 hash
+    Differs,
+    /// There is no local file at the path
+    Missing,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Problem::Unknown => "unknown",
+            Problem::Differs => "differs",
+            Problem::Missing => "missing",
+        };
+        f.pad(s)
+    }
+}
+
+/// A local path reported by `check_local` as not matching its stash file
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CheckEntry {
+    /// The local path
+    pub path: PathBuf,
+    /// The stash file id
+    pub file_id: i64,
+    /// Why the local file does not match
+    pub problem: Problem,
+}
+
+impl fmt::Display for CheckEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<7}  {}", self.problem, self.path.display())
+    }
+}
+
+async fn check_file(local_path: PathBuf, file: File) -> Result<Option<CheckEntry>> {
+    let problem = match fs::metadata(&local_path).await {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Some(Problem::Missing),
+        Err(err) => return Err(err.into()),
+        Ok(attr) if !attr.is_file() => Some(Problem::Missing),
+        Ok(attr) => match file_b3sum(&file) {
+            None => Some(Problem::Unknown),
+            Some(_) if attr.len() != file.size as u64 => Some(Problem::Differs),
+            Some(b3sum) if b3sum_local_file(&local_path).await?.as_bytes() != &b3sum => Some(Problem::Differs),
+            Some(_) => None,
+        },
+    };
+    Ok(problem.map(|problem| CheckEntry { path: local_path, file_id: file.id, problem }))
+}
+
+/// Compare each of `files`, with paths relative to `local_dir`, with the local file at
+/// the same path, hashing up to `jobs` local files at a time. Calls `on_entry` in the
+/// order of `files` for each file that is missing locally, has no b3sum in the stash,
+/// or differs in size or BLAKE3 hash. Local files not in `files` are not reported.
+pub async fn check_local(
+    local_dir: &Path,
+    files: Vec<(String, File)>,
+    jobs: usize,
+    mut on_entry: impl FnMut(CheckEntry) -> Result<()>,
+) -> Result<()> {
+    let mut results = stream::iter(files)
+        .map(|(path, file)| check_file(local_dir.join(path), file))
+        .buffered(jobs);
+    while let Some(entry) = results.try_next().await? {
+        if let Some(entry) = entry {
+            on_entry(entry)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::dirent::Dirent;
+    use crate::db::dirent::tests::make_basename;
+    use crate::db::inode::{Birth, InodeId, NewDir, NewFile};
+    use crate::db::tests::new_primary_pool;
+
+    #[test]
+    fn test_file_b3sum() {
+        let file = |size, b3sum| File { id: 1, mtime: Utc::now(), birth: Birth::here_and_now(), size, executable: false, b3sum };
+        assert_eq!(file_b3sum(&file(0, None)), Some(*b3sum_bytes(b"").as_bytes()));
+        assert_eq!(file_b3sum(&file(5, None)), None);
+        assert_eq!(file_b3sum(&file(5, Some([1; 32]))), Some([1; 32]));
+    }
+
+    #[test]
+    fn test_manifest_line() {
+        let b3sum = [0xab; 32];
+        let hash = "ab".repeat(32);
+        assert_eq!(manifest_line(&b3sum, "dir/file"), format!("{hash}  dir/file"));
+        assert_eq!(manifest_line(&b3sum, "a\\b\nc"), format!("\\{hash}  a\\\\b\\nc"));
+    }
+
+    mod api {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_check_local() -> Result<()> {
+            let pool = new_primary_pool().await;
+            let mtime = Utc::now();
+            let birth = Birth::here_and_now();
+
+            let local_dir = tempfile::tempdir()?;
+            let local_path = local_dir.path();
+            std::fs::create_dir(local_path.join("subdir"))?;
+            std::fs::write(local_path.join("subdir").join("same"), b"hello")?;
+            std::fs::write(local_path.join("differs"), b"world")?;
+            std::fs::write(local_path.join("unknown"), b"hello")?;
+            std::fs::write(local_path.join("local_only"), b"hello")?;
+            std::fs::write(local_path.join("empty"), b"")?;
+            std::fs::write(local_path.join("not_empty"), b"hello")?;
+
+            let mut transaction = pool.begin().await?;
+            let root_dir = NewDir { mtime, birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(1, make_basename("check_local_root"), InodeId::Dir(root_dir.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let subdir = NewDir { mtime, birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(root_dir.id, "subdir", InodeId::Dir(subdir.id)).create(&mut transaction).await?;
+            let hello = *b3sum_bytes(b"hello").as_bytes();
+            let new_file = |b3sum| NewFile { size: 5, executable: false, mtime, birth: birth.clone(), b3sum };
+            let same = new_file(Some(hello)).create(&mut transaction).await?;
+            Dirent::new(subdir.id, "same", InodeId::File(same.id)).create(&mut transaction).await?;
+            let differs = new_file(Some(hello)).create(&mut transaction).await?;
+            Dirent::new(root_dir.id, "differs", InodeId::File(differs.id)).create(&mut transaction).await?;
+            let unknown = new_file(None).create(&mut transaction).await?;
+            Dirent::new(root_dir.id, "unknown", InodeId::File(unknown.id)).create(&mut transaction).await?;
+            let missing = new_file(Some(hello)).create(&mut transaction).await?;
+            Dirent::new(root_dir.id, "missing", InodeId::File(missing.id)).create(&mut transaction).await?;
+            // Empty files have no b3sum
+            let empty = NewFile { size: 0, ..new_file(None) }.create(&mut transaction).await?;
+            Dirent::new(root_dir.id, "empty", InodeId::File(empty.id)).create(&mut transaction).await?;
+            let not_empty = NewFile { size: 0, ..new_file(None) }.create(&mut transaction).await?;
+            Dirent::new(root_dir.id, "not_empty", InodeId::File(not_empty.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let files = files_below(&mut transaction, root_dir.id, None).await?;
+            transaction.commit().await?; // close read-only transaction
+            let paths: Vec<&str> = files.iter().map(|(path, _)| path.as_str()).collect();
+            assert_eq!(paths, vec!["differs", "empty", "missing", "not_empty", "subdir/same", "unknown"]);
+
+            let mut entries = vec![];
+            check_local(local_path, files, 2, |entry| {
+                entries.push(entry);
+                Ok(())
+            }).await?;
+            assert_eq!(entries, vec![
+                CheckEntry { path: local_path.join("differs"), file_id: differs.id, problem: Problem::Differs },
+                CheckEntry { path: local_path.join("missing"), file_id: missing.id, problem: Problem::Missing },
+                CheckEntry { path: local_path.join("not_empty"), file_id: not_empty.id, problem: Problem::Differs },
+                CheckEntry { path: local_path.join("unknown"), file_id: unknown.id, problem: Problem::Unknown },
+            ]);
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Compare a local partial mirror against the corresponding stash dir
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use sqlx::{Postgres, Transaction};
+use tokio::fs;
+use crate::blake3::b3sum_local_file;
+use crate::db::dirent::Dirent;
+use crate::db::inode::{Inode, InodeId};
+use crate::storage::RelevantFileMetadata;
+use crate::util;
+
+/// The type of a local path or stash inode
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryType {
+    /// A directory
+    Dir,
+    /// A regular file
+    File,
+    /// A symbolic link
+    Symlink,
+    /// A local socket, FIFO, or device, which cannot exist in the stash
+    Other,
+}
+
+impl EntryType {
+    fn of_local(attr: &Metadata) -> EntryType {
+        let file_type = attr.file_type();
+        if file_type.is_dir() {
+            EntryType::Dir
+        } else if file_type.is_file() {
+            EntryType::File
+        } else if file_type.is_symlink() {
+            EntryType::Symlink
+        } else {
+            EntryType::Other
+        }
+    }
+
+    fn of_stash(inode: &Inode) -> EntryType {
+        match inode {
+            Inode::Dir(_) => EntryType::Dir,
+            Inode::File(_) => EntryType::File,
+            Inode::Symlink(_) => EntryType::Symlink,
+        }
+    }
+}
+
+/// Whether a path exists locally, in the stash, or both
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    /// Only exists on the local filesystem
+    LocalOnly,
+    /// Only exists in the stash
+    StashOnly,
+    /// Exists in both places, but with some differences
+    Differs,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Status::LocalOnly => "local-only",
+            Status::StashOnly => "stash-only",
+            Status::Differs => "differs",
+        };
+        f.pad(s)
+    }
+}
+
+/// A way in which a local path differs from its stash equivalent
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Difference {
+    /// One is a dir, file, or symlink and the other is something else
+    Type,
+    /// The file sizes differ
+    Size,
+    /// The file or symlink mtimes differ
+    Mtime,
+    /// The files differ in whether they are executable
+    Executable,
+    /// The BLAKE3 hashes of the files differ
+    B3sum,
+    /// The symlink targets differ
+    Target,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Difference::Type => "type",
+            Difference::Size => "size",
+            Difference::Mtime => "mtime",
+            Difference::Executable => "executable",
+            Difference::B3sum => "b3sum",
+            Difference::Target => "target",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A path reported by `status` as not being the same locally and in the stash
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct StatusEntry {
+    /// The local path
+    pub path: PathBuf,
+    /// Whether the path exists locally, in the stash, or both
+    pub status: Status,
+    /// The type of the local path, if it exists
+    pub local_type: Option<EntryType>,
+    /// The type of the stash inode, if it exists
+    pub stash_type: Option<EntryType>,
+    /// How the local path differs from the stash inode, if both exist
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub differences: Vec<Difference>,
+}
+
+impl fmt::Display for StatusEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<10}  {}", self.status, self.path.display())?;
+        if !self.differences.is_empty() {
+            let differences: Vec<String> = self.differences.iter().map(ToString::to_string).collect();
+            write!(f, " ({})", differences.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+async fn local_metadata(path: &Path) -> Result<Option<Metadata>> {
+    match fs::symlink_metadata(path).await {
+        Ok(attr) => Ok(Some(attr)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn compare(path: &Path, local: Option<&Metadata>, stash: Option<&Inode>, checksum: bool) -> Result<Option<StatusEntry>> {
+    let local_type = local.map(EntryType::of_local);
+    let stash_type = stash.map(EntryType::of_stash);
+    let mut differences = vec![];
+    let status = match (local, stash) {
+        (None, None) => return Ok(None),
+        (Some(_), None) => Status::LocalOnly,
+        (None, Some(_)) => Status::StashOnly,
+        (Some(attr), Some(inode)) => {
+            match inode {
+                _ if local_type != stash_type => differences.push(Difference::Type),
+                // Dir mtimes are not compared because they change whenever a child is added or removed
+                Inode::Dir(_) => {}
+                Inode::File(file) => {
+                    let metadata: RelevantFileMetadata = attr.try_into()?;
+                    if metadata.size != file.size {
+                        differences.push(Difference::Size);
+                    }
+                    if metadata.mtime != file.mtime {
+                        differences.push(Difference::Mtime);
+                    }
+                    if metadata.executable != file.executable {
+                        differences.push(Difference::Executable);
+                    }
+                    // Stash files without a b3sum cannot be compared by content
+                    if let (true, true, Some(b3sum)) = (checksum, metadata.size == file.size, file.b3sum) {
+                        if b3sum_local_file(path).await?.as_bytes() != &b3sum {
+                            differences.push(Difference::B3sum);
+                        }
+                    }
+                }
+                Inode::Symlink(symlink) => {
+                    let target = fs::read_link(path).await?;
+                    if target.to_str() != Some(symlink.target.as_str()) {
+                        differences.push(Difference::Target);
+                    }
+                    if util::without_nanos(attr.modified()?.into()) != symlink.mtime {
+                        differences.push(Difference::Mtime);
+                    }
+                }
+            }
+            if differences.is_empty() {
+                return Ok(None);
+            }
+            Status::Differs
+        }
+    };
+    Ok(Some(StatusEntry { path: path.to_path_buf(), status, local_type, stash_type, differences }))
+}
+
+/// Compare local path `local_path` with its stash equivalent `stash_inode` (`None` if
+/// it does not exist), calling `on_entry` for each path that is not the same in both
+/// places. Dirs that exist in both places are walked together; a path that exists in
+/// only one place is reported once, without its descendants.
+///
+/// Files are compared by size, mtime, and executable bit, and if `checksum` is set,
+/// also by BLAKE3 hash when the stash file has a b3sum. Symlinks are compared by
+/// target and mtime.
+pub async fn status(
+    transaction: &mut Transaction<'_, Postgres>,
+    local_path: &Path,
+    stash_inode: Option<InodeId>,
+    checksum: bool,
+    mut on_entry: impl FnMut(StatusEntry) -> Result<()>,
+) -> Result<()> {
+    let local = local_metadata(local_path).await?;
+    let stash = match stash_inode {
+        Some(inode_id) => {
+            let mut inodes = Inode::find_by_inode_ids(transaction, &[inode_id]).await?;
+            Some(inodes.remove(&inode_id).ok_or_else(|| anyhow!("{:?} not found in database", inode_id))?)
+        }
+        None => None,
+    };
+    if let Some(entry) = compare(local_path, local.as_ref(), stash.as_ref(), checksum).await? {
+        on_entry(entry)?;
+    }
+
+    let mut stack = vec![];
+    if let (Some(EntryType::Dir), Some(Inode::Dir(dir))) = (local.as_ref().map(EntryType::of_local), &stash) {
+        stack.push((local_path.to_path_buf(), dir.id));
+    }
+    while let Some((dir_path, dir_id)) = stack.pop() {
+        let mut local_children = HashMap::new();
+        let mut entries = fs::read_dir(&dir_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().into_string()
+                .map_err(|name| anyhow!("filename {name:?} in {dir_path:?} is not UTF-8"))?;
+            local_children.insert(name, entry.metadata().await?);
+        }
+
+        let dirents = Dirent::find_by_parents(transaction, &[dir_id], None).await?;
+        let children: Vec<InodeId> = dirents.iter().map(|dirent| dirent.child).collect();
+        let inodes = Inode::find_by_inode_ids(transaction, &children).await?;
+        let stash_children: HashMap<String, &Inode> = dirents
+            .iter()
+            .map(|dirent| Ok((dirent.basename.clone(), inodes.get(&dirent.child).ok_or_else(|| anyhow!("{:?} not found in database", dirent.child))?)))
+            .collect::<Result<_>>()?;
+
+        let names: BTreeSet<&String> = local_children.keys().chain(stash_children.keys()).collect();
+        let mut subdirs = vec![];
+        for name in names {
+            let path = dir_path.join(name);
+            let local = local_children.get(name);
+            let stash = stash_children.get(name).copied();
+            if let Some(entry) = compare(&path, local, stash, checksum).await? {
+                on_entry(entry)?;
+            }
+            if let (Some(EntryType::Dir), Some(Inode::Dir(dir))) = (local.map(EntryType::of_local), stash) {
+                subdirs.push((path, dir.id));
+            }
+        }
+        // Reverse so that subdirs are walked in sorted order
+        stack.extend(subdirs.into_iter().rev());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::new_primary_pool;
+    use crate::db::inode::{Birth, NewDir, NewFile, NewSymlink};
+    use crate::db::dirent::tests::make_basename;
+    use chrono::{DateTime, Utc};
+
+    mod api {
+        use super::*;
+
+        #[tokio::test]
+        async fn test_status() -> Result<()> {
+            let pool = new_primary_pool().await;
+            let mtime = DateTime::<Utc>::from_timestamp(1_600_000_000, 123_000).unwrap();
+            let birth = Birth::here_and_now();
+
+            let local_dir = tempfile::tempdir()?;
+            let local_path = local_dir.path();
+            std::fs::write(local_path.join("same"), b"hello")?;
+            std::fs::write(local_path.join("content_differs"), b"world")?;
+            std::fs::write(local_path.join("size_differs"), b"hello")?;
+            std::fs::write(local_path.join("local_only"), b"")?;
+            std::fs::create_dir(local_path.join("type_differs"))?;
+            std::fs::create_dir(local_path.join("subdir"))?;
+            std::os::unix::fs::symlink("other_target", local_path.join("subdir").join("symlink"))?;
+            let local_mtime = filetime::FileTime::from_system_time(mtime.into());
+            for name in ["same", "content_differs", "size_differs"] {
+                filetime::set_file_mtime(local_path.join(name), local_mtime)?;
+            }
+            filetime::set_symlink_file_times(local_path.join("subdir").join("symlink"), local_mtime, local_mtime)?;
+
+            let mut transaction = pool.begin().await?;
+            let root_dir = NewDir { mtime, birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(1, make_basename("status_root"), InodeId::Dir(root_dir.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let mut transaction = pool.begin().await?;
+            let subdir = NewDir { mtime, birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(root_dir.id, "subdir", InodeId::Dir(subdir.id)).create(&mut transaction).await?;
+            let hello = *crate::blake3::b3sum_bytes(b"hello").as_bytes();
+            for name in ["same", "content_differs", "type_differs", "stash_only"] {
+                let file = NewFile { size: 5, executable: false, mtime, birth: birth.clone(), b3sum: Some(hello) }.create(&mut transaction).await?;
+                Dirent::new(root_dir.id, name, InodeId::File(file.id)).create(&mut transaction).await?;
+            }
+            let file = NewFile { size: 4, executable: false, mtime, birth: birth.clone(), b3sum: None }.create(&mut transaction).await?;
+            Dirent::new(root_dir.id, "size_differs", InodeId::File(file.id)).create(&mut transaction).await?;
+            let symlink = NewSymlink { target: "target".into(), mtime, birth: birth.clone() }.create(&mut transaction).await?;
+            Dirent::new(subdir.id, "symlink", InodeId::Symlink(symlink.id)).create(&mut transaction).await?;
+            transaction.commit().await?;
+
+            let entry = |name: &str, status, local_type, stash_type, differences| {
+                StatusEntry { path: local_path.join(name), status, local_type, stash_type, differences }
+            };
+            let file = Some(EntryType::File);
+
+            let mut transaction = pool.begin().await?;
+            let mut entries = vec![];
+            status(&mut transaction, local_path, Some(InodeId::Dir(root_dir.id)), false, |entry| {
+                entries.push(entry);
+                Ok(())
+            }).await?;
+            assert_eq!(entries, vec![
+                entry("local_only", Status::LocalOnly, file, None, vec![]),
+                entry("size_differs", Status::Differs, file, file, vec![Difference::Size]),
+                entry("stash_only", Status::StashOnly, None, file, vec![]),
+                entry("type_differs", Status::Differs, Some(EntryType::Dir), file, vec![Difference::Type]),
+                entry("subdir/symlink", Status::Differs, Some(EntryType::Symlink), Some(EntryType::Symlink), vec![Difference::Target]),
+            ]);
+
+            // With checksum, content_differs is also reported
+            let mut entries = vec![];
+            status(&mut transaction, local_path, Some(InodeId::Dir(root_dir.id)), true, |entry| {
+                entries.push(entry);
+                Ok(())
+            }).await?;
+            assert_eq!(entries[0], entry("content_differs", Status::Differs, file, file, vec![Difference::B3sum]));
+            assert_eq!(entries.len(), 6);
+
+            // A missing stash inode reports just the local path
+            let mut entries = vec![];
+            status(&mut transaction, local_path, None, false, |entry| {
+                entries.push(entry);
+                Ok(())
+            }).await?;
+            assert_eq!(entries, vec![
+                StatusEntry { path: local_path.to_path_buf(), status: Status::LocalOnly, local_type: Some(EntryType::Dir), stash_type: None, differences: vec![] },
+            ]);
+            transaction.commit().await?; // close read-only transaction
+
+            Ok(())
+        }
+    }
+}
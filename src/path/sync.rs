@@ -0,0 +1,200 @@
+//! Planning for one-way syncs between a local partial mirror and the stash
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::path::status::{EntryType, Status, StatusEntry};
+
+/// Which side of a sync is the source of truth
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Make the stash match the local filesystem
+    ToStash,
+    /// Make the local filesystem match the stash
+    FromStash,
+}
+
+/// What a sync does with a path reported by `status`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Copy the source to the destination, where nothing exists yet
+    Copy,
+    /// Replace the file or symlink at the destination with the source
+    Replace,
+    /// Remove the destination, which does not exist in the source
+    Delete,
+    /// Remove the dir at the destination, then copy the source there
+    DeleteAndCopy,
+    /// Leave the path alone
+    Skip {
+        /// Why the path is left alone
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Action::Copy => "copy",
+            Action::Replace => "replace",
+            Action::Delete => "delete",
+            Action::DeleteAndCopy => "delete-and-copy",
+            Action::Skip { .. } => "skip",
+        };
+        f.pad(s)
+    }
+}
+
+/// Decide what a sync in `direction` does with `entry`. Removing a path that does
+/// not exist in the source, or a destination dir that would be replaced by a file
+/// or symlink, is only done if `delete` is set.
+pub fn plan(entry: &StatusEntry, direction: Direction, delete: bool) -> Action {
+    let (source_type, destination_type) = match direction {
+        Direction::ToStash => (entry.local_type, entry.stash_type),
+        Direction::FromStash => (entry.stash_type, entry.local_type),
+    };
+    let source_only = match direction {
+        Direction::ToStash => Status::LocalOnly,
+        Direction::FromStash => Status::StashOnly,
+    };
+    if source_type == Some(EntryType::Other) {
+        return Action::Skip { reason: "not a dir, file, or symlink" };
+    }
+    match (entry.status, destination_type) {
+        (status, _) if status == source_only => Action::Copy,
+        (Status::Differs, Some(EntryType::Dir)) if delete => Action::DeleteAndCopy,
+        (Status::Differs, Some(EntryType::Dir)) => Action::Skip { reason: "would replace a dir, use --delete to allow" },
+        (Status::Differs, _) => Action::Replace,
+        _ if delete => Action::Delete,
+        _ => Action::Skip { reason: "not in source, use --delete to remove" },
+    }
+}
+
+/// A path handled by a sync, along with the action taken and the state of the source
+/// at the time, so that a path that changed again after being synced is not skipped
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct JournalEntry {
+    /// The local path
+    pub path: PathBuf,
+    /// The action taken, as displayed by `Action`
+    pub action: String,
+    /// The size of the source, if it is a file
+    pub size: Option<i64>,
+    /// The mtime of the source, if it exists
+    pub mtime: Option<DateTime<Utc>>,
+}
+
+/// A record of the paths already handled by a sync, so that an interrupted sync
+/// can be resumed without repeating work. Each line is a JSON-encoded `JournalEntry`.
+#[derive(Debug)]
+pub struct Journal {
+    file: std::fs::File,
+    done: HashSet<JournalEntry>,
+}
+
+impl Journal {
+    /// Open the journal at `path`, creating it if needed and loading any entries
+    /// recorded by a previous run
+    pub fn open(path: &Path) -> Result<Journal> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let mut done = HashSet::new();
+        for line in content.lines() {
+            // The last line may be incomplete if we were killed while writing it
+            if let Ok(entry) = serde_json::from_str::<JournalEntry>(line) {
+                done.insert(entry);
+            }
+        }
+        if !content.is_empty() && !content.ends_with('\n') {
+            writeln!(file)?;
+        }
+        Ok(Journal { file, done })
+    }
+
+    /// Whether `entry` was already recorded as handled, with the same action and
+    /// source size and mtime
+    pub fn is_done(&self, entry: &JournalEntry) -> bool {
+        self.done.contains(entry)
+    }
+
+    /// Record `entry` as handled, syncing the journal to disk
+    pub fn record(&mut self, entry: JournalEntry) -> Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(&entry)?)?;
+        self.file.sync_data()?;
+        self.done.insert(entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(status: Status, local_type: Option<EntryType>, stash_type: Option<EntryType>) -> StatusEntry {
+        StatusEntry { path: PathBuf::from("x"), status, local_type, stash_type, differences: vec![] }
+    }
+
+    #[test]
+    fn test_plan() {
+        let dir = Some(EntryType::Dir);
+        let file = Some(EntryType::File);
+        let other = Some(EntryType::Other);
+        for (entry, direction, delete, expected) in [
+            (entry(Status::LocalOnly, file, None), Direction::ToStash, false, Action::Copy),
+            (entry(Status::LocalOnly, file, None), Direction::FromStash, false, Action::Skip { reason: "not in source, use --delete to remove" }),
+            (entry(Status::LocalOnly, file, None), Direction::FromStash, true, Action::Delete),
+            (entry(Status::LocalOnly, other, None), Direction::ToStash, true, Action::Skip { reason: "not a dir, file, or symlink" }),
+            (entry(Status::StashOnly, None, dir), Direction::FromStash, false, Action::Copy),
+            (entry(Status::StashOnly, None, dir), Direction::ToStash, true, Action::Delete),
+            (entry(Status::Differs, file, file), Direction::ToStash, false, Action::Replace),
+            (entry(Status::Differs, file, file), Direction::FromStash, false, Action::Replace),
+            (entry(Status::Differs, dir, file), Direction::ToStash, false, Action::Replace),
+            (entry(Status::Differs, dir, file), Direction::FromStash, false, Action::Skip { reason: "would replace a dir, use --delete to allow" }),
+            (entry(Status::Differs, dir, file), Direction::FromStash, true, Action::DeleteAndCopy),
+            (entry(Status::Differs, other, file), Direction::FromStash, false, Action::Replace),
+        ] {
+            assert_eq!(plan(&entry, direction, delete), expected, "{entry:?} {direction:?} delete={delete}");
+        }
+    }
+
+    #[test]
+    fn test_journal() -> Result<()> {
+        let mtime = DateTime::<Utc>::from_timestamp(1_600_000_000, 0).unwrap();
+        let journal_entry = |path: &str, size| {
+            JournalEntry { path: PathBuf::from(path), action: Action::Copy.to_string(), size, mtime: Some(mtime) }
+        };
+        let journal_path = tempfile::NamedTempFile::new()?.into_temp_path();
+        let mut journal = Journal::open(&journal_path)?;
+        assert!(!journal.is_done(&journal_entry("a", Some(1))));
+        journal.record(journal_entry("a", Some(1)))?;
+        journal.record(journal_entry("b\nc", None))?;
+        assert!(journal.is_done(&journal_entry("a", Some(1))));
+        drop(journal);
+
+        // Simulate an incomplete line from an interrupted run
+        let mut file = OpenOptions::new().append(true).open(&journal_path)?;
+        write!(file, "{{\"path\":\"d")?;
+        drop(file);
+
+        let mut journal = Journal::open(&journal_path)?;
+        assert!(journal.is_done(&journal_entry("a", Some(1))));
+        assert!(journal.is_done(&journal_entry("b\nc", None)));
+        assert!(!journal.is_done(&journal_entry("d", Some(1))));
+        // A path that changed after it was synced is not done
+        assert!(!journal.is_done(&journal_entry("a", Some(2))));
+        let delete = JournalEntry { action: Action::Delete.to_string(), ..journal_entry("a", Some(1)) };
+        assert!(!journal.is_done(&delete));
+        journal.record(journal_entry("d", Some(1)))?;
+        drop(journal);
+
+        let journal = Journal::open(&journal_path)?;
+        assert!(journal.is_done(&journal_entry("d", Some(1))));
+        Ok(())
+    }
+}
@@ -0,0 +1,349 @@
+//! Writing a stash dir as a POSIX pax tar stream, reading file content
+//! straight from storage
+
+use anyhow::{bail, ensure, Result};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use crate::db;
+use crate::db::inode::{Dir, File, Inode, InodeId};
+use crate::db::traversal;
+use crate::storage;
+
+const BLOCK_SIZE: usize = 512;
+
+/// The largest size that fits in the 12-byte octal size field of a ustar header
+const MAX_USTAR_SIZE: u64 = 0o777_7777_7777;
+
+/// The kind of a tar entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    /// A directory
+    Dir,
+    /// A regular file
+    File,
+    /// A symbolic link
+    Symlink,
+}
+
+impl EntryType {
+    fn tar_entry_type(self) -> tar::EntryType {
+        match self {
+            EntryType::Dir => tar::EntryType::Directory,
+            EntryType::File => tar::EntryType::Regular,
+            EntryType::Symlink => tar::EntryType::Symlink,
+        }
+    }
+}
+
+/// The metadata for one entry in a tar stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryHeader {
+    /// The path of the entry, with components separated by `/`
+    pub path: String,
+    /// The kind of entry
+    pub entry_type: EntryType,
+    /// The permission bits
+    pub mode: u32,
+    /// The size of the content that follows the header, 0 for dirs and symlinks
+    pub size: u64,
+    /// Modification time
+    pub mtime: DateTime<Utc>,
+    /// The target of a symlink
+    pub link_target: Option<String>,
+    /// Additional pax records, e.g. `EXASTASH.b3sum`
+    pub extra: Vec<(String, String)>,
+}
+
+impl EntryHeader {
+    /// Encode the header as a ustar header block, preceded by a pax extended header
+    /// if the path, symlink target, size, or mtime do not fit in a ustar header,
+    /// or if there are `extra` records.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut path = self.path.clone();
+        if self.entry_type == EntryType::Dir && !path.ends_with('/') {
+            path.push('/');
+        }
+
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(self.entry_type.tar_entry_type());
+        header.set_mode(self.mode);
+        header.set_uid(0);
+        header.set_gid(0);
+        let mut records: Vec<(&str, String)> = vec![];
+        if header.set_path(&path).is_err() {
+            // Too long even when split into the ustar prefix and name
+            records.push(("path", path.clone()));
+            let ustar = header.as_ustar_mut().expect("header is ustar");
+            ustar.prefix.fill(0);
+            copy_truncated(&mut ustar.name, &path);
+        }
+        if let Some(link_target) = &self.link_target {
+            if header.set_link_name_literal(link_target).is_err() {
+                records.push(("linkpath", link_target.clone()));
+                copy_truncated(&mut header.as_old_mut().linkname, link_target);
+            }
+        }
+        if self.size > MAX_USTAR_SIZE {
+            records.push(("size", self.size.to_string()));
+            header.set_size(0);
+        } else {
+            header.set_size(self.size);
+        }
+        let mtime_secs = self.mtime.timestamp();
+        if self.mtime.timestamp_subsec_micros() != 0 || mtime_secs < 0 {
+            records.push(("mtime", pax_mtime(self.mtime)));
+        }
+        header.set_mtime(mtime_secs.max(0) as u64);
+        records.extend(self.extra.iter().map(|(key, value)| (key.as_str(), value.clone())));
+        header.set_cksum();
+
+        let mut builder = tar::Builder::new(vec![]);
+        builder.append_pax_extensions(records.iter().map(|(key, value)| (*key, value.as_bytes())))?;
+        // Take the bytes instead of using `into_inner`, which would also write the end-of-archive marker
+        let mut out = std::mem::take(builder.get_mut());
+        out.extend_from_slice(header.as_bytes());
+        Ok(out)
+    }
+}
+
+/// The number of zero bytes needed after `size` bytes of content to reach a block boundary
+pub fn padding(size: u64) -> usize {
+    let rem = (size % BLOCK_SIZE as u64) as usize;
+    if rem == 0 { 0 } else { BLOCK_SIZE - rem }
+}
+
+/// Copy the longest prefix of `s` that fits in `field` and ends on a char boundary,
+/// for readers that do not support the pax record with the full value
+fn copy_truncated(field: &mut [u8], s: &str) {
+    let mut end = s.len().min(field.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    field.fill(0);
+    field[..end].copy_from_slice(&s.as_bytes()[..end]);
+}
+
+/// Format `mtime` as the value of a pax `mtime` record, a decimal number of seconds
+/// since the epoch, e.g. "1600000000.5" or "-1.5"
+fn pax_mtime(mtime: DateTime<Utc>) -> String {
+    let micros = mtime.timestamp_micros();
+    let sign = if micros < 0 { "-" } else { "" };
+    let micros = micros.unsigned_abs();
+    let s = format!("{sign}{}.{:06}", micros / 1_000_000, micros % 1_000_000);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Writes tar entries to an `AsyncWrite`
+#[derive(Debug)]
+pub struct TarWriter<W> {
+    sink: W,
+    pax_metadata: bool,
+}
+
+impl<W: AsyncWrite + Unpin> TarWriter<W> {
+    /// Create a `TarWriter` that writes to `sink`. If `pax_metadata` is true,
+    /// file entries get `EXASTASH.file_id` and `EXASTASH.b3sum` pax records.
+    pub fn new(sink: W, pax_metadata: bool) -> Self {
+        TarWriter { sink, pax_metadata }
+    }
+
+    /// Write a dir entry
+    pub async fn append_dir(&mut self, path: &str, mtime: DateTime<Utc>) -> Result<()> {
+        let header = EntryHeader {
+            path: path.into(), entry_type: EntryType::Dir, mode: 0o755, size: 0, mtime, link_target: None, extra: vec![],
+        };
+        self.sink.write_all(&header.encode()?).await?;
+        Ok(())
+    }
+
+    /// Write a symlink entry
+    pub async fn append_symlink(&mut self, path: &str, target: &str, mtime: DateTime<Utc>) -> Result<()> {
+        let header = EntryHeader {
+            path: path.into(), entry_type: EntryType::Symlink, mode: 0o777, size: 0, mtime, link_target: Some(target.into()), extra: vec![],
+        };
+        self.sink.write_all(&header.encode()?).await?;
+        Ok(())
+    }
+
+    /// Write a file entry, reading the content of `file` from its storage
+    pub async fn append_file(&mut self, path: &str, file: &File) -> Result<()> {
+        let mut extra = vec![];
+        if self.pax_metadata {
+            extra.push(("EXASTASH.file_id".into(), file.id.to_string()));
+            if let Some(b3sum) = file.b3sum {
+                extra.push(("EXASTASH.b3sum".into(), hex::encode(b3sum)));
+            }
+        }
+        let size = file.size as u64;
+        let header = EntryHeader {
+            path: path.into(),
+            entry_type: EntryType::File,
+            mode: if file.executable { 0o755 } else { 0o644 },
+            size,
+            mtime: file.mtime,
+            link_target: None,
+            extra,
+        };
+        self.sink.write_all(&header.encode()?).await?;
+
+        let (mut stream, _) = storage::read::read(file.id).await?;
+        let mut written = 0;
+        while let Some(bytes) = stream.try_next().await? {
+            written += bytes.len() as u64;
+            // The header already promised `size` bytes, so stop before writing a corrupt archive
+            ensure!(written <= size, "read more than the expected {size} bytes of file id={}", file.id);
+            self.sink.write_all(&bytes).await?;
+        }
+        ensure!(written == size, "read {written} bytes but expected {size} bytes of file id={}", file.id);
+        self.sink.write_all(&vec![0; padding(size)]).await?;
+        Ok(())
+    }
+
+    /// Write the end-of-archive marker, flush, and return the sink
+    pub async fn finish(mut self) -> Result<W> {
+        self.sink.write_all(&[0; 2 * BLOCK_SIZE]).await?;
+        self.sink.flush().await?;
+        Ok(self.sink)
+    }
+}
+
+/// Write the tree below dir `dir_id` as a tar stream to `sink`, with every path
+/// prefixed by `root_name`, and return the sink.
+pub async fn write_dir<W: AsyncWrite + Unpin>(
+    dir_id: i64,
+    root_name: &str,
+    as_of: Option<DateTime<Utc>>,
+    pax_metadata: bool,
+    sink: W,
+) -> Result<W> {
+    let pool = db::pgpool().await;
+
+    // Collect the entire tree before doing the unpredictably-long read operations
+    let mut transaction = pool.begin().await?;
+    let roots = match as_of {
+        None => Dir::find_by_ids(&mut transaction, &[dir_id]).await?,
+        Some(as_of) => Dir::find_by_ids_as_of(&mut transaction, &[dir_id], as_of).await?,
+    };
+    let Some(root) = roots.into_iter().next() else {
+        bail!("dir id={dir_id} not found in database");
+    };
+    let mut entries = vec![];
+    let mut walk = traversal::walk(&mut transaction, dir_id, as_of);
+    while let Some(entry) = walk.try_next().await? {
+        entries.push(entry);
+    }
+    drop(walk);
+    transaction.commit().await?; // close read-only transaction
+
+    let mut writer = TarWriter::new(sink, pax_metadata);
+    writer.append_dir(root_name, root.mtime).await?;
+    // `walk` yields each dir before its descendants, as tar extractors expect
+    for entry in entries {
+        let path = format!("{root_name}/{}", entry.path);
+        match (entry.dirent.child, entry.inode) {
+            (InodeId::Dir(_), Some(inode)) => writer.append_dir(&path, inode.mtime()).await?,
+            (_, Some(Inode::File(file))) => writer.append_file(&path, &file).await?,
+            (_, Some(Inode::Symlink(symlink))) => writer.append_symlink(&path, &symlink.target, symlink.mtime).await?,
+            (inode_id, _) => bail!("{:?} for {:?} not found in database", inode_id, path),
+        }
+    }
+    writer.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn header(path: &str, entry_type: EntryType, size: u64, mtime: DateTime<Utc>) -> EntryHeader {
+        EntryHeader { path: path.into(), entry_type, mode: 0o644, size, mtime, link_target: None, extra: vec![] }
+    }
+
+    fn parse_header(block: &[u8]) -> &tar::Header {
+        tar::Header::from_byte_slice(&block[..BLOCK_SIZE])
+    }
+
+    #[test]
+    fn test_pax_mtime() {
+        let mtime = |secs, nanos| pax_mtime(Utc.timestamp_opt(secs, nanos).unwrap());
+        assert_eq!(mtime(1_600_000_000, 500_000_000), "1600000000.5");
+        assert_eq!(mtime(1_600_000_000, 1_000), "1600000000.000001");
+        assert_eq!(mtime(-100, 0), "-100");
+        // -1.5s is 2s before the epoch plus 0.5s
+        assert_eq!(mtime(-2, 500_000_000), "-1.5");
+        assert_eq!(mtime(-1, 999_999_000), "-0.000001");
+    }
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(padding(0), 0);
+        assert_eq!(padding(1), 511);
+        assert_eq!(padding(512), 0);
+        assert_eq!(padding(513), 511);
+    }
+
+    #[test]
+    fn test_encode_ustar() -> Result<()> {
+        let mtime = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let block = header("dir/file", EntryType::File, 1234, mtime).encode()?;
+        assert_eq!(block.len(), BLOCK_SIZE);
+        let parsed = parse_header(&block);
+        assert_eq!(parsed.path()?.to_str(), Some("dir/file"));
+        assert_eq!(parsed.mode()?, 0o644);
+        assert_eq!(parsed.size()?, 1234);
+        assert_eq!(parsed.mtime()?, 1_600_000_000);
+        assert_eq!(parsed.entry_type(), tar::EntryType::Regular);
+        assert!(parsed.as_ustar().is_some());
+        let mut recomputed = parsed.clone();
+        recomputed.set_cksum();
+        assert_eq!(recomputed.as_bytes(), parsed.as_bytes());
+
+        let block = header("dir", EntryType::Dir, 0, mtime).encode()?;
+        assert_eq!(parse_header(&block).path_bytes().as_ref(), b"dir/");
+        assert_eq!(parse_header(&block).entry_type(), tar::EntryType::Directory);
+
+        // Paths up to 255 bytes are split into the ustar prefix and name
+        let path = format!("{}/file", "d".repeat(120));
+        let block = header(&path, EntryType::File, 0, mtime).encode()?;
+        assert_eq!(block.len(), BLOCK_SIZE);
+        assert_eq!(parse_header(&block).path()?.to_str(), Some(path.as_str()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_pax() -> Result<()> {
+        let mtime = Utc.timestamp_opt(-2, 500_000_000).unwrap();
+        let path = format!("{}/file", "d".repeat(300));
+        let mut entry = header(&path, EntryType::Symlink, MAX_USTAR_SIZE + 1, mtime);
+        let target = "t".repeat(150);
+        entry.link_target = Some(target.clone());
+        entry.extra.push(("EXASTASH.file_id".into(), "7".into()));
+        let out = entry.encode()?;
+
+        let pax = parse_header(&out);
+        assert_eq!(pax.entry_type(), tar::EntryType::XHeader);
+        let len = pax.size()? as usize;
+        let records: Vec<(String, String)> = tar::PaxExtensions::new(&out[BLOCK_SIZE..BLOCK_SIZE + len])
+            .map(|ext| {
+                let ext = ext.unwrap();
+                (ext.key().unwrap().to_string(), ext.value().unwrap().to_string())
+            })
+            .collect();
+        assert_eq!(records, vec![
+            ("path".into(), path.clone()),
+            ("linkpath".into(), target.clone()),
+            ("size".into(), (MAX_USTAR_SIZE + 1).to_string()),
+            ("mtime".into(), "-1.5".into()),
+            ("EXASTASH.file_id".into(), "7".into()),
+        ]);
+
+        assert_eq!(out.len(), 2 * BLOCK_SIZE + len + padding(len as u64));
+        let ustar = parse_header(&out[out.len() - BLOCK_SIZE..]);
+        assert_eq!(ustar.path_bytes().as_ref(), &path.as_bytes()[..100]);
+        assert_eq!(ustar.link_name_bytes().unwrap().as_ref(), &target.as_bytes()[..100]);
+        assert_eq!(ustar.size()?, 0);
+        assert_eq!(ustar.mtime()?, 0);
+        Ok(())
+    }
+}
@@ -10,6 +10,7 @@ use custom_debug_derive::Debug as CustomDebug;
 use crate::util::elide;
 use crate::storage::StoragesDescriptor;
 use crate::storage::RelevantFileMetadata;
+use crate::db::storage::gdrive::Cipher;
 
 impl TryFrom<JsValue> for StoragesDescriptor {
     type Error = anyhow::Error;
@@ -66,6 +67,20 @@ impl TryFrom<JsValue> for StoragesDescriptor {
     }
 }
 
+/// The object passed to policy.js functions that make decisions about a new file
+fn new_file_properties(stash_path: &[&str], metadata: &RelevantFileMetadata) -> JsValue {
+    let mut properties: HashMap<String, JsValue> = HashMap::new();
+    let stash_path_js = stash_path
+        .iter()
+        .map(|&s| JsValue::String(s.into()))
+        .collect();
+    properties.insert("stash_path".into(), JsValue::Array(stash_path_js));
+    properties.insert("size".into(),       JsValue::BigInt(metadata.size.into()));
+    properties.insert("mtime".into(),      JsValue::Date(metadata.mtime));
+    properties.insert("executable".into(), JsValue::Bool(metadata.executable));
+    JsValue::Object(properties)
+}
+
 /// Policy object that can be used to make decisions about file placement
 #[derive(CustomDebug)]
 pub struct Policy {
@@ -77,22 +92,26 @@ impl Policy {
     /// Call policy.js's `new_file_storages` and convert the result to a `StoragesDescriptor`.
     /// These are the storages into which the new file should be stored.
     pub fn new_file_storages(&self, stash_path: &[&str], metadata: &RelevantFileMetadata) -> Result<StoragesDescriptor> {
-        let mut properties: HashMap<String, JsValue> = HashMap::new();
-        let stash_path_js = stash_path
-            .iter()
-            .map(|&s| JsValue::String(s.into()))
-            .collect();
-        properties.insert("stash_path".into(), JsValue::Array(stash_path_js));
-        properties.insert("size".into(),       JsValue::BigInt(metadata.size.into()));
-        properties.insert("mtime".into(),      JsValue::Date(metadata.mtime));
-        properties.insert("executable".into(), JsValue::Bool(metadata.executable));
-
-        let args = vec![JsValue::Object(properties)];
+        let args = vec![new_file_properties(stash_path, metadata)];
         let desired_storages = self.js_context.call_function("new_file_storages", args)?.try_into()?;
         info!(?desired_storages, ?stash_path, "policy.js:new_file_storages returned");
         Ok(desired_storages)
     }
 
+    /// Call policy.js's `dedupe_new_file` and convert the result to a `bool`.
+    /// If true, a new file should be linked to an existing file with the same content
+    /// instead of being stored again, if there is one with the desired storages.
+    /// If policy.js does not define `dedupe_new_file`, returns `false`.
+    pub fn dedupe_new_file(&self, stash_path: &[&str], metadata: &RelevantFileMetadata) -> Result<bool> {
+        let defined: bool = self.js_context.eval_as("typeof dedupe_new_file === 'function'")?;
+        if !defined {
+            return Ok(false);
+        }
+        let args = vec![new_file_properties(stash_path, metadata)];
+        let dedupe: bool = self.js_context.call_function("dedupe_new_file", args)?.try_into()?;
+        Ok(dedupe)
+    }
+
     /// Call policy.js's `fofs_base_url` and convert the result to a `String`.
     /// The string is the URL at which a remote (i.e. not on localhost) fofs pile is reachable
     pub fn fofs_base_url(&self, pile_hostname: &str) -> Result<String> {
@@ -100,6 +119,23 @@ impl Policy {
         let base_url = self.js_context.call_function("fofs_base_url", args)?.try_into()?;
         Ok(base_url)
     }
+
+    /// Call policy.js's `gdrive_cipher` and convert the result to a `Cipher`.
+    /// This is the cipher with which new files in google domain `domain_id` are encrypted.
+    /// If policy.js does not define `gdrive_cipher`, returns `Cipher::Aes128Gcm`.
+    pub fn gdrive_cipher(&self, domain_id: i16) -> Result<Cipher> {
+        let defined: bool = self.js_context.eval_as("typeof gdrive_cipher === 'function'")?;
+        if !defined {
+            return Ok(Cipher::Aes128Gcm);
+        }
+        let args = vec![JsValue::Int(domain_id.into())];
+        let cipher: String = self.js_context.call_function("gdrive_cipher", args)?.try_into()?;
+        match cipher.as_str() {
+            "AES_128_GCM" => Ok(Cipher::Aes128Gcm),
+            "AES_256_GCM" => Ok(Cipher::Aes256Gcm),
+            _ => bail!("gdrive_cipher returned {cipher:?}, expected \"AES_128_GCM\" or \"AES_256_GCM\""),
+        }
+    }
 }
 
 pub(crate) fn parse_policy(script: &str) -> Result<Policy> {
@@ -182,6 +218,25 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_dedupe_new_file() -> Result<()> {
+        let script = r#"
+            function dedupe_new_file({ stash_path, size, mtime, executable }) {
+                return stash_path[0] == "media" && size > 1000;
+            }
+        "#;
+        let policy = parse_policy(script)?;
+        let metadata = RelevantFileMetadata { size: 1001, mtime: Utc::now(), executable: false };
+        assert!(policy.dedupe_new_file(&["media", "video.mkv"], &metadata)?);
+        assert!(!policy.dedupe_new_file(&["other", "video.mkv"], &metadata)?);
+
+        // Default when policy.js has no dedupe_new_file
+        let policy = parse_policy("")?;
+        assert!(!policy.dedupe_new_file(&["media", "video.mkv"], &metadata)?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_fofs_base_url() -> Result<()> {
         let script = r#"
@@ -194,4 +249,31 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_gdrive_cipher() -> Result<()> {
+        let script = r#"
+            function gdrive_cipher(domain_id) {
+                if (domain_id == 2) {
+                    return "AES_256_GCM";
+                } else if (domain_id == 3) {
+                    return "AES_128_CTR";
+                }
+                return "AES_128_GCM";
+            }
+        "#;
+        let policy = parse_policy(script)?;
+        assert_eq!(policy.gdrive_cipher(1)?, Cipher::Aes128Gcm);
+        assert_eq!(policy.gdrive_cipher(2)?, Cipher::Aes256Gcm);
+        assert_eq!(
+            policy.gdrive_cipher(3).expect_err("expected an error").to_string(),
+            r#"gdrive_cipher returned "AES_128_CTR", expected "AES_128_GCM" or "AES_256_GCM""#
+        );
+
+        // Default when policy.js has no gdrive_cipher
+        let policy = parse_policy("")?;
+        assert_eq!(policy.gdrive_cipher(1)?, Cipher::Aes128Gcm);
+
+        Ok(())
+    }
 }
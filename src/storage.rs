@@ -9,6 +9,7 @@ use std::collections::HashSet;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use crate::util;
+use crate::db::storage::StorageView;
 
 /// Descriptor indicating which storages should be created or deleted
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -40,6 +41,30 @@ impl StoragesDescriptor {
         }
         true
     }
+
+    /// Describe the inline, fofs, and gdrive storages among `storages`
+    pub fn of_storages(storages: &[StorageView]) -> StoragesDescriptor {
+        let mut descriptor = StoragesDescriptor { inline: false, fofs: HashSet::new(), gdrive: HashSet::new() };
+        for storage in storages {
+            match storage {
+                StorageView::Inline(_) => descriptor.inline = true,
+                StorageView::Fofs(view) => { descriptor.fofs.insert(view.pile_id); }
+                StorageView::Gdrive(storage) => { descriptor.gdrive.insert(storage.google_domain); }
+                StorageView::NamedFiles(_) | StorageView::InternetArchive(_) => {}
+            }
+        }
+        descriptor
+    }
+
+    /// Whether `other` describes every storage described here
+    pub fn is_subset(&self, other: &StoragesDescriptor) -> bool {
+        (!self.inline || other.inline) && self.fofs.is_subset(&other.fofs) && self.gdrive.is_subset(&other.gdrive)
+    }
+
+    /// Whether `storages` include every storage described here
+    pub fn is_satisfied_by(&self, storages: &[StorageView]) -> bool {
+        self.is_subset(&StoragesDescriptor::of_storages(storages))
+    }
 }
 
 
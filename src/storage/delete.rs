@@ -1,67 +1,111 @@
 //! Functions to delete storage
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use sqlx::{Postgres, Transaction};
 use crate::db;
 use crate::gdrive::delete_gdrive_file;
 use crate::util;
 use crate::storage::StoragesDescriptor;
 use tracing::info;
 
-/// Delete storages for a file and remove them to the database.
-pub async fn delete_storages(file_id: i64, undesired: &StoragesDescriptor, delete_google_drive_files: bool) -> Result<()> {
-    if undesired.is_empty() {
-        return Ok(());
-    }
-
-    let pool = db::pgpool().await;
+/// Stored data that must be removed after the database rows pointing to it are gone
+struct RemovedStorages {
+    /// fofs storages whose files are still on disk
+    fofs: Vec<db::storage::fofs::StorageView>,
+    /// Google Drive files that are no longer referenced by any storage_gdrive row
+    gdrive_ids: Vec<String>,
+}
 
+/// Delete the database rows for the `undesired` storages of file `file_id` and return
+/// the stored data that the caller must remove with `remove_stored_data` after committing.
+/// Does not commit the transaction, you must do so yourself.
+async fn delete_storage_rows(transaction: &mut Transaction<'_, Postgres>, file_id: i64, undesired: &StoragesDescriptor) -> Result<RemovedStorages> {
+    let mut removed = RemovedStorages { fofs: vec![], gdrive_ids: vec![] };
     if !undesired.fofs.is_empty() {
-        let mut transaction = pool.begin().await?;
-        let storage_views = db::storage::fofs::StorageView::find_by_file_ids(&mut transaction, &[file_id]).await?;
-        transaction.commit().await?;
-
+        let storage_views = db::storage::fofs::StorageView::find_by_file_ids(transaction, &[file_id]).await?;
         let my_hostname = util::get_hostname();
         for view in storage_views {
-            info!(file_id, pile_id = view.pile_id, cell_id = view.cell_id, "deleting storage_fofs for file");
             if view.pile_hostname != my_hostname {
-                unimplemented!("deleting from another machine");
+                bail!("cannot delete file {file_id} from fofs pile {} on {}", view.pile_id, view.pile_hostname);
             }
-            let mut transaction = pool.begin().await?;
-            db::storage::fofs::Storage::delete_by_file_id_and_cell_id(&mut transaction, file_id, view.cell_id).await?;
-            transaction.commit().await?;
-            // Above, we remove the database reference first to avoid the possibility
-            // of the database pointing to nonexistent storages.
-            let fname = format!("{}/{}/{}/{file_id}", view.pile_path, view.pile_id, view.cell_id);
-            tokio::fs::remove_file(fname).await?;
+            info!(file_id, pile_id = view.pile_id, cell_id = view.cell_id, "deleting storage_fofs for file");
+            db::storage::fofs::Storage::delete_by_file_id_and_cell_id(transaction, file_id, view.cell_id).await?;
+            removed.fofs.push(view);
         }
     }
     if undesired.inline {
         info!(file_id, "deleting storage_inline for file");
-        let mut transaction = pool.begin().await?;
-        db::storage::inline::Storage::delete_by_file_ids(&mut transaction, &[file_id]).await?;
-        transaction.commit().await?;
+        db::storage::inline::Storage::delete_by_file_ids(transaction, &[file_id]).await?;
     }
     if !undesired.gdrive.is_empty() {
-        // Get the IDs of the Google Drive files we need to delete
-        let mut transaction = pool.begin().await?;
-        let storages = db::storage::gdrive::Storage::find_by_file_ids(&mut transaction, &[file_id]).await?;
-        let gdrive_ids: Vec<String> = storages.into_iter().flat_map(|s| s.gdrive_ids).collect();
-        transaction.commit().await?; // close read-only transaction
+        let storages = db::storage::gdrive::Storage::find_by_file_ids(transaction, &[file_id]).await?;
+        removed.gdrive_ids = storages.into_iter().flat_map(|s| s.gdrive_ids).collect();
+        db::storage::gdrive::Storage::delete_by_file_ids(transaction, &[file_id]).await?;
+    }
+    Ok(removed)
+}
 
+/// Remove the stored data of file `file_id` that `delete_storage_rows` returned, then the
+/// gdrive_files rows, which are kept until now because `delete_gdrive_file` needs them.
+async fn remove_stored_data(file_id: i64, removed: RemovedStorages, delete_google_drive_files: bool) -> Result<()> {
+    for view in removed.fofs {
+        let fname = format!("{}/{}/{}/{file_id}", view.pile_path, view.pile_id, view.cell_id);
+        tokio::fs::remove_file(fname).await?;
+    }
+    if !removed.gdrive_ids.is_empty() {
         if delete_google_drive_files {
-            // Delete the Google Drive files
-            for gdrive_id in &gdrive_ids {
+            for gdrive_id in &removed.gdrive_ids {
                 delete_gdrive_file(gdrive_id).await?;
             }
         }
-
-        // Update our database
+        let pool = db::pgpool().await;
         let mut transaction = pool.begin().await?;
-        db::storage::gdrive::Storage::delete_by_file_ids(&mut transaction, &[file_id]).await?;
-        let gdrive_ids: Vec<&str> = gdrive_ids.iter().map(AsRef::as_ref).collect();
+        let gdrive_ids: Vec<&str> = removed.gdrive_ids.iter().map(AsRef::as_ref).collect();
         db::storage::gdrive::file::GdriveFile::delete_by_ids(&mut transaction, &gdrive_ids).await?;
         transaction.commit().await?;
     }
-
     Ok(())
 }
+
+/// Delete storages for a file and remove them from the database. The database rows are
+/// deleted before any stored data is removed, to avoid the possibility of the database
+/// pointing to nonexistent storages.
+pub async fn delete_storages(file_id: i64, undesired: &StoragesDescriptor, delete_google_drive_files: bool) -> Result<()> {
+    if undesired.is_empty() {
+        return Ok(());
+    }
+
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    let removed = delete_storage_rows(&mut transaction, file_id, undesired).await?;
+    transaction.commit().await?;
+    remove_stored_data(file_id, removed, delete_google_drive_files).await
+}
+
+/// Delete file `file_id` and all of its inline, fofs, and gdrive storages if no dirent
+/// points to it, returning whether it was deleted. Unlike `delete_storages`, the file row
+/// is locked, the dirent count rechecked, and the file and storage rows deleted in one
+/// transaction, so a dirent created concurrently cannot end up pointing to a file without
+/// its data.
+pub async fn delete_unreferenced_file(file_id: i64, delete_google_drive_files: bool) -> Result<bool> {
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    if !db::inode::File::lock_for_update(&mut transaction, file_id).await? {
+        return Ok(false);
+    }
+    let counts = db::dirent::Dirent::count_by_children(&mut transaction, &[db::inode::InodeId::File(file_id)]).await?;
+    if counts.contains_key(&db::inode::InodeId::File(file_id)) {
+        info!(file_id, "file is referenced again, not deleting");
+        return Ok(false);
+    }
+
+    // New storages for the file are blocked by our row lock, so this sees all of them
+    let storages = db::storage::get_storage_views(&[file_id]).await?;
+    let all = StoragesDescriptor::of_storages(&storages);
+    let removed = delete_storage_rows(&mut transaction, file_id, &all).await?;
+    db::inode::File::delete(&mut transaction, &[file_id]).await?;
+    transaction.commit().await?;
+
+    remove_stored_data(file_id, removed, delete_google_drive_files).await?;
+    Ok(true)
+}
@@ -27,7 +27,7 @@ use crate::db::google_auth::{GoogleAccessToken, GoogleServiceAccount};
 use crate::util;
 use crate::policy;
 use crate::gdrive::{request_gdrive_file, get_crc32c_in_response};
-use crate::crypto::{GcmDecoder, gcm_create_key};
+use crate::crypto::{GcmDecoder, gcm_create_key_from_halves};
 
 type Aes128Ctr = ctr::Ctr64BE<aes::Aes128>;
 
@@ -273,7 +273,7 @@ fn stream_gdrive_gcm_chunks(file: &inode::File, storage: &gdrive::Storage) -> Re
             transaction.commit().await?; // close read-only transaction
 
             let whole_block_size = 65536;
-            // Block size for all of our AES-128-GCM files
+            // Block size for all of our AES-GCM files
             let block_size = whole_block_size - 16;
             let aes_gcm_length = get_aes_gcm_length(file.size as u64, block_size);
 
@@ -298,7 +298,7 @@ fn stream_gdrive_gcm_chunks(file: &inode::File, storage: &gdrive::Storage) -> Re
                 let keep_bytes = aes_gcm_length - last_gcm_stream_bytes;
                 let truncated_read = encrypted_read.take(keep_bytes);
 
-                let key = gcm_create_key(storage.cipher_key).unwrap();
+                let key = gcm_create_key_from_halves(storage.cipher_key, storage.cipher_key_ext)?;
                 let first_block_number = last_gcm_stream_bytes / whole_block_size as u64;
                 let decoder = GcmDecoder::new(block_size, key, first_block_number);
                 let frame_reader = FramedRead::new(truncated_read, decoder);
@@ -313,7 +313,7 @@ fn stream_gdrive_gcm_chunks(file: &inode::File, storage: &gdrive::Storage) -> Re
 
 fn stream_gdrive_files(file: &inode::File, storage: &gdrive::Storage) -> ReadStream {
     match storage.cipher {
-        gdrive::Cipher::Aes128Gcm => stream_gdrive_gcm_chunks(file, storage),
+        gdrive::Cipher::Aes128Gcm | gdrive::Cipher::Aes256Gcm => stream_gdrive_gcm_chunks(file, storage),
         // We no longer create AES-128-CTR files, but we still need to read them
         gdrive::Cipher::Aes128Ctr => stream_gdrive_ctr_chunks(file, storage),
     }
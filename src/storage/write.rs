@@ -16,17 +16,20 @@ use tokio::{fs, io::{AsyncRead, AsyncReadExt}};
 use tokio_util::codec::{Encoder, FramedRead};
 use blake3::Hash;
 use crate::util::FixedReadSizeDecoder;
-use crate::crypto::{GcmEncoder, gcm_create_key};
+use crate::crypto::{GcmEncoder, gcm_create_key_from_halves};
 use crate::conceal_size::conceal_size;
 use crate::db;
-use crate::db::inode;
+use crate::db::inode::{self, InodeId};
+use crate::db::dirent::Dirent;
 use crate::db::storage::{inline, gdrive::{self, file::GdriveFile}, fofs, StorageView, get_storage_views};
-use crate::blake3::{Blake3HashingReader, b3sum_bytes};
+use crate::blake3::{Blake3HashingReader, b3sum_bytes, b3sum_local_file};
 use crate::storage::{StoragesDescriptor, RelevantFileMetadata};
 use crate::storage::read::{get_access_tokens, get_aes_gcm_length};
 use crate::gdrive::{create_gdrive_file, GdriveUploadError};
 use crate::util;
+use crate::policy;
 use pin_project::pin_project;
+use ring::aead::LessSafeKey;
 use parking_lot::Mutex;
 use md5::{Md5, Digest};
 
@@ -142,9 +145,14 @@ fn new_chunk_filename() -> String {
     format!("{secs}-{nanos}-{}", hex::encode(random))
 }
 
-#[inline]
-fn new_cipher_key() -> [u8; 16] {
-    rand::thread_rng().gen::<[u8; 16]>()
+/// Return a new random `(cipher_key, cipher_key_ext)` for `cipher`
+fn new_cipher_key(cipher: gdrive::Cipher) -> Result<([u8; 16], Option<[u8; 16]>)> {
+    let mut rng = rand::thread_rng();
+    match cipher {
+        gdrive::Cipher::Aes128Gcm => Ok((rng.gen::<[u8; 16]>(), None)),
+        gdrive::Cipher::Aes256Gcm => Ok((rng.gen::<[u8; 16]>(), Some(rng.gen::<[u8; 16]>()))),
+        gdrive::Cipher::Aes128Ctr => bail!("refusing to write new files with cipher {:?}", cipher),
+    }
 }
 
 struct RandomPadding {
@@ -172,12 +180,12 @@ impl Iterator for RandomPadding {
     }
 }
 
-/// Takes an unencrypted AsyncRead and returns an AES-128-GCM encrypted stream,
+/// Takes an unencrypted AsyncRead and returns an AES-GCM encrypted stream,
 /// suitable for storing in untrusted storage.
 async fn encrypt_reader<A: AsyncRead + Send + Sync + 'static>(
     reader: A,
     block_size: usize,
-    cipher_key: [u8; 16],
+    key: LessSafeKey,
     padding_size: u64,
 ) -> Result<Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send + Sync + 'static>>> {
     // Re-chunk the stream to make sure each chunk is appropriately-sized for the GcmEncoder
@@ -186,10 +194,7 @@ async fn encrypt_reader<A: AsyncRead + Send + Sync + 'static>(
         FramedRead::new(reader, decoder)
     };
 
-    let mut encoder = {
-        let key = gcm_create_key(cipher_key).unwrap();
-        GcmEncoder::new(block_size, key, 0)
-    };
+    let mut encoder = GcmEncoder::new(block_size, key, 0);
 
     let stream = rechunked.map_ok(move |bytes| -> Bytes {
         assert!(bytes.len() <= block_size, "single read from file must be shorter or same length as block size {block_size}, was {}", bytes.len());
@@ -272,8 +277,15 @@ pub async fn write_to_gdrive<A: AsyncRead + Send + Sync + 'static>(
     let encrypted_size = get_aes_gcm_length(file.size as u64, block_size);
     let gdrive_file_size = conceal_size(encrypted_size);
     let padding_size = gdrive_file_size - encrypted_size;
-    let cipher_key = new_cipher_key();
-    let efp = encrypt_reader(reader, block_size, cipher_key, padding_size).await?;
+    // We need `policy` to go out of scope because trait `std::marker::Send`
+    // is not implemented for `*mut libquickjs_sys::JSRuntime`
+    let cipher = {
+        let policy = policy::get_policy()?;
+        policy.gdrive_cipher(domain_id)?
+    };
+    let (cipher_key, cipher_key_ext) = new_cipher_key(cipher)?;
+    let key = gcm_create_key_from_halves(cipher_key, cipher_key_ext)?;
+    let efp = encrypt_reader(reader, block_size, key, padding_size).await?;
 
     let filename = new_chunk_filename();
     // While terastash uploaded large files as multi-chunk files,
@@ -295,8 +307,9 @@ pub async fn write_to_gdrive<A: AsyncRead + Send + Sync + 'static>(
     let storage = gdrive::Storage {
         file_id: file.id,
         google_domain: domain_id,
-        cipher: gdrive::Cipher::Aes128Gcm,
+        cipher,
         cipher_key,
+        cipher_key_ext,
         gdrive_ids: vec![gdrive_file.id.clone()],
     };
 
@@ -568,6 +581,105 @@ pub async fn create_stash_file_from_local_file(path: String, metadata: &Relevant
     Ok(file.id)
 }
 
+/// Files up to this size are buffered in memory when more than one storage needs their content
+const MAX_IN_MEMORY_SIZE: i64 = 64 * 1024 * 1024;
+
+/// Create a new stash file from content that can only be read once, write storage,
+/// return the new file id. With one desired storage, `reader` is streamed straight
+/// into it. With more than one, the content is buffered in memory, or in a temporary
+/// file if it is larger than `MAX_IN_MEMORY_SIZE`.
+pub async fn create_stash_file_from_reader<R: AsyncRead + Send + Sync + Unpin + 'static>(
+    mut reader: R,
+    metadata: &RelevantFileMetadata,
+    desired: &StoragesDescriptor,
+) -> Result<i64> {
+    if metadata.size > 0 && desired.len() == 0 {
+        bail!("a file with size > 0 needs storage, but no storage was specified");
+    }
+
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    let birth = inode::Birth::here_and_now();
+    let file = inode::NewFile {
+        mtime: metadata.mtime,
+        birth,
+        size: metadata.size,
+        executable: metadata.executable,
+        b3sum: None,
+    }.create(&mut transaction).await?;
+    transaction.commit().await?;
+
+    type BoxedReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+    if desired.len() <= 1 {
+        let mut reader = Some(reader);
+        let producer = move || {
+            let reader: BoxedReader = Box::new(reader.take().ok_or_else(|| anyhow!("no readers left"))?);
+            Ok(reader)
+        };
+        add_storages(producer, &file, desired).await?;
+    } else if metadata.size <= MAX_IN_MEMORY_SIZE {
+        let mut content = Vec::with_capacity(metadata.size as usize);
+        reader.read_to_end(&mut content).await?;
+        let content = Bytes::from(content);
+        let producer = move || {
+            let reader: BoxedReader = Box::new(std::io::Cursor::new(content.clone()));
+            Ok(reader)
+        };
+        add_storages(producer, &file, desired).await?;
+    } else {
+        // Keep `temp_path` until we are done, because dropping it removes the file
+        let temp_path = tempfile::NamedTempFile::new()?.into_temp_path();
+        let mut temp_file = fs::File::create(&temp_path).await?;
+        tokio::io::copy(&mut reader, &mut temp_file).await?;
+        drop(temp_file);
+        let mut readers = readers_for_file(temp_path.to_path_buf(), desired.len()).await?;
+        let producer = move || {
+            let reader: BoxedReader = Box::new(readers.pop().ok_or_else(|| anyhow!("no readers left"))?);
+            Ok(reader)
+        };
+        add_storages(producer, &file, desired).await?;
+    }
+
+    Ok(file.id)
+}
+
+/// Return the id of an existing stash file with the same content, size, mtime, and
+/// executable bit as the local file at `path`, that some dirent points to and that
+/// already has all of the `desired` storages, or `None` if there is no such file.
+/// Unreferenced files are skipped because `es gc` may be deleting them.
+/// Hashes the entire local file. Empty files are never deduplicated because they
+/// need no storage.
+pub async fn find_existing_file_for_local_file(path: &Path, metadata: &RelevantFileMetadata, desired: &StoragesDescriptor) -> Result<Option<i64>> {
+    if metadata.size == 0 {
+        return Ok(None);
+    }
+    let b3sum = b3sum_local_file(path).await?;
+    let pool = db::pgpool().await;
+    let mut transaction = pool.begin().await?;
+    let candidates: Vec<inode::File> = inode::File::find_by_b3sum_and_size(&mut transaction, b3sum.as_bytes(), metadata.size).await?
+        .into_iter()
+        .filter(|file| file.executable == metadata.executable && file.mtime == metadata.mtime)
+        .collect();
+    let inode_ids: Vec<InodeId> = candidates.iter().map(|file| InodeId::File(file.id)).collect();
+    let counts = Dirent::count_by_children(&mut transaction, &inode_ids).await?;
+    transaction.commit().await?; // close read-only transaction
+    let candidates: Vec<inode::File> = candidates.into_iter()
+        .filter(|file| counts.contains_key(&InodeId::File(file.id)))
+        .collect();
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+
+    let file_ids: Vec<i64> = candidates.iter().map(|file| file.id).collect();
+    let mut storages: HashMap<i64, Vec<StorageView>> = HashMap::new();
+    for view in get_storage_views(&file_ids).await? {
+        storages.entry(view.file_id()).or_default().push(view);
+    }
+    Ok(file_ids.into_iter().find(|id| {
+        desired.is_satisfied_by(storages.get(id).map_or(&[], Vec::as_slice))
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -612,4 +724,14 @@ mod tests {
 
         Ok(())
     }
+
+    /// Ensure the future returned by `create_stash_file_from_reader` is Send,
+    /// because `es x import-archive` reads members in another thread.
+    #[test]
+    fn test_create_stash_file_from_reader_is_send() {
+        let desired = storage::StoragesDescriptor { inline: true, fofs: hset![], gdrive: hset![] };
+        let metadata = storage::RelevantFileMetadata { size: 0, mtime: Utc::now(), executable: false };
+        let fut = storage::write::create_stash_file_from_reader(tokio::io::empty(), &metadata, &desired);
+        ensure_send(fut);
+    }
 }